@@ -1,6 +1,6 @@
 use std::time::Duration;
 
-use nrg_hass::config::HomeAssistantConfig;
+use nrg_hass::{config::HomeAssistantConfig, policy::PublishPolicy};
 use nrg_mqtt::config::MqttConfig;
 use serde::Deserialize;
 
@@ -10,6 +10,18 @@ pub struct Config {
     pub mqtt: MqttConfig,
     #[serde(rename = "home-assistant")]
     pub hass: HomeAssistantConfig,
+    #[serde(default)]
+    pub publish: PublishConfig,
+}
+
+/// When the values are published. By default every reading is
+/// published.
+#[derive(Debug, Default, Deserialize)]
+pub struct PublishConfig {
+    #[serde(default)]
+    pub w: PublishPolicy,
+    #[serde(default)]
+    pub wh: PublishPolicy,
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,4 +31,7 @@ pub struct ModbusConfig {
     pub slave: u8,
     pub retry_delay: Duration,
     pub poll_delay: Duration,
+    /// Maximum time to wait for a response
+    #[serde(default = "nrg_modbus::connection::default_timeout")]
+    pub timeout: Duration,
 }
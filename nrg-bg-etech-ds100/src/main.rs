@@ -1,34 +1,52 @@
-use std::{fs, time::Duration};
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use config::Config;
 use nrg_hass::{
+    availability::publish_availability,
     discovery::announce,
     models::{device_class::DeviceClass, state_class::StateClass, unit::UnitOfMeasurement},
+    policy::Throttle,
     state::publish_state,
 };
+use nrg_modbus::{connection::timeout, Register};
 use nrg_mqtt::client::MqttClient;
 use tokio::time::sleep;
-use tokio_modbus::{
-    client::{Context, Reader},
-    Slave,
-};
+use tokio_modbus::{client::Context, Slave};
 use tokio_serial::SerialStream;
+use tracing::{info, warn, Level};
+use tracing_subscriber::FmtSubscriber;
+
+use crate::config::ModbusConfig;
 
 mod config;
 
+/// Active power in W
+const ACTIVE_POWER: Register<u32> = Register::new("active_power", 0x0420);
+/// Total active energy in 10 Wh
+const TOTAL_ENERGY: Register<u32> = Register::new("total_energy", 0x010E);
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
     let data = fs::read("nrg-bg-etech-ds100.toml").expect("Could not read config.toml");
     let data = String::from_utf8(data).expect("Config file contains non-utf8 characters");
     let cfg: Config = toml::from_str(&data).expect("Error in config file");
 
-    let builder = tokio_serial::new(cfg.modbus.device, cfg.modbus.baud);
-    let stream = SerialStream::open(&builder)?;
-    let mut ctx = tokio_modbus::client::rtu::attach_slave(stream, Slave(cfg.modbus.slave));
+    let mut ctx = connect(&cfg.modbus).await;
 
     let mqtt = MqttClient::new(&cfg.mqtt);
 
+    let availability_topic = format!("nrg/energy-meter/{}/availability", cfg.hass.object_id);
+
     let hass_wh = nrg_hass::models::sensor::Sensor::builder()
         .name(format!("{} Energie", cfg.hass.name))
         .object_id(format!("{}_{}", cfg.hass.object_id, "wh"))
@@ -38,6 +56,7 @@ async fn main() -> Result<()> {
         .state_class(StateClass::TotalIncreasing)
         .unit_of_measurement(UnitOfMeasurement::WattHours)
         .icon("mdi:heat-pump-outline")
+        .availability_topic(&availability_topic)
         .build()
         .unwrap();
 
@@ -49,27 +68,56 @@ async fn main() -> Result<()> {
         .unique_id(format!("{}_{}", cfg.hass.object_id, "w"))
         .unit_of_measurement(UnitOfMeasurement::Watt)
         .icon("mdi:home-lightning-bolt-outline")
+        .availability_topic(&availability_topic)
         .build()
         .unwrap();
 
     announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass_wh).await?;
     announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass_w).await?;
+    publish_availability(&mqtt, &availability_topic, true).await?;
+
+    let mut throttle_w = Throttle::new(cfg.publish.w);
+    let mut throttle_wh = Throttle::new(cfg.publish.wh);
 
     loop {
-        let w = read_u32(&mut ctx, 0x420).await?;
-        let wh = read_u32(&mut ctx, 0x010E).await? * 10;
-        println!("{:4} W  {:6} Wh", w, wh);
-        publish_state(&mqtt, &hass_w, w).await?;
-        publish_state(&mqtt, &hass_wh, wh).await?;
-        sleep(Duration::from_millis(500)).await;
+        let (w, wh) = match read_values(&mut ctx, cfg.modbus.timeout).await {
+            Ok(values) => values,
+            Err(e) => {
+                warn!("Reading energy meter failed: {}", e);
+                publish_availability(&mqtt, &availability_topic, false).await?;
+                sleep(cfg.modbus.retry_delay).await;
+                ctx = connect(&cfg.modbus).await;
+                publish_availability(&mqtt, &availability_topic, true).await?;
+                continue;
+            }
+        };
+        info!("{:4} W  {:6} Wh", w, wh);
+        let now = Instant::now();
+        if let Some(w) = throttle_w.update(w.into(), now) {
+            publish_state(&mqtt, &hass_w, w).await?;
+        }
+        if let Some(wh) = throttle_wh.update(wh.into(), now) {
+            publish_state(&mqtt, &hass_wh, wh).await?;
+        }
+        sleep(cfg.modbus.poll_delay).await;
     }
 }
 
-async fn read_u32(ctx: &mut Context, addr: u16) -> Result<u32> {
-    let data: [u16; 2] = ctx
-        .read_holding_registers(addr, 2)
-        .await??
-        .try_into()
-        .expect("read_holding_registers returned the wrong amount of registers");
-    Ok(((data[0] as u32) << 16) + (data[1] as u32))
+/// Open the serial port
+async fn connect(cfg: &ModbusConfig) -> Context {
+    nrg_modbus::connection::connect(cfg.retry_delay, cfg.timeout, || async {
+        info!("Opening {}...", cfg.device);
+        let stream = SerialStream::open(&tokio_serial::new(&cfg.device, cfg.baud))?;
+        Ok::<_, tokio_serial::Error>(tokio_modbus::client::rtu::attach_slave(
+            stream,
+            Slave(cfg.slave),
+        ))
+    })
+    .await
+}
+
+async fn read_values(ctx: &mut Context, duration: Duration) -> Result<(u32, u32)> {
+    let w = timeout(duration, nrg_modbus::read(ctx, ACTIVE_POWER)).await?;
+    let wh = timeout(duration, nrg_modbus::read(ctx, TOTAL_ENERGY)).await? * 10;
+    Ok((w, wh))
 }
@@ -0,0 +1,23 @@
+use rumqttc::AsyncClient;
+
+/// Default payload of Home Assistant for an available entity
+pub const PAYLOAD_AVAILABLE: &str = "online";
+/// Default payload of Home Assistant for an unavailable entity
+pub const PAYLOAD_NOT_AVAILABLE: &str = "offline";
+
+/// Publish the availability of all entities using the given
+/// `availability_topic`.
+pub async fn publish_availability(
+    client: &AsyncClient,
+    topic: &str,
+    available: bool,
+) -> Result<(), rumqttc::ClientError> {
+    let payload = if available {
+        PAYLOAD_AVAILABLE
+    } else {
+        PAYLOAD_NOT_AVAILABLE
+    };
+    client
+        .publish(topic, rumqttc::QoS::AtLeastOnce, true, payload)
+        .await
+}
@@ -1,4 +1,6 @@
+pub mod availability;
 pub mod config;
 pub mod discovery;
 pub mod models;
+pub mod policy;
 pub mod state;
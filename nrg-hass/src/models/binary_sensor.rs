@@ -5,7 +5,6 @@ use crate::{discovery::Discovery, state::State};
 use super::{
     availability::{Availability, AvailabilityMode},
     device::Device,
-    device_class::DeviceClass,
     entity_category::EntityCategory,
     qos::Qos,
     state_class::StateClass,
@@ -13,6 +12,68 @@ use super::{
 use derive_builder::Builder;
 use serde::Serialize;
 
+/// https://www.home-assistant.io/integrations/binary_sensor/#device-class
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinarySensorDeviceClass {
+    /// On means low, Off means normal
+    Battery,
+    /// On means charging, Off means not charging
+    BatteryCharging,
+    /// On means carbon monoxide detected, Off means no carbon monoxide (clear)
+    CarbonMonoxide,
+    /// On means cold, Off means normal
+    Cold,
+    /// On means connected, Off means disconnected
+    Connectivity,
+    /// On means open, Off means closed
+    Door,
+    /// On means open, Off means closed
+    GarageDoor,
+    /// On means gas detected, Off means no gas (clear)
+    Gas,
+    /// On means hot, Off means normal
+    Heat,
+    /// On means light detected, Off means no light
+    Light,
+    /// On means open (unlocked), Off means closed (locked)
+    Lock,
+    /// On means moisture detected (wet), Off means no moisture (dry)
+    Moisture,
+    /// On means motion detected, Off means no motion (clear)
+    Motion,
+    /// On means moving, Off means not moving (stopped)
+    Moving,
+    /// On means occupied (detected), Off means not occupied (clear)
+    Occupancy,
+    /// On means open, Off means closed
+    Opening,
+    /// On means device is plugged in, Off means device is unplugged
+    Plug,
+    /// On means power detected, Off means no power
+    Power,
+    /// On means home, Off means away
+    Presence,
+    /// On means problem detected, Off means no problem (OK)
+    Problem,
+    /// On means running, Off means not running
+    Running,
+    /// On means unsafe, Off means safe
+    Safety,
+    /// On means smoke detected, Off means no smoke (clear)
+    Smoke,
+    /// On means sound detected, Off means no sound (clear)
+    Sound,
+    /// On means tampering detected, Off means no tampering (clear)
+    Tamper,
+    /// On means update available, Off means up-to-date
+    Update,
+    /// On means vibration detected, Off means no vibration (clear)
+    Vibration,
+    /// On means open, Off means closed
+    Window,
+}
+
 /// https://www.home-assistant.io/integrations/binary_sensor.mqtt/
 #[derive(Clone, Debug, Default, Serialize, Builder)]
 #[builder(default, setter(into, strip_option))]
@@ -28,7 +89,7 @@ pub struct BinarySensor {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device: Option<Arc<Device>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub device_class: Option<DeviceClass>,
+    pub device_class: Option<BinarySensorDeviceClass>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub enabled_by_default: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use derive_builder::Builder;
+use serde::Serialize;
+
+use crate::discovery::Discovery;
+
+use super::{
+    availability::{Availability, AvailabilityMode},
+    device::Device,
+    entity_category::EntityCategory,
+    qos::Qos,
+};
+
+/// https://www.home-assistant.io/integrations/button.mqtt/
+#[derive(Clone, Debug, Default, Serialize, Builder)]
+#[builder(default, setter(into, strip_option))]
+pub struct Button {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availabilty: Option<Vec<Availability>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_mode: Option<AvailabilityMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_template: Option<String>,
+    pub command_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Arc<Device>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled_by_default: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<EntityCategory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // This field is marked as optional in the docs but since
+    // the field is required for the auto discovery to work it
+    // is marked as required.
+    pub object_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_available: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_not_available: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_press: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qos: Option<Qos>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retain: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_id: Option<String>,
+}
+
+impl Button {
+    pub fn builder() -> ButtonBuilder {
+        ButtonBuilder::default()
+    }
+}
+
+impl Discovery for Button {
+    const COMPONENT: &'static str = "button";
+    fn object_id(&self) -> &str {
+        &self.object_id
+    }
+}
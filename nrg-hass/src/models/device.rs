@@ -19,6 +19,8 @@ pub struct Device {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub suggested_area: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sw_version: Option<String>,
@@ -20,6 +20,8 @@ pub enum NumberMode {
 #[derive(Clone, Debug, Default, Serialize, Builder)]
 #[builder(default, setter(into, strip_option))]
 pub struct Number {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_topic: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub command_template: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -49,6 +51,8 @@ pub struct Number {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub unit_of_measurement: Option<UnitOfMeasurement>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub value_template: Option<String>,
 }
 
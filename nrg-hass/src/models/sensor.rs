@@ -53,6 +53,9 @@ pub struct Sensor {
     // the field is required for the auto discovery to work it
     // is marked as required.
     pub object_id: String,
+    /// List of allowed states for sensors with the device class `enum`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub payload_available: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -1,7 +1,7 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 // https://github.com/home-assistant/core/blob/master/homeassistant/const.py#L384
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum UnitOfMeasurement {
     // Apparent power units
     #[serde(rename = "VA")]
@@ -70,7 +70,8 @@ pub enum UnitOfMeasurement {
     Days,
     #[serde(rename = "w")]
     Weeks,
-    #[serde(rename = "m")]
+    // Same unit as `Meters`, which is used when deserializing "m"
+    #[serde(rename = "m", skip_deserializing)]
     Months,
     #[serde(rename = "y")]
     Years,
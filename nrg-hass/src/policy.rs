@@ -0,0 +1,245 @@
+//! Rate limiting of numeric states.
+//!
+//! Meters are read far more often than their values are of interest.
+//! A [`Throttle`] decides per entity which readings are published,
+//! based on a [`PublishPolicy`] from the config.
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// When a state is published. The default publishes every value.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PublishPolicy {
+    /// Minimum time between two messages
+    #[serde(default)]
+    pub min_interval: Duration,
+    /// Publish the current value after this time even if it did not
+    /// change significantly. Checked whenever a new value is read.
+    pub max_interval: Option<Duration>,
+    /// Only publish if the value changed by more than this, e.g. `5.0`
+    /// for 5 W. `0.0` publishes every change.
+    pub deadband: Option<f64>,
+    /// Only publish if the value changed by more than this fraction of
+    /// the last published value, e.g. `0.05` for 5 %. If `deadband` is
+    /// set as well, the change has to exceed both.
+    pub relative_deadband: Option<f64>,
+    /// Publish the average of all values read since the last message
+    /// instead of the latest value
+    #[serde(default)]
+    pub average: bool,
+}
+
+impl PublishPolicy {
+    fn has_deadband(&self) -> bool {
+        self.deadband.is_some() || self.relative_deadband.is_some()
+    }
+
+    fn is_significant(&self, last: f64, value: f64) -> bool {
+        if !self.has_deadband() {
+            return true;
+        }
+        let threshold = self
+            .deadband
+            .unwrap_or(0.0)
+            .max(self.relative_deadband.unwrap_or(0.0) * last.abs());
+        (value - last).abs() > threshold
+    }
+}
+
+/// State of the policy for one entity
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    policy: PublishPolicy,
+    /// Last published value and time
+    last: Option<(f64, Instant)>,
+    /// Sum and count of the values since the last message
+    sum: f64,
+    count: u32,
+}
+
+impl Throttle {
+    pub fn new(policy: PublishPolicy) -> Self {
+        Self {
+            policy,
+            last: None,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Add a value read at `now`. Returns the value to publish, if any.
+    pub fn update(&mut self, value: f64, now: Instant) -> Option<f64> {
+        self.sum += value;
+        self.count += 1;
+        let value = if self.policy.average {
+            self.sum / f64::from(self.count)
+        } else {
+            value
+        };
+        if let Some((last, time)) = self.last {
+            let elapsed = now.saturating_duration_since(time);
+            if elapsed < self.policy.min_interval {
+                return None;
+            }
+            let heartbeat = self.policy.max_interval.is_some_and(|max| elapsed >= max);
+            if !heartbeat && !self.policy.is_significant(last, value) {
+                return None;
+            }
+        }
+        self.last = Some((value, now));
+        self.sum = 0.0;
+        self.count = 0;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn default_publishes_every_value() {
+        let mut throttle = Throttle::new(PublishPolicy::default());
+        let start = Instant::now();
+        assert_eq!(throttle.update(1.0, start), Some(1.0));
+        assert_eq!(throttle.update(1.0, start), Some(1.0));
+        assert_eq!(throttle.update(2.0, secs(start, 1)), Some(2.0));
+    }
+
+    #[test]
+    fn min_interval() {
+        let mut throttle = Throttle::new(PublishPolicy {
+            min_interval: Duration::from_secs(10),
+            ..PublishPolicy::default()
+        });
+        let start = Instant::now();
+        assert_eq!(throttle.update(1.0, start), Some(1.0));
+        assert_eq!(throttle.update(2.0, secs(start, 5)), None);
+        assert_eq!(throttle.update(3.0, secs(start, 9)), None);
+        assert_eq!(throttle.update(4.0, secs(start, 10)), Some(4.0));
+        assert_eq!(throttle.update(5.0, secs(start, 15)), None);
+    }
+
+    #[test]
+    fn max_interval() {
+        let mut throttle = Throttle::new(PublishPolicy {
+            max_interval: Some(Duration::from_secs(60)),
+            deadband: Some(10.0),
+            ..PublishPolicy::default()
+        });
+        let start = Instant::now();
+        assert_eq!(throttle.update(100.0, start), Some(100.0));
+        assert_eq!(throttle.update(105.0, secs(start, 30)), None);
+        assert_eq!(throttle.update(105.0, secs(start, 60)), Some(105.0));
+        assert_eq!(throttle.update(106.0, secs(start, 61)), None);
+    }
+
+    #[test]
+    fn absolute_deadband() {
+        let mut throttle = Throttle::new(PublishPolicy {
+            deadband: Some(5.0),
+            ..PublishPolicy::default()
+        });
+        let start = Instant::now();
+        assert_eq!(throttle.update(100.0, start), Some(100.0));
+        assert_eq!(throttle.update(104.0, secs(start, 1)), None);
+        // The change has to exceed the deadband
+        assert_eq!(throttle.update(95.0, secs(start, 2)), None);
+        assert_eq!(throttle.update(94.0, secs(start, 3)), Some(94.0));
+        // Compared with the last published value, not the last read
+        assert_eq!(throttle.update(97.0, secs(start, 4)), None);
+        assert_eq!(throttle.update(99.5, secs(start, 5)), Some(99.5));
+    }
+
+    #[test]
+    fn zero_deadband_publishes_changes() {
+        let mut throttle = Throttle::new(PublishPolicy {
+            deadband: Some(0.0),
+            ..PublishPolicy::default()
+        });
+        let start = Instant::now();
+        assert_eq!(throttle.update(1.0, start), Some(1.0));
+        assert_eq!(throttle.update(1.0, secs(start, 1)), None);
+        assert_eq!(throttle.update(1.5, secs(start, 2)), Some(1.5));
+    }
+
+    #[test]
+    fn relative_deadband() {
+        let mut throttle = Throttle::new(PublishPolicy {
+            relative_deadband: Some(0.1),
+            ..PublishPolicy::default()
+        });
+        let start = Instant::now();
+        assert_eq!(throttle.update(1000.0, start), Some(1000.0));
+        assert_eq!(throttle.update(1090.0, secs(start, 1)), None);
+        assert_eq!(throttle.update(1101.0, secs(start, 2)), Some(1101.0));
+        // Negative values use the magnitude
+        let mut throttle = Throttle::new(PublishPolicy {
+            relative_deadband: Some(0.1),
+            ..PublishPolicy::default()
+        });
+        assert_eq!(throttle.update(-1000.0, start), Some(-1000.0));
+        assert_eq!(throttle.update(-950.0, secs(start, 1)), None);
+        assert_eq!(throttle.update(-850.0, secs(start, 2)), Some(-850.0));
+    }
+
+    #[test]
+    fn both_deadbands() {
+        let mut throttle = Throttle::new(PublishPolicy {
+            deadband: Some(20.0),
+            relative_deadband: Some(0.1),
+            ..PublishPolicy::default()
+        });
+        let start = Instant::now();
+        // The relative deadband is larger for large values
+        assert_eq!(throttle.update(1000.0, start), Some(1000.0));
+        assert_eq!(throttle.update(1050.0, secs(start, 1)), None);
+        assert_eq!(throttle.update(1150.0, secs(start, 2)), Some(1150.0));
+        // The absolute deadband is larger for small values
+        let mut throttle = Throttle::new(PublishPolicy {
+            deadband: Some(20.0),
+            relative_deadband: Some(0.1),
+            ..PublishPolicy::default()
+        });
+        assert_eq!(throttle.update(100.0, start), Some(100.0));
+        assert_eq!(throttle.update(115.0, secs(start, 1)), None);
+        assert_eq!(throttle.update(121.0, secs(start, 2)), Some(121.0));
+    }
+
+    #[test]
+    fn average() {
+        let mut throttle = Throttle::new(PublishPolicy {
+            min_interval: Duration::from_secs(10),
+            average: true,
+            ..PublishPolicy::default()
+        });
+        let start = Instant::now();
+        assert_eq!(throttle.update(100.0, start), Some(100.0));
+        assert_eq!(throttle.update(10.0, secs(start, 4)), None);
+        assert_eq!(throttle.update(20.0, secs(start, 8)), None);
+        assert_eq!(throttle.update(30.0, secs(start, 12)), Some(20.0));
+        // The average starts over after each message
+        assert_eq!(throttle.update(50.0, secs(start, 16)), None);
+        assert_eq!(throttle.update(70.0, secs(start, 22)), Some(60.0));
+    }
+
+    #[test]
+    fn average_with_deadband() {
+        let mut throttle = Throttle::new(PublishPolicy {
+            deadband: Some(10.0),
+            average: true,
+            ..PublishPolicy::default()
+        });
+        let start = Instant::now();
+        assert_eq!(throttle.update(100.0, start), Some(100.0));
+        // Values below the deadband are kept for the average
+        assert_eq!(throttle.update(105.0, secs(start, 1)), None);
+        assert_eq!(throttle.update(125.0, secs(start, 2)), Some(115.0));
+    }
+}
@@ -0,0 +1,86 @@
+//! Simulator of a KEBA P30 x-series charging station which serves the
+//! Modbus TCP register map. The binary controls the simulated vehicle
+//! with a script, tests can use [`Station`] directly.
+
+use std::{
+    future, io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    net::TcpListener,
+    time::{interval, Instant},
+};
+use tokio_modbus::{
+    prelude::*,
+    server::{
+        tcp::{accept_tcp_connection, Server},
+        Service,
+    },
+};
+use tracing::{error, info};
+
+pub use crate::station::{Station, Vehicle, WriteError};
+
+pub mod station;
+
+struct StationService {
+    station: Arc<Mutex<Station>>,
+}
+
+impl Service for StationService {
+    type Request = Request<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = future::Ready<Result<Self::Response, Self::Exception>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let mut station = self.station.lock().unwrap();
+        let result = match req {
+            Request::ReadHoldingRegisters(addr, len) => station
+                .read_registers(addr, len)
+                .map(Response::ReadHoldingRegisters)
+                .ok_or(ExceptionCode::IllegalDataAddress),
+            Request::WriteSingleRegister(addr, value) => station
+                .write(addr, value)
+                .map(|()| Response::WriteSingleRegister(addr, value))
+                .map_err(|e| match e {
+                    WriteError::IllegalAddress => ExceptionCode::IllegalDataAddress,
+                    WriteError::IllegalValue => ExceptionCode::IllegalDataValue,
+                }),
+            _ => Err(ExceptionCode::IllegalFunction),
+        };
+        future::ready(result)
+    }
+}
+
+/// Serve the register map of the charging station via Modbus TCP.
+pub async fn serve(listener: TcpListener, station: Arc<Mutex<Station>>) -> io::Result<()> {
+    let server = Server::new(listener);
+    let on_connected = |stream, socket_addr| {
+        let station = station.clone();
+        async move {
+            info!("Client {} connected", socket_addr);
+            accept_tcp_connection(stream, socket_addr, |_| {
+                Ok(Some(StationService {
+                    station: station.clone(),
+                }))
+            })
+        }
+    };
+    let on_process_error = |e| error!("Connection failed: {}", e);
+    server.serve(&on_connected, on_process_error).await
+}
+
+/// Advance the simulation once per second.
+pub async fn simulate(station: Arc<Mutex<Station>>) {
+    let mut interval = interval(Duration::from_secs(1));
+    let mut last = Instant::now();
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        station.lock().unwrap().tick(now - last);
+        last = now;
+    }
+}
@@ -0,0 +1,138 @@
+//! Simulator of a KEBA P30 x-series charging station which serves the
+//! Modbus TCP register map. The behavior of the vehicle is controlled
+//! by commands read from a script file or from stdin:
+//!
+//! - `plug [rfid]`: connect a vehicle, optionally with a RFID card
+//! - `unplug`: disconnect the vehicle
+//! - `charge`: the vehicle requests charging
+//! - `suspend`: the vehicle stops requesting charging
+//! - `error <code>`: put the charging station into the error state
+//! - `clear`: clear the error state
+//! - `sleep <seconds>`: wait before executing the next command
+//! - `status`: log the state of the charging station
+//!
+//! Empty lines and lines starting with `#` are ignored.
+
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::Parser;
+use nrg_keba_p30_simulator::{serve, simulate, Station, Vehicle};
+use tokio::{
+    fs::File,
+    io::{stdin, AsyncBufRead, AsyncBufReadExt, BufReader},
+    net::TcpListener,
+    time::sleep,
+};
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::FmtSubscriber;
+
+#[derive(Parser)]
+struct Args {
+    /// Address of the Modbus TCP server
+    #[arg(long, default_value = "127.0.0.1:5020")]
+    listen: SocketAddr,
+    /// Script controlling the vehicle. Commands are read from stdin
+    /// if no script is given.
+    script: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    let args = Args::parse();
+
+    let station = Arc::new(Mutex::new(Station::default()));
+
+    tokio::spawn(simulate(station.clone()));
+
+    let script: Box<dyn AsyncBufRead + Unpin + Send> = match &args.script {
+        Some(path) => Box::new(BufReader::new(File::open(path).await?)),
+        None => Box::new(BufReader::new(stdin())),
+    };
+    tokio::spawn(run_script(script, station.clone()));
+
+    let listener = TcpListener::bind(args.listen).await?;
+    info!("Listening on {}", args.listen);
+    serve(listener, station).await?;
+
+    Ok(())
+}
+
+async fn run_script(script: impl AsyncBufRead + Unpin, station: Arc<Mutex<Station>>) {
+    let mut lines = script.lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                error!("Reading script failed: {}", e);
+                break;
+            }
+        };
+        let mut args = line.split_whitespace();
+        let Some(cmd) = args.next().filter(|cmd| !cmd.starts_with('#')) else {
+            continue;
+        };
+        let arg = args.next();
+        if cmd == "sleep" {
+            match arg.and_then(|secs| secs.parse().ok()) {
+                Some(secs) => sleep(Duration::from_secs_f64(secs)).await,
+                None => warn!("Invalid sleep: {}", line),
+            }
+            continue;
+        }
+        let mut station = station.lock().unwrap();
+        match (cmd, arg) {
+            ("plug", rfid) => {
+                match rfid.map(|rfid| u32::from_str_radix(rfid, 16)) {
+                    None => station.plug(0),
+                    Some(Ok(rfid)) => station.plug(rfid),
+                    Some(Err(_)) => {
+                        warn!("Invalid RFID card: {}", line);
+                        continue;
+                    }
+                }
+                info!("Vehicle plugged in");
+            }
+            ("unplug", _) => {
+                station.unplug();
+                info!("Vehicle unplugged");
+            }
+            ("charge", _) => {
+                station.set_vehicle(Vehicle::Charging);
+                info!("Vehicle requests charging");
+            }
+            ("suspend", _) => {
+                station.set_vehicle(Vehicle::Suspended);
+                info!("Vehicle suspended charging");
+            }
+            ("error", Some(code)) => match code.parse() {
+                Ok(code) => {
+                    station.set_error(code);
+                    info!("Error {}", code);
+                }
+                Err(_) => warn!("Invalid error code: {}", line),
+            },
+            ("clear", _) => {
+                station.set_error(0);
+                info!("Error cleared");
+            }
+            ("status", _) => {}
+            _ => {
+                warn!("Unknown command: {}", line);
+                continue;
+            }
+        }
+        info!("{}", station.summary());
+    }
+}
@@ -0,0 +1,337 @@
+//! Simulated KEBA P30 x-series charging station.
+//!
+//! The register map follows the
+//! "P30 Charging Station Modbus TCP Programmers Guide V 1.06".
+//! All readable registers are 32 bit values stored in two registers.
+
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+// Readable
+pub const CHARGING_STATE: u16 = 1000;
+pub const CABLE_STATE: u16 = 1004;
+pub const ERROR_CODE: u16 = 1006;
+pub const CHARGING_CURRENT_PHASE_1: u16 = 1008;
+pub const CHARGING_CURRENT_PHASE_2: u16 = 1010;
+pub const CHARGING_CURRENT_PHASE_3: u16 = 1012;
+pub const SERIAL_NUMBER: u16 = 1014;
+pub const PRODUCT_TYPE_AND_FEATURES: u16 = 1016;
+pub const FIRMWARE_VERSION: u16 = 1018;
+pub const ACTIVE_POWER: u16 = 1020;
+pub const TOTAL_ENERGY: u16 = 1036;
+pub const VOLTAGE_PHASE_1: u16 = 1040;
+pub const VOLTAGE_PHASE_2: u16 = 1042;
+pub const VOLTAGE_PHASE_3: u16 = 1044;
+pub const POWER_FACTOR: u16 = 1046;
+pub const MAX_CHARGING_CURRENT: u16 = 1100;
+pub const MAX_SUPPORTED_CURRENT: u16 = 1110;
+pub const RFID_CARD: u16 = 1500;
+pub const CHARGED_ENERGY: u16 = 1502;
+pub const PHASE_SWITCHING_SOURCE: u16 = 1550;
+pub const PHASE_SWITCHING_STATE: u16 = 1552;
+pub const FAILSAFE_CURRENT_SETTING: u16 = 1600;
+pub const FAILSAFE_TIMEOUT_SETTING: u16 = 1602;
+
+// Writable
+pub const SET_CHARGING_CURRENT: u16 = 5004;
+pub const SET_ENERGY: u16 = 5010;
+pub const UNLOCK_PLUG: u16 = 5012;
+pub const ENABLE_CHARGING_STATION: u16 = 5014;
+pub const FAILSAFE_CURRENT: u16 = 5016;
+pub const FAILSAFE_TIMEOUT: u16 = 5018;
+pub const FAILSAFE_PERSIST: u16 = 5020;
+pub const SET_PHASE_SWITCH_TOGGLE: u16 = 5050;
+pub const TRIGGER_PHASE_SWITCH: u16 = 5052;
+
+/// Readable registers are located in this range. Addresses within
+/// this range which are not part of the register map read as zero.
+const READABLE: std::ops::Range<u16> = 1000..1700;
+
+const SERIAL: u32 = 22_123_456;
+/// Arbitrary values, the service publishes them as they are
+const PRODUCT_TYPE: u32 = 301_321;
+const FIRMWARE: u32 = 0x030A_1B00;
+const MAX_CURRENT: u32 = 32_000;
+const MIN_CURRENT: u32 = 6_000;
+const VOLTAGE: u32 = 230;
+/// Power factor in 0.1 %
+const POWER_FACTOR_VALUE: u32 = 990;
+/// Phase switching source which allows toggling via Modbus
+const SOURCE_MODBUS: u32 = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ChargingState {
+    NotReady = 1,
+    Ready = 2,
+    Active = 3,
+    Error = 4,
+    Suspended = 5,
+}
+
+/// Behavior of the simulated vehicle
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Vehicle {
+    /// No vehicle is connected
+    Disconnected,
+    /// The vehicle is connected and requests charging
+    Charging,
+    /// The vehicle is connected but does not request charging, e.g.
+    /// because the battery is full
+    Suspended,
+}
+
+/// Error raised when writing a register
+#[derive(Debug)]
+pub enum WriteError {
+    IllegalAddress,
+    IllegalValue,
+}
+
+pub struct Station {
+    vehicle: Vehicle,
+    /// Error code reported by the charging station. Any code other
+    /// than 0 puts the charging station into the error state.
+    error_code: u32,
+    rfid_card: u32,
+    enabled: bool,
+    plug_locked: bool,
+    /// Charging current in mA as written via Modbus
+    charging_current: u32,
+    last_current_write: Instant,
+    /// Energy limit of the session in 0.1 Wh, 0 if disabled
+    energy_limit: u32,
+    /// Total energy in 0.1 Wh
+    total_energy: f64,
+    /// Energy of the current session in 0.1 Wh
+    charged_energy: f64,
+    phase_switching_source: u32,
+    /// 0 for single phase and 1 for three phase charging
+    phases: u32,
+    failsafe_current: u32,
+    /// Failsafe timeout in seconds, 0 if disabled
+    failsafe_timeout: u32,
+}
+
+impl Default for Station {
+    fn default() -> Self {
+        Self {
+            vehicle: Vehicle::Disconnected,
+            error_code: 0,
+            rfid_card: 0,
+            enabled: true,
+            plug_locked: false,
+            charging_current: MAX_CURRENT,
+            last_current_write: Instant::now(),
+            energy_limit: 0,
+            total_energy: 0.0,
+            charged_energy: 0.0,
+            phase_switching_source: 0,
+            phases: 1,
+            failsafe_current: 0,
+            failsafe_timeout: 0,
+        }
+    }
+}
+
+impl Station {
+    fn charging_state(&self) -> ChargingState {
+        if self.error_code != 0 {
+            return ChargingState::Error;
+        }
+        match self.vehicle {
+            Vehicle::Disconnected => ChargingState::NotReady,
+            _ if !self.enabled || self.energy_limit_reached() => ChargingState::Suspended,
+            Vehicle::Charging if self.current() >= MIN_CURRENT => ChargingState::Active,
+            _ => ChargingState::Ready,
+        }
+    }
+
+    fn energy_limit_reached(&self) -> bool {
+        self.energy_limit != 0 && self.charged_energy >= self.energy_limit as f64
+    }
+
+    fn cable_state(&self) -> u32 {
+        match (self.vehicle, self.plug_locked) {
+            (Vehicle::Disconnected, _) => 1,
+            (_, false) => 5,
+            (_, true) => 7,
+        }
+    }
+
+    fn phase_count(&self) -> u32 {
+        if self.phases == 0 {
+            1
+        } else {
+            3
+        }
+    }
+
+    /// Charging current in mA taking the failsafe timeout into account
+    fn current(&self) -> u32 {
+        if self.failsafe_timeout != 0
+            && self.last_current_write.elapsed() > Duration::from_secs(self.failsafe_timeout.into())
+        {
+            self.failsafe_current
+        } else {
+            self.charging_current
+        }
+    }
+
+    /// Current per phase in mA
+    fn phase_current(&self, phase: u32) -> u32 {
+        if self.charging_state() == ChargingState::Active && phase < self.phase_count() {
+            self.current()
+        } else {
+            0
+        }
+    }
+
+    /// Active power in mW
+    fn active_power(&self) -> u32 {
+        (0..3)
+            .map(|phase| {
+                u64::from(self.phase_current(phase)) * u64::from(VOLTAGE * POWER_FACTOR_VALUE)
+                    / 1000
+            })
+            .sum::<u64>() as u32
+    }
+
+    /// Advance the simulation by the given time.
+    pub fn tick(&mut self, elapsed: Duration) {
+        // mW * s = 1 / 360 0.1 Wh
+        let energy = self.active_power() as f64 * elapsed.as_secs_f64() / 360_000.0;
+        self.total_energy += energy;
+        self.charged_energy += energy;
+    }
+
+    pub fn read(&self, addr: u16) -> Option<u32> {
+        let value = match addr {
+            CHARGING_STATE => self.charging_state() as u32,
+            CABLE_STATE => self.cable_state(),
+            ERROR_CODE => self.error_code,
+            CHARGING_CURRENT_PHASE_1 => self.phase_current(0),
+            CHARGING_CURRENT_PHASE_2 => self.phase_current(1),
+            CHARGING_CURRENT_PHASE_3 => self.phase_current(2),
+            SERIAL_NUMBER => SERIAL,
+            PRODUCT_TYPE_AND_FEATURES => PRODUCT_TYPE,
+            FIRMWARE_VERSION => FIRMWARE,
+            ACTIVE_POWER => self.active_power(),
+            TOTAL_ENERGY => self.total_energy as u32,
+            VOLTAGE_PHASE_1 | VOLTAGE_PHASE_2 | VOLTAGE_PHASE_3 => VOLTAGE,
+            POWER_FACTOR => {
+                if self.charging_state() == ChargingState::Active {
+                    POWER_FACTOR_VALUE
+                } else {
+                    0
+                }
+            }
+            MAX_CHARGING_CURRENT => match self.charging_state() {
+                ChargingState::Active => self.current(),
+                _ => 0,
+            },
+            MAX_SUPPORTED_CURRENT => MAX_CURRENT,
+            RFID_CARD => self.rfid_card,
+            CHARGED_ENERGY => self.charged_energy as u32,
+            PHASE_SWITCHING_SOURCE => self.phase_switching_source,
+            PHASE_SWITCHING_STATE => self.phases,
+            FAILSAFE_CURRENT_SETTING => self.failsafe_current,
+            FAILSAFE_TIMEOUT_SETTING => self.failsafe_timeout,
+            _ if READABLE.contains(&addr) => 0,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Read `len` registers starting at `addr`
+    pub fn read_registers(&self, addr: u16, len: u16) -> Option<Vec<u16>> {
+        (addr..addr.checked_add(len)?)
+            .map(|reg| {
+                // Every value occupies an even and the following odd register
+                let value = self.read(reg & !1)?;
+                Some(if reg % 2 == 0 {
+                    (value >> 16) as u16
+                } else {
+                    value as u16
+                })
+            })
+            .collect()
+    }
+
+    pub fn write(&mut self, addr: u16, value: u16) -> Result<(), WriteError> {
+        let value = u32::from(value);
+        match addr {
+            SET_CHARGING_CURRENT => {
+                if !(MIN_CURRENT..=MAX_CURRENT).contains(&value) && value != 0 {
+                    return Err(WriteError::IllegalValue);
+                }
+                self.charging_current = value;
+                self.last_current_write = Instant::now();
+            }
+            SET_ENERGY => self.energy_limit = value * 100,
+            UNLOCK_PLUG => {
+                if self.charging_state() == ChargingState::Active {
+                    return Err(WriteError::IllegalValue);
+                }
+                self.plug_locked = false;
+            }
+            ENABLE_CHARGING_STATION => self.enabled = value != 0,
+            FAILSAFE_CURRENT => self.failsafe_current = value,
+            FAILSAFE_TIMEOUT => self.failsafe_timeout = value,
+            // Restarts are not simulated
+            FAILSAFE_PERSIST => {}
+            SET_PHASE_SWITCH_TOGGLE => self.phase_switching_source = value,
+            TRIGGER_PHASE_SWITCH => {
+                if self.phase_switching_source != SOURCE_MODBUS || value > 1 {
+                    return Err(WriteError::IllegalValue);
+                }
+                // The contactors are only switched while not charging
+                if self.charging_state() != ChargingState::Active {
+                    self.phases = value;
+                }
+            }
+            _ => return Err(WriteError::IllegalAddress),
+        }
+        info!("Register {} = {}", addr, value);
+        Ok(())
+    }
+
+    /// Plug in the vehicle. The charged energy of the previous session
+    /// is reset and the plug gets locked.
+    pub fn plug(&mut self, rfid_card: u32) {
+        self.vehicle = Vehicle::Charging;
+        self.rfid_card = rfid_card;
+        self.charged_energy = 0.0;
+        self.plug_locked = true;
+    }
+
+    pub fn unplug(&mut self) {
+        self.vehicle = Vehicle::Disconnected;
+        self.rfid_card = 0;
+        self.plug_locked = false;
+    }
+
+    /// Change the behavior of a connected vehicle
+    pub fn set_vehicle(&mut self, vehicle: Vehicle) {
+        if self.vehicle != Vehicle::Disconnected {
+            self.vehicle = vehicle;
+        }
+    }
+
+    pub fn set_error(&mut self, error_code: u32) {
+        self.error_code = error_code;
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "state={:?} cable={} enabled={} current={} mA phases={} power={} mW charged={} Wh",
+            self.charging_state(),
+            self.cable_state(),
+            self.enabled,
+            self.current(),
+            self.phase_count(),
+            self.active_power(),
+            self.charged_energy as u32 / 10
+        )
+    }
+}
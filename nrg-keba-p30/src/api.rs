@@ -0,0 +1,173 @@
+//! REST API for scripts and clients which do not use Home Assistant.
+//!
+//! The API is described in `openapi.yaml` which is also served at
+//! `/openapi.yaml`. Commands sent via the API are executed exactly
+//! like commands received via MQTT.
+
+use std::{
+    collections::BTreeMap,
+    sync::{atomic::Ordering, Arc, Mutex as StdMutex},
+};
+
+use nrg_mqtt::client::MqttClient;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    execute,
+    http::{Request, Response},
+    phase::PhaseMode,
+    report,
+    session::Session,
+    Command, CommandError, ErrorState, State,
+};
+
+const OPENAPI: &str = include_str!("../openapi.yaml");
+
+/// Charging stations by their object id
+pub type Stations = StdMutex<BTreeMap<String, Arc<State>>>;
+
+#[derive(Serialize)]
+struct StationStatus {
+    id: String,
+    available: bool,
+    enabled: bool,
+    /// Charging current as requested in mA
+    charging_current: Option<u16>,
+    phase_mode: Option<PhaseMode>,
+    /// Latest values read from the charging station. `None` until
+    /// the charging station has been polled successfully.
+    status: Option<PolledStatus>,
+}
+
+#[derive(Serialize)]
+struct PolledStatus {
+    charging_state: String,
+    cable_state: String,
+    error: ErrorState,
+    /// Active power in W
+    active_power: f64,
+    /// Total energy in Wh
+    total_energy: f64,
+    /// Energy of the current session in Wh
+    session_energy: f64,
+    /// Charging current per phase in A
+    currents: [f64; 3],
+    /// Voltage per phase in V
+    voltages: [u32; 3],
+    /// Power factor in %
+    power_factor: f64,
+    phases: u16,
+}
+
+impl StationStatus {
+    fn new(id: &str, state: &State) -> Self {
+        let status = state.status.lock().unwrap();
+        Self {
+            id: id.to_owned(),
+            available: state.available.load(Ordering::Relaxed),
+            enabled: state.enabled.load(Ordering::Relaxed),
+            charging_current: *state.charging_current.lock().unwrap(),
+            phase_mode: *state.phase_mode.lock().unwrap(),
+            status: status.as_ref().map(|status| PolledStatus {
+                charging_state: status.charging_state.to_string(),
+                cable_state: status.cable_state.to_string(),
+                error: status.error_code.into(),
+                active_power: status.active_power as f64 / 1000.0,
+                total_energy: status.total_energy as f64 / 10.0,
+                session_energy: status.charged_energy as f64 / 10.0,
+                currents: status.currents.map(|current| current as f64 / 1000.0),
+                voltages: status.voltages,
+                power_factor: status.power_factor as f64 / 10.0,
+                phases: status.phases.count(),
+            }),
+        }
+    }
+}
+
+pub async fn handle(mqtt: &MqttClient, stations: &Stations, request: Request) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["openapi.yaml"]) => Response {
+            status: 200,
+            content_type: "application/yaml",
+            body: OPENAPI.into(),
+        },
+        ("GET", ["stations"]) => {
+            let stations = stations.lock().unwrap();
+            let list: Vec<_> = stations
+                .iter()
+                .map(|(id, state)| StationStatus::new(id, state))
+                .collect();
+            Response::json(200, &list)
+        }
+        (method, ["stations", id, rest @ ..]) => {
+            let Some(state) = stations.lock().unwrap().get(*id).cloned() else {
+                return Response::error(404, format!("Unknown charging station {}", id));
+            };
+            match (method, rest) {
+                ("GET", []) => Response::json(200, &StationStatus::new(id, &state)),
+                ("GET", ["sessions"]) => sessions(&state),
+                ("PUT", ["enabled"]) => {
+                    command(mqtt, &state, &request.body, Command::SetEnabled).await
+                }
+                ("PUT", ["charging-current"]) => {
+                    command(mqtt, &state, &request.body, Command::SetChargingCurrent).await
+                }
+                ("PUT", ["phase-mode"]) => {
+                    command(mqtt, &state, &request.body, Command::SetPhaseMode).await
+                }
+                ("PUT", ["energy-limit"]) => {
+                    command(mqtt, &state, &request.body, Command::SetEnergy).await
+                }
+                ("POST", ["unlock-plug"]) => run(mqtt, &state, Command::UnlockPlug).await,
+                (
+                    _,
+                    []
+                    | ["sessions" | "enabled" | "charging-current" | "phase-mode" | "energy-limit"
+                    | "unlock-plug"],
+                ) => Response::error(405, "Method not allowed"),
+                _ => Response::error(404, "Not found"),
+            }
+        }
+        _ => Response::error(404, "Not found"),
+    }
+}
+
+/// Completed sessions from the sessions file. Without a sessions file
+/// only the last session since the start of the service is known.
+fn sessions(state: &State) -> Response {
+    match &state.sessions_file {
+        Some(path) => match Session::read_all(path) {
+            Ok(sessions) => Response::json(200, &sessions),
+            Err(e) => Response::error(500, format!("Reading sessions failed: {}", e)),
+        },
+        None => {
+            let last_session = state.last_session.lock().unwrap();
+            Response::json(200, &last_session.iter().collect::<Vec<_>>())
+        }
+    }
+}
+
+async fn command<P: DeserializeOwned>(
+    mqtt: &MqttClient,
+    state: &State,
+    body: &[u8],
+    cmd: fn(P) -> Command,
+) -> Response {
+    match serde_json::from_slice(body) {
+        Ok(payload) => run(mqtt, state, cmd(payload)).await,
+        Err(e) => Response::error(400, format!("Invalid request body: {}", e)),
+    }
+}
+
+async fn run(mqtt: &MqttClient, state: &State, cmd: Command) -> Response {
+    let name = cmd.name();
+    let result = execute(mqtt, state, cmd).await;
+    let status = match &result {
+        Ok(_) => 200,
+        Err(CommandError::Invalid(_)) => 400,
+        Err(CommandError::Wallbox(_)) => 502,
+        Err(CommandError::Mqtt(_)) => 500,
+    };
+    Response::json(status, &report(mqtt, state, name, result).await)
+}
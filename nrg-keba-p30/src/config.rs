@@ -1,21 +1,298 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 use nrg_hass::config::HomeAssistantConfig;
 use nrg_mqtt::config::MqttConfig;
 use serde::Deserialize;
+use thiserror::Error;
 
 #[derive(Debug, Deserialize)]
+#[serde(try_from = "ConfigFile")]
 pub struct Config {
-    pub modbus: ModbusConfig,
     pub mqtt: MqttConfig,
+    pub stations: Vec<StationConfig>,
+    pub api: Option<ApiConfig>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("No charging station configured")]
+    NoStation,
+    #[error(
+        "A charging station configured without [[station]] requires [modbus] and [home-assistant]"
+    )]
+    IncompleteStation,
+}
+
+/// Layout of the config file. Charging stations are configured as
+/// `[[station]]` tables. A single charging station configured with the
+/// top-level `[modbus]`, `[home-assistant]`, `[phase-switching]`,
+/// `[sessions]` and `[failsafe]` tables of earlier versions is
+/// accepted as well.
+#[derive(Deserialize)]
+struct ConfigFile {
+    mqtt: MqttConfig,
+    #[serde(default, rename = "station")]
+    stations: Vec<StationConfig>,
+    api: Option<ApiConfig>,
+    #[serde(flatten)]
+    legacy: LegacyStationConfig,
+}
+
+#[derive(Deserialize)]
+struct LegacyStationConfig {
+    modbus: Option<ModbusConfig>,
     #[serde(rename = "home-assistant")]
-    pub hass: HomeAssistantConfig,
+    hass: Option<HomeAssistantConfig>,
+    #[serde(rename = "phase-switching")]
+    phase_switching: Option<PhaseSwitchingConfig>,
+    sessions: Option<SessionsConfig>,
+    failsafe: Option<FailsafeConfig>,
+}
+
+impl TryFrom<ConfigFile> for Config {
+    type Error = ConfigError;
+
+    fn try_from(file: ConfigFile) -> Result<Self, Self::Error> {
+        let mut stations = file.stations;
+        let legacy = file.legacy;
+        match (legacy.modbus, legacy.hass) {
+            (Some(modbus), Some(hass)) => stations.push(StationConfig {
+                backend: BackendConfig::Modbus(modbus),
+                hass,
+                phase_switching: legacy.phase_switching.unwrap_or_default(),
+                sessions: legacy.sessions,
+                failsafe: legacy.failsafe,
+            }),
+            (None, None)
+                if legacy.phase_switching.is_none()
+                    && legacy.sessions.is_none()
+                    && legacy.failsafe.is_none() => {}
+            _ => return Err(ConfigError::IncompleteStation),
+        }
+        if stations.is_empty() {
+            return Err(ConfigError::NoStation);
+        }
+        Ok(Self {
+            mqtt: file.mqtt,
+            stations,
+            api: file.api,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
+pub struct ApiConfig {
+    /// Address of the HTTP server serving the REST API. The API can
+    /// control the charging stations, so it should only listen on
+    /// localhost unless a token is set.
+    pub listen: SocketAddr,
+    /// Token clients have to send as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StationConfig {
+    /// Either a `[station.modbus]` or a `[station.udp]` table
+    #[serde(flatten)]
+    pub backend: BackendConfig,
+    #[serde(rename = "home-assistant")]
+    pub hass: HomeAssistantConfig,
+    #[serde(default, rename = "phase-switching")]
+    pub phase_switching: PhaseSwitchingConfig,
+    pub sessions: Option<SessionsConfig>,
+    pub failsafe: Option<FailsafeConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendConfig {
+    /// Modbus TCP as supported by the x-series
+    Modbus(ModbusConfig),
+    /// KEBA UDP protocol as supported by the c-series and older models
+    Udp(UdpConfig),
+}
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct ModbusConfig {
     pub addr: SocketAddr,
     pub slave: u8,
     pub retry_delay: Duration,
     pub poll_delay: Duration,
+    /// Maximum time to wait for a response
+    #[serde(default = "nrg_modbus::connection::default_timeout")]
+    pub timeout: Duration,
+    /// Maximum number of unused registers between two registers
+    /// which are still read with a single request.
+    #[serde(default = "default_max_read_gap")]
+    pub max_read_gap: u16,
+}
+
+fn default_max_read_gap() -> u16 {
+    16
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UdpConfig {
+    pub addr: IpAddr,
+    #[serde(default = "default_udp_port")]
+    pub port: u16,
+    /// Local address of the UDP socket. The charging station always
+    /// sends its responses to port 7090.
+    #[serde(default = "default_udp_bind")]
+    pub bind: SocketAddr,
+    /// Maximum time to wait for a response
+    #[serde(default = "default_udp_timeout")]
+    pub timeout: Duration,
+    pub retry_delay: Duration,
+    pub poll_delay: Duration,
+}
+
+fn default_udp_port() -> u16 {
+    7090
+}
+
+fn default_udp_bind() -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), default_udp_port())
+}
+
+fn default_udp_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FailsafeConfig {
+    /// Charging current in mA which is used by the charging station
+    /// when this service stops communicating with it. 0 stops the
+    /// charging.
+    pub current: u16,
+    /// Time without communication after which the charging station
+    /// falls back to the failsafe current
+    pub timeout: Duration,
+    /// Keep the failsafe settings after a restart of the charging
+    /// station
+    #[serde(default)]
+    pub persist: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SessionsConfig {
+    /// Completed charging sessions are appended to this JSON lines file
+    pub file: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PhaseSwitchingConfig {
+    /// Minimum time between two phase switches
+    #[serde(default = "default_cooldown")]
+    pub cooldown: Duration,
+    /// Maximum time to wait for the charging to be paused before
+    /// the phase switch is triggered anyways
+    #[serde(default = "default_pause_timeout")]
+    pub pause_timeout: Duration,
+    /// Time to wait after triggering the phase switch before the
+    /// charging is resumed
+    #[serde(default = "default_settle_delay")]
+    pub settle_delay: Duration,
+}
+
+impl Default for PhaseSwitchingConfig {
+    fn default() -> Self {
+        Self {
+            cooldown: default_cooldown(),
+            pause_timeout: default_pause_timeout(),
+            settle_delay: default_settle_delay(),
+        }
+    }
+}
+
+fn default_cooldown() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_pause_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_settle_delay() -> Duration {
+    Duration::from_secs(5)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MQTT: &str = r#"
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        client_id = "nrg-keba-p30"
+        topic_prefix = "nrg"
+    "#;
+
+    #[test]
+    fn stations() {
+        let cfg: Config = toml::from_str(&format!(
+            r#"{MQTT}
+            [[station]]
+            modbus = {{ addr = "192.168.1.10:502", slave = 255, retry_delay = {{ secs = 1, nanos = 0 }}, poll_delay = {{ secs = 5, nanos = 0 }} }}
+            home-assistant = {{ discovery_prefix = "homeassistant", object_id = "garage", name = "Garage" }}
+
+            [[station]]
+            udp = {{ addr = "192.168.1.11", retry_delay = {{ secs = 1, nanos = 0 }}, poll_delay = {{ secs = 5, nanos = 0 }} }}
+            home-assistant = {{ discovery_prefix = "homeassistant", object_id = "carport", name = "Carport" }}
+            "#
+        ))
+        .unwrap();
+        assert_eq!(cfg.stations.len(), 2);
+        assert!(matches!(cfg.stations[0].backend, BackendConfig::Modbus(_)));
+        assert!(matches!(cfg.stations[1].backend, BackendConfig::Udp(_)));
+        assert_eq!(cfg.stations[1].hass.object_id, "carport");
+    }
+
+    #[test]
+    fn single_station_layout() {
+        let cfg: Config = toml::from_str(&format!(
+            r#"{MQTT}
+            [modbus]
+            addr = "192.168.1.10:502"
+            slave = 255
+            retry_delay = {{ secs = 1, nanos = 0 }}
+            poll_delay = {{ secs = 5, nanos = 0 }}
+
+            [home-assistant]
+            discovery_prefix = "homeassistant"
+            object_id = "wallbox"
+            name = "Wallbox"
+
+            [failsafe]
+            current = 6000
+            timeout = {{ secs = 60, nanos = 0 }}
+            "#
+        ))
+        .unwrap();
+        assert_eq!(cfg.stations.len(), 1);
+        let station = &cfg.stations[0];
+        assert_eq!(station.hass.object_id, "wallbox");
+        assert!(matches!(&station.backend, BackendConfig::Modbus(modbus) if modbus.slave == 255));
+        assert_eq!(station.failsafe.as_ref().unwrap().current, 6000);
+        assert_eq!(station.phase_switching.cooldown, default_cooldown());
+    }
+
+    #[test]
+    fn incomplete_single_station() {
+        let result = toml::from_str::<Config>(&format!(
+            r#"{MQTT}
+            [home-assistant]
+            discovery_prefix = "homeassistant"
+            object_id = "wallbox"
+            name = "Wallbox"
+            "#
+        ));
+        assert!(result.is_err());
+        assert!(toml::from_str::<Config>(MQTT).is_err());
+    }
 }
@@ -0,0 +1,118 @@
+//! Failsafe configuration of the charging station.
+//!
+//! The charging station falls back to the failsafe current if the
+//! charging current has not been written within the failsafe timeout.
+//! This service writes the charging current periodically so the
+//! fallback only happens when the service stops.
+
+use std::{sync::Arc, time::Duration};
+
+use thiserror::Error;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+use crate::{
+    config::FailsafeConfig,
+    phase::{apply_charging_current, MIN_CHARGING_CURRENT},
+    wallbox::{Wallbox, WallboxError},
+    State,
+};
+
+#[derive(Debug, Error)]
+pub enum FailsafeError {
+    #[error("Invalid failsafe current {0} mA")]
+    InvalidCurrent(u16),
+    #[error("Invalid failsafe timeout {0:?}")]
+    InvalidTimeout(Duration),
+    #[error("Failsafe setting {name} is {actual} instead of {expected}")]
+    Mismatch {
+        name: &'static str,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("{0}")]
+    Wallbox(#[from] WallboxError),
+}
+
+/// Write the failsafe settings to the charging station and verify
+/// them by reading them back.
+pub async fn configure(
+    wallbox: &dyn Wallbox,
+    cfg: &FailsafeConfig,
+    max_supported_current: u16,
+) -> Result<(), FailsafeError> {
+    if cfg.current != 0
+        && !(MIN_CHARGING_CURRENT..=max_supported_current.max(MIN_CHARGING_CURRENT))
+            .contains(&cfg.current)
+    {
+        return Err(FailsafeError::InvalidCurrent(cfg.current));
+    }
+    let timeout: u16 = match cfg.timeout.as_secs().try_into() {
+        Ok(timeout) if timeout > 0 => timeout,
+        _ => return Err(FailsafeError::InvalidTimeout(cfg.timeout)),
+    };
+
+    wallbox
+        .set_failsafe(cfg.current, timeout, cfg.persist)
+        .await?;
+
+    let (current, actual_timeout) = wallbox.failsafe().await?;
+    for (name, expected, actual) in [
+        ("failsafe_current", u32::from(cfg.current), current),
+        ("failsafe_timeout", u32::from(timeout), actual_timeout),
+    ] {
+        if actual != expected {
+            return Err(FailsafeError::Mismatch {
+                name,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    info!(
+        "Failsafe configured: {} mA after {} s",
+        cfg.current, timeout
+    );
+    Ok(())
+}
+
+/// Configure the failsafe settings and rewrite the charging current
+/// periodically so the charging station does not fall back to the
+/// failsafe current while this service is running. Configuring is
+/// retried until it succeeds.
+pub async fn heartbeat(state: Arc<State>, cfg: FailsafeConfig) {
+    let interval = (cfg.timeout / 3).max(Duration::from_secs(1));
+    let mut configured = false;
+    loop {
+        if !configured {
+            match configure(&*state.wallbox, &cfg, state.max_supported_current).await {
+                Ok(()) => configured = true,
+                Err(e @ (FailsafeError::InvalidCurrent(_) | FailsafeError::InvalidTimeout(_))) => {
+                    error!("{}, failsafe disabled", e);
+                    return;
+                }
+                Err(e) => error!("Configuring failsafe failed: {}", e),
+            }
+        }
+        sleep(interval).await;
+        if let Err(e) = refresh_charging_current(&state).await {
+            error!("Failsafe heartbeat failed: {}", e);
+        }
+    }
+}
+
+/// Write the requested charging current. As long as no charging current
+/// has been requested the charging station is not limited and the
+/// maximum supported current is written instead.
+async fn refresh_charging_current(state: &State) -> Result<(), WallboxError> {
+    let requested = state.charging_current.lock().unwrap().is_some();
+    if requested {
+        apply_charging_current(state).await
+    } else {
+        state
+            .wallbox
+            .set_charging_current(state.max_supported_current)
+            .await
+    }
+}
@@ -1,10 +1,14 @@
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc};
 
 use nrg_hass::{
     config::HomeAssistantConfig,
     models::{
+        binary_sensor::{BinarySensor, BinarySensorDeviceClass},
+        button::Button,
         device_class::DeviceClass,
+        entity_category::EntityCategory,
         number::{Number, NumberMode},
+        select::Select,
         sensor::Sensor,
         state_class::StateClass,
         switch::Switch,
@@ -12,34 +16,85 @@ use nrg_hass::{
     },
 };
 
-use crate::registers::{ACTIVE_POWER, CABLE_STATE, CHARGING_STATE, TOTAL_ENERGY};
+use crate::{
+    phase::{PhaseMode, MIN_CHARGING_CURRENT},
+    registers::{
+        ACTIVE_POWER, CABLE_STATE, CHARGED_ENERGY, CHARGING_CURRENT_PHASE_1,
+        CHARGING_CURRENT_PHASE_2, CHARGING_CURRENT_PHASE_3, CHARGING_STATE, ERROR_CODE,
+        MAX_ENERGY_LIMIT, PHASE_SWITCHING_STATE, POWER_FACTOR, SET_ENERGY, TOTAL_ENERGY,
+        UNLOCK_PLUG, VOLTAGE_PHASE_1, VOLTAGE_PHASE_2, VOLTAGE_PHASE_3,
+    },
+};
 
 pub struct Hass {
+    pub availability_topic: String,
     pub charging_state: Sensor,
     pub cable_state: Sensor,
     pub active_power: Sensor,
     pub total_energy: Sensor,
     pub enabled: Switch,
     pub charging_current: Number,
+    pub phases: Sensor,
+    pub phase_mode: Select,
+    pub current_phases: [Sensor; 3],
+    pub voltage_phases: [Sensor; 3],
+    pub power_factor: Sensor,
+    pub session_energy: Sensor,
+    pub last_session_energy: Sensor,
+    pub last_session_start: Sensor,
+    pub last_session_end: Sensor,
+    pub last_session_peak_power: Sensor,
+    pub last_session_rfid_card: Sensor,
+    pub energy_limit: Number,
+    pub unlock_plug: Button,
+    pub command_result: Sensor,
+    pub error_code: Sensor,
+    pub error: BinarySensor,
+}
+
+/// Device information read from the charging station
+pub struct DeviceInfo {
+    pub configuration_url: String,
+    /// Model name, e.g. "KeContact P30"
+    pub model: String,
+    /// Product description as logged on startup
+    pub product: String,
+    /// Product code, only reported via UDP
+    pub hw_version: Option<String>,
+    pub serial_number: String,
+    pub firmware_version: String,
+}
+
+/// URL of the web interface of the charging station. IPv6 addresses
+/// are enclosed in brackets.
+pub fn configuration_url(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("http://{}/", ip),
+        IpAddr::V6(ip) => format!("http://[{}]/", ip),
+    }
 }
 
 impl Hass {
-    pub fn new(cfg: &HomeAssistantConfig) -> Self {
-        let device = Arc::new(
-            nrg_hass::models::device::Device::builder()
-                .configuration_url("http://192.168.178.40/")
-                .identifiers(vec![cfg.object_id.clone()])
-                .manufacturer("KEBA")
-                .model("P30 X")
-                .name(&cfg.name)
-                // sw_version
-                // via_device
-                .build()
-                .unwrap(),
-        );
+    pub fn new(cfg: &HomeAssistantConfig, info: &DeviceInfo) -> Self {
+        let availability_topic = format!("nrg/charging_station/{}/availability", cfg.object_id);
+
+        let mut device = nrg_hass::models::device::Device::builder();
+        device
+            .configuration_url(&info.configuration_url)
+            .identifiers(vec![cfg.object_id.clone()])
+            .manufacturer("KEBA")
+            .model(&info.model)
+            .name(&cfg.name)
+            .serial_number(&info.serial_number)
+            .sw_version(&info.firmware_version);
+        if let Some(hw_version) = &info.hw_version {
+            device.hw_version(hw_version);
+        }
+        let device = Arc::new(device.build().unwrap());
 
         let charging_state = Sensor::builder()
             .device(device.clone())
+            .availability_topic(&availability_topic)
             .name(format!("{} Ladezustand", cfg.name))
             .object_id(format!("{}_{}", cfg.object_id, CHARGING_STATE.name))
             .state_topic(format!(
@@ -64,6 +119,7 @@ impl Hass {
 
         let cable_state = Sensor::builder()
             .device(device.clone())
+            .availability_topic(&availability_topic)
             .name(format!("{} Kabelzustand", cfg.name))
             .object_id(format!("{}_{}", cfg.object_id, CABLE_STATE.name))
             .state_topic(format!(
@@ -87,6 +143,7 @@ impl Hass {
 
         let active_power = Sensor::builder()
             .device(device.clone())
+            .availability_topic(&availability_topic)
             .name(format!("{} Leistung", cfg.name))
             .object_id(format!("{}_{}", cfg.object_id, ACTIVE_POWER.name))
             .state_topic(format!(
@@ -102,6 +159,7 @@ impl Hass {
 
         let total_energy = Sensor::builder()
             .device(device.clone())
+            .availability_topic(&availability_topic)
             .name(format!("{} Gesamtenergie", cfg.name))
             .object_id(format!("{}_{}", cfg.object_id, TOTAL_ENERGY.name))
             .state_topic(format!(
@@ -118,6 +176,7 @@ impl Hass {
 
         let enabled = Switch::builder()
             .device(device.clone())
+            .availability_topic(&availability_topic)
             .name(format!("{} Aktiv", cfg.name))
             .object_id(format!("{}_{}", cfg.object_id, "enabled"))
             .state_off("false")
@@ -142,9 +201,13 @@ impl Hass {
                 cfg.object_id
             ))
             .device(device.clone())
+            .availability_topic(&availability_topic)
             .name(format!("{} Ladestrom", cfg.name))
             .object_id(format!("{}_{}", cfg.object_id, "charging_current"))
-            .min(6000.0)
+            // Currents below the minimum charging current are only
+            // accepted in the automatic phase mode. Switching to a fixed
+            // phase mode afterwards raises them to the minimum.
+            .min(f64::from(MIN_CHARGING_CURRENT / 3))
             .max(16000.0)
             .mode(NumberMode::Slider)
             .device_class(DeviceClass::Power)
@@ -157,13 +220,266 @@ impl Hass {
             .build()
             .unwrap();
 
+        let phases = Sensor::builder()
+            .device(device.clone())
+            .availability_topic(&availability_topic)
+            .name(format!("{} Phasen", cfg.name))
+            .object_id(format!("{}_{}", cfg.object_id, PHASE_SWITCHING_STATE.name))
+            .state_topic(format!(
+                "nrg/charging_station/{}/{}",
+                cfg.object_id, PHASE_SWITCHING_STATE.name
+            ))
+            .unique_id(format!("{}_{}", cfg.object_id, PHASE_SWITCHING_STATE.name))
+            .icon("mdi:sine-wave")
+            .build()
+            .unwrap();
+
+        let phase_mode = Select::builder()
+            .device(device.clone())
+            .availability_topic(&availability_topic)
+            .name(format!("{} Phasenmodus", cfg.name))
+            .object_id(format!("{}_{}", cfg.object_id, "phase_mode"))
+            .options(
+                PhaseMode::OPTIONS
+                    .iter()
+                    .map(|mode| mode.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .state_topic(format!(
+                "nrg/charging_station/{}/{}",
+                cfg.object_id, "phase_mode"
+            ))
+            .value_template("{{ value_json }}")
+            .command_topic(format!(
+                "nrg/charging_station/{}/{}",
+                cfg.object_id, "set_phase_mode"
+            ))
+            .command_template(r#""{{ value }}""#)
+            .unique_id(format!("{}_{}", cfg.object_id, "phase_mode"))
+            .build()
+            .unwrap();
+
+        let measurement = |name: &str, reg_name: &str, device_class, unit| {
+            Sensor::builder()
+                .device(device.clone())
+                .availability_topic(&availability_topic)
+                .name(format!("{} {}", cfg.name, name))
+                .object_id(format!("{}_{}", cfg.object_id, reg_name))
+                .state_topic(format!(
+                    "nrg/charging_station/{}/{}",
+                    cfg.object_id, reg_name
+                ))
+                .device_class(device_class)
+                .state_class(StateClass::Measurement)
+                .unique_id(format!("{}_{}", cfg.object_id, reg_name))
+                .unit_of_measurement(unit)
+                .build()
+                .unwrap()
+        };
+
+        let current_phases = [
+            (CHARGING_CURRENT_PHASE_1, "Strom L1"),
+            (CHARGING_CURRENT_PHASE_2, "Strom L2"),
+            (CHARGING_CURRENT_PHASE_3, "Strom L3"),
+        ]
+        .map(|(reg, label)| {
+            measurement(
+                label,
+                reg.name,
+                DeviceClass::Current,
+                UnitOfMeasurement::Ampere,
+            )
+        });
+
+        let voltage_phases = [
+            (VOLTAGE_PHASE_1, "Spannung L1"),
+            (VOLTAGE_PHASE_2, "Spannung L2"),
+            (VOLTAGE_PHASE_3, "Spannung L3"),
+        ]
+        .map(|(reg, label)| {
+            measurement(
+                label,
+                reg.name,
+                DeviceClass::Voltage,
+                UnitOfMeasurement::Volt,
+            )
+        });
+
+        let power_factor = measurement(
+            "Leistungsfaktor",
+            POWER_FACTOR.name,
+            DeviceClass::PowerFactor,
+            UnitOfMeasurement::Percentage,
+        );
+
+        let session_energy = Sensor::builder()
+            .device(device.clone())
+            .availability_topic(&availability_topic)
+            .name(format!("{} Energie Ladevorgang", cfg.name))
+            .object_id(format!("{}_{}", cfg.object_id, CHARGED_ENERGY.name))
+            .state_topic(format!(
+                "nrg/charging_station/{}/{}",
+                cfg.object_id, CHARGED_ENERGY.name
+            ))
+            .device_class(DeviceClass::Energy)
+            .state_class(StateClass::TotalIncreasing)
+            .unique_id(format!("{}_{}", cfg.object_id, CHARGED_ENERGY.name))
+            .unit_of_measurement(UnitOfMeasurement::WattHours)
+            .icon("mdi:ev-plug-type2")
+            .build()
+            .unwrap();
+
+        // All values of the last session are published as one JSON
+        // object and extracted by the value templates.
+        let last_session_topic = format!("nrg/charging_station/{}/last_session", cfg.object_id);
+        let last_session = |name: &str, key: &str| {
+            let mut builder = Sensor::builder();
+            builder
+                .device(device.clone())
+                .availability_topic(&availability_topic)
+                .name(format!("{} Letzter Ladevorgang {}", cfg.name, name))
+                .object_id(format!("{}_last_session_{}", cfg.object_id, key))
+                .state_topic(&last_session_topic)
+                .json_attributes_topic(&last_session_topic)
+                .unique_id(format!("{}_last_session_{}", cfg.object_id, key))
+                .value_template(format!("{{{{ value_json.{} }}}}", key));
+            builder
+        };
+
+        let last_session_energy = last_session("Energie", "energy")
+            .device_class(DeviceClass::Energy)
+            .unit_of_measurement(UnitOfMeasurement::WattHours)
+            .icon("mdi:ev-plug-type2")
+            .build()
+            .unwrap();
+
+        let last_session_start = last_session("Beginn", "start")
+            .device_class(DeviceClass::Timestamp)
+            .build()
+            .unwrap();
+
+        let last_session_end = last_session("Ende", "end")
+            .device_class(DeviceClass::Timestamp)
+            .build()
+            .unwrap();
+
+        let last_session_peak_power = last_session("Maximale Leistung", "peak_power")
+            .device_class(DeviceClass::Power)
+            .unit_of_measurement(UnitOfMeasurement::Watt)
+            .build()
+            .unwrap();
+
+        let last_session_rfid_card = last_session("RFID-Karte", "rfid_card")
+            .icon("mdi:card-account-details-outline")
+            .build()
+            .unwrap();
+
+        let energy_limit = Number::builder()
+            .command_topic(format!(
+                "nrg/charging_station/{}/{}",
+                cfg.object_id, SET_ENERGY.name
+            ))
+            .device(device.clone())
+            .availability_topic(&availability_topic)
+            .name(format!("{} Energielimit", cfg.name))
+            .object_id(format!("{}_{}", cfg.object_id, "energy_limit"))
+            .unique_id(format!("{}_{}", cfg.object_id, "energy_limit"))
+            .min(0.0)
+            .max(f64::from(MAX_ENERGY_LIMIT))
+            .mode(NumberMode::Box)
+            .device_class(DeviceClass::Energy)
+            .state_topic(format!(
+                "nrg/charging_station/{}/{}",
+                cfg.object_id, "energy_limit"
+            ))
+            .step(10.0)
+            .unit_of_measurement(UnitOfMeasurement::WattHours)
+            .build()
+            .unwrap();
+
+        let unlock_plug = Button::builder()
+            .command_topic(format!(
+                "nrg/charging_station/{}/{}",
+                cfg.object_id, UNLOCK_PLUG.name
+            ))
+            .device(device.clone())
+            .availability_topic(&availability_topic)
+            .name(format!("{} Stecker entriegeln", cfg.name))
+            .object_id(format!("{}_{}", cfg.object_id, UNLOCK_PLUG.name))
+            .unique_id(format!("{}_{}", cfg.object_id, UNLOCK_PLUG.name))
+            .icon("mdi:lock-open-variant-outline")
+            .build()
+            .unwrap();
+
+        let command_result_topic = format!("nrg/charging_station/{}/command_result", cfg.object_id);
+        let command_result = Sensor::builder()
+            .device(device.clone())
+            .availability_topic(&availability_topic)
+            .name(format!("{} Letzter Befehl", cfg.name))
+            .object_id(format!("{}_{}", cfg.object_id, "command_result"))
+            .state_topic(&command_result_topic)
+            .json_attributes_topic(&command_result_topic)
+            .value_template("{{ value_json.message }}")
+            .entity_category(EntityCategory::Diagnostic)
+            .unique_id(format!("{}_{}", cfg.object_id, "command_result"))
+            .build()
+            .unwrap();
+
+        // The error code is published as JSON object so further details
+        // can be added as attributes.
+        let error_code_topic =
+            format!("nrg/charging_station/{}/{}", cfg.object_id, ERROR_CODE.name);
+        let error_code = Sensor::builder()
+            .device(device.clone())
+            .availability_topic(&availability_topic)
+            .name(format!("{} Fehlercode", cfg.name))
+            .object_id(format!("{}_{}", cfg.object_id, ERROR_CODE.name))
+            .state_topic(&error_code_topic)
+            .json_attributes_topic(&error_code_topic)
+            .value_template("{{ value_json.code }}")
+            .entity_category(EntityCategory::Diagnostic)
+            .unique_id(format!("{}_{}", cfg.object_id, ERROR_CODE.name))
+            .icon("mdi:alert-circle-outline")
+            .build()
+            .unwrap();
+
+        let error = BinarySensor::builder()
+            .device(device.clone())
+            .availability_topic(&availability_topic)
+            .name(format!("{} Fehler", cfg.name))
+            .object_id(format!("{}_{}", cfg.object_id, "error"))
+            .state_topic(&error_code_topic)
+            .json_attributes_topic(&error_code_topic)
+            .value_template("{{ 'ON' if value_json.code != 0 else 'OFF' }}")
+            .device_class(BinarySensorDeviceClass::Problem)
+            .unique_id(format!("{}_{}", cfg.object_id, "error"))
+            .build()
+            .unwrap();
+
         Self {
+            availability_topic,
             charging_state,
             cable_state,
             active_power,
             total_energy,
             enabled,
             charging_current,
+            phases,
+            phase_mode,
+            current_phases,
+            voltage_phases,
+            power_factor,
+            session_energy,
+            last_session_energy,
+            last_session_start,
+            last_session_end,
+            last_session_peak_power,
+            last_session_rfid_card,
+            energy_limit,
+            unlock_plug,
+            command_result,
+            error_code,
+            error,
         }
     }
 }
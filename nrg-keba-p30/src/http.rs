@@ -0,0 +1,325 @@
+//! Minimal HTTP/1.1 server for the REST API.
+//!
+//! Every connection handles a single request and is closed after the
+//! response has been sent. This is plenty for scripts polling the
+//! charging stations and keeps the dependencies small. The size of a
+//! request and the time to send it are limited so a client can neither
+//! exhaust the memory nor hold connections open.
+
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::TcpListener,
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+/// Requests with a larger body are rejected
+const MAX_BODY_LEN: usize = 64 * 1024;
+/// Maximum length of the request line and of every header line
+const MAX_LINE_LEN: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+/// Time a client has to send the complete request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json(status: u16, value: &impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(value).unwrap_or_default(),
+        }
+    }
+    pub fn error(status: u16, message: impl Into<String>) -> Self {
+        #[derive(Serialize)]
+        struct Error {
+            error: String,
+        }
+        Self::json(
+            status,
+            &Error {
+                error: message.into(),
+            },
+        )
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Accept connections and pass every request to `handler`. If a
+/// `token` is given, requests without the header
+/// `Authorization: Bearer <token>` are rejected.
+pub async fn serve<H, F>(addr: SocketAddr, token: Option<String>, handler: H) -> io::Result<()>
+where
+    H: Fn(Request) -> F + Send + Sync + 'static,
+    F: Future<Output = Response> + Send,
+{
+    let listener = TcpListener::bind(addr).await?;
+    info!("REST API listening on {}", addr);
+    let handler = Arc::new(handler);
+    let token: Option<Arc<str>> = token.map(Into::into);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let handler = handler.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, token.as_deref(), &*handler).await {
+                debug!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection<S, H, F>(stream: S, token: Option<&str>, handler: &H) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    H: Fn(Request) -> F,
+    F: Future<Output = Response>,
+{
+    let mut stream = BufReader::new(stream);
+    let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => request?,
+        Err(_) => Err(Response::error(408, "Request timeout")),
+    };
+    let response = match request {
+        Ok(request) if !authorized(&request, token) => {
+            warn!("{} {} -> 401", request.method, request.path);
+            Response::error(401, "Missing or invalid token")
+        }
+        Ok(request) => {
+            let (method, path) = (request.method.clone(), request.path.clone());
+            let response = handler(request).await;
+            if response.status >= 400 {
+                warn!("{} {} -> {}", method, path, response.status);
+            } else {
+                debug!("{} {} -> {}", method, path, response.status);
+            }
+            response
+        }
+        Err(response) => response,
+    };
+    let header = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
+
+fn authorized(request: &Request, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let Some(bearer) = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Compare all bytes so the time does not reveal the matching prefix
+    bearer.len() == token.len()
+        && bearer
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Read a line of at most `MAX_LINE_LEN` bytes. Returns `false` if the
+/// line is longer.
+async fn read_line(
+    stream: &mut (impl AsyncBufRead + Unpin),
+    line: &mut String,
+) -> io::Result<bool> {
+    line.clear();
+    let len = (&mut *stream)
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_line(line)
+        .await?;
+    Ok(len <= MAX_LINE_LEN)
+}
+
+/// Read a request. Malformed requests result in an error response.
+async fn read_request(
+    stream: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<Result<Request, Response>> {
+    let mut line = String::new();
+    if !read_line(stream, &mut line).await? {
+        return Ok(Err(Response::error(414, "Request line too long")));
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Ok(Err(Response::error(400, "Invalid request line")));
+    };
+    let method = method.to_owned();
+    // The query string is not used by any endpoint
+    let path = path.split('?').next().unwrap_or_default().to_owned();
+
+    let mut content_length = 0;
+    let mut headers = Vec::new();
+    loop {
+        if !read_line(stream, &mut line).await? {
+            return Ok(Err(Response::error(431, "Header line too long")));
+        }
+        if line.is_empty() {
+            return Ok(Err(Response::error(400, "Incomplete request header")));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Ok(Err(Response::error(431, "Too many headers")));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+            if name == "content-length" {
+                match value.parse() {
+                    Ok(len) => content_length = len,
+                    Err(_) => return Ok(Err(Response::error(400, "Invalid Content-Length"))),
+                }
+            }
+            headers.push((name, value.to_owned()));
+        }
+    }
+    if content_length > MAX_BODY_LEN {
+        return Ok(Err(Response::error(413, "Request body too large")));
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+    Ok(Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Send `request` and return the status of the response
+    async fn status(request: &[u8], token: Option<&str>) -> u16 {
+        let (mut client, server) = duplex(1024 * 1024);
+        client.write_all(request).await.unwrap();
+        let handler = |request: Request| async move {
+            assert_eq!(request.method, "PUT");
+            assert_eq!(request.path, "/stations/wallbox/enabled");
+            assert_eq!(request.header("Content-Type"), Some("application/json"));
+            assert_eq!(request.body, b"true");
+            Response::json(200, &true)
+        };
+        handle_connection(server, token, &handler).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response[9..12].parse().unwrap()
+    }
+
+    fn request(headers: &str) -> Vec<u8> {
+        format!(
+            "PUT /stations/wallbox/enabled?x=1 HTTP/1.1\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: 4\r\n\
+            {}\r\n\
+            true",
+            headers
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn valid_request() {
+        assert_eq!(status(&request(""), None).await, 200);
+    }
+
+    #[tokio::test]
+    async fn token() {
+        let token = Some("secret");
+        assert_eq!(status(&request(""), token).await, 401);
+        let wrong = request("Authorization: Bearer secreT\r\n");
+        assert_eq!(status(&wrong, token).await, 401);
+        let valid = request("Authorization: Bearer secret\r\n");
+        assert_eq!(status(&valid, token).await, 200);
+    }
+
+    #[tokio::test]
+    async fn line_too_long() {
+        let path = "x".repeat(MAX_LINE_LEN);
+        let request = format!("GET /{} HTTP/1.1\r\n\r\n", path);
+        assert_eq!(status(request.as_bytes(), None).await, 414);
+        let header = format!("X-Long: {}\r\n", path);
+        assert_eq!(status(&self::request(&header), None).await, 431);
+    }
+
+    #[tokio::test]
+    async fn too_many_headers() {
+        let headers = "X-Header: 1\r\n".repeat(MAX_HEADERS);
+        assert_eq!(status(&request(&headers), None).await, 431);
+    }
+
+    #[tokio::test]
+    async fn body_too_large() {
+        let request = format!(
+            "PUT / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_LEN + 1
+        );
+        assert_eq!(status(request.as_bytes(), None).await, 413);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn incomplete_request_times_out() {
+        assert_eq!(status(b"GET /stations HTTP/1.1\r\n", None).await, 408);
+    }
+}
@@ -3,38 +3,59 @@ use std::{
     path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex as StdMutex,
     },
     time::Duration,
 };
 
+use chrono::Utc;
 use clap::Parser;
-use config::Config;
-use nrg_hass::{discovery::announce, state::publish_state};
+use config::{BackendConfig, Config, PhaseSwitchingConfig, StationConfig};
+use nrg_hass::{availability::publish_availability, discovery::announce, state::publish_state};
 use nrg_mqtt::{
     client::{CallbackSubscriber, MqttClient},
-    command::{Commands, JsonDecoder},
+    command::{Commands, JsonDecoder, TriggerDecoder},
 };
-use tokio::{sync::Mutex, time::sleep};
-use tokio_modbus::{
-    client::{tcp::connect_slave, Context},
-    Slave,
+use rumqttc::AsyncClient;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    task::JoinSet,
+    time::{sleep, Instant},
 };
-use tracing::{debug, info, Level};
+use tracing::{debug, error, info, info_span, warn, Instrument, Level};
 use tracing_subscriber::FmtSubscriber;
 
-use modbus::{read_register, write_register};
-use registers::{
-    ChargingState, ACTIVE_POWER, CABLE_STATE, CHARGING_STATE, ENABLE_CHARGING_STATION,
-    MAX_SUPPORTED_CURRENT, SET_CHARGING_CURRENT, TOTAL_ENERGY,
+use modbus::ModbusWallbox;
+use phase::{
+    apply_charging_current, begin_phase_switch, switch_phases, PhaseMode, MIN_CHARGING_CURRENT,
+};
+use registers::{ChargingState, ErrorCode, MAX_ENERGY_LIMIT, SET_ENERGY, UNLOCK_PLUG};
+use udp::{UdpSockets, UdpWallbox};
+use wallbox::{Status, Wallbox, WallboxError};
+
+use crate::{
+    api::Stations,
+    hass::{DeviceInfo, Hass},
+    session::{Session, SessionTracker},
 };
 
-use crate::hass::Hass;
-
+mod api;
 mod config;
+mod failsafe;
 mod hass;
+mod http;
 mod modbus;
+mod phase;
 mod registers;
+mod session;
+mod udp;
+mod wallbox;
+
+/// Delay before a failed charging station is restarted. The delay is
+/// doubled after every failure up to `MAX_RESTART_DELAY`.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
 
 #[derive(Parser)]
 struct Args {
@@ -44,12 +65,73 @@ struct Args {
 enum Command {
     SetEnabled(bool),
     SetChargingCurrent(u16),
+    SetPhaseMode(PhaseMode),
+    /// Energy limit of the current session in Wh. 0 disables the limit.
+    SetEnergy(u32),
+    UnlockPlug,
+}
+
+impl Command {
+    /// Name of the command as reported in the command result
+    fn name(&self) -> &'static str {
+        match self {
+            Self::SetEnabled(_) => "enabled",
+            Self::SetChargingCurrent(_) => "charging_current",
+            Self::SetPhaseMode(_) => "phase_mode",
+            Self::SetEnergy(_) => SET_ENERGY.name,
+            Self::UnlockPlug => UNLOCK_PLUG.name,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+enum CommandError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0}")]
+    Wallbox(#[from] WallboxError),
+    #[error("MQTT error: {0}")]
+    Mqtt(#[from] rumqttc::ClientError),
+}
+
+/// Error state as reported via MQTT
+#[derive(Serialize)]
+struct ErrorState {
+    code: u32,
+}
+
+impl From<ErrorCode> for ErrorState {
+    fn from(error_code: ErrorCode) -> Self {
+        Self { code: error_code.0 }
+    }
+}
+
+/// Result of a command as reported via MQTT and the REST API
+#[derive(Serialize)]
+struct CommandResult {
+    command: &'static str,
+    success: bool,
+    message: String,
 }
 
 struct State {
     hass: Hass,
+    /// Set while the charging station can be polled
+    available: AtomicBool,
+    /// Latest values read from the charging station
+    status: StdMutex<Option<Status>>,
+    last_session: StdMutex<Option<Session>>,
+    sessions_file: Option<PathBuf>,
     enabled: AtomicBool,
-    context: Mutex<Context>,
+    wallbox: Box<dyn Wallbox>,
+    max_supported_current: u16,
+    /// Charging current as requested via MQTT
+    charging_current: StdMutex<Option<u16>>,
+    phase_mode: StdMutex<Option<PhaseMode>>,
+    phase_switching_cfg: PhaseSwitchingConfig,
+    /// Set while a phase switch is in progress
+    phase_switching: AtomicBool,
+    last_phase_switch: StdMutex<Option<Instant>>,
 }
 
 #[tokio::main]
@@ -67,17 +149,112 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cfg: Config = toml::from_str(&data).expect("Error in config file");
 
     let mqtt = Arc::new(MqttClient::new(&cfg.mqtt));
+    let udp = Arc::new(UdpSockets::default());
+    let registry = Arc::new(Stations::default());
+
+    if let Some(api_cfg) = &cfg.api {
+        let mqtt = mqtt.clone();
+        let registry = registry.clone();
+        let addr = api_cfg.listen;
+        let token = api_cfg.token.clone();
+        if token.is_none() && !addr.ip().is_loopback() {
+            warn!("REST API listens on {} without a token", addr);
+        }
+        tokio::spawn(async move {
+            let result = http::serve(addr, token, move |request| {
+                let mqtt = mqtt.clone();
+                let registry = registry.clone();
+                async move { api::handle(&mqtt, &registry, request).await }
+            })
+            .await;
+            if let Err(e) = result {
+                error!("REST API failed: {}", e);
+            }
+        });
+    }
+
+    // Every charging station is run in its own task so a failing
+    // charging station does not affect the other ones.
+    let stations = cfg
+        .stations
+        .into_iter()
+        .map(|station_cfg| {
+            let span = info_span!("station", id = %station_cfg.hass.object_id);
+            tokio::spawn(
+                supervise_station(mqtt.clone(), udp.clone(), registry.clone(), station_cfg)
+                    .instrument(span),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    for station in stations {
+        if let Err(e) = station.await {
+            error!("Supervising charging station failed: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a charging station and restart it if it fails or panics.
+async fn supervise_station(
+    mqtt: Arc<MqttClient>,
+    udp: Arc<UdpSockets>,
+    registry: Arc<Stations>,
+    cfg: StationConfig,
+) {
+    let mut delay = RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        let station = tokio::spawn(
+            run_station(mqtt.clone(), udp.clone(), registry.clone(), cfg.clone()).in_current_span(),
+        );
+        match station.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Charging station failed: {}", e),
+            Err(e) => error!("Charging station task failed: {}", e),
+        }
+        registry.lock().unwrap().remove(&cfg.hass.object_id);
 
-    info!("Connecting to charging station {:?}...", cfg.modbus.addr);
-    let ctx = connect_slave(cfg.modbus.addr, Slave(cfg.modbus.slave)).await?;
-    let ctx = Mutex::new(ctx);
-    info!("Connected.");
+        // A charging station which ran for a while is restarted quickly
+        if started.elapsed() > MAX_RESTART_DELAY {
+            delay = RESTART_DELAY;
+        }
+        warn!("Restarting charging station in {:?}", delay);
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
 
-    let mut hass = Hass::new(&cfg.hass);
-    // Read max charging current from device
-    let max_supported_current = read_register(&ctx, MAX_SUPPORTED_CURRENT).await?;
+async fn run_station(
+    mqtt: Arc<MqttClient>,
+    udp: Arc<UdpSockets>,
+    registry: Arc<Stations>,
+    cfg: StationConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let wallbox: Box<dyn Wallbox> = match cfg.backend {
+        BackendConfig::Modbus(modbus_cfg) => Box::new(ModbusWallbox::connect(modbus_cfg).await),
+        BackendConfig::Udp(udp_cfg) => Box::new(UdpWallbox::new(&udp, udp_cfg).await?),
+    };
+
+    // The device information is needed for the discovery. Therefore
+    // the service waits until the charging station is reachable.
+    let (info, max_supported_current) = loop {
+        match read_device_info(&*wallbox).await {
+            Ok(result) => break result,
+            Err(e) => warn!("Reading device information failed: {}", e),
+        }
+        wallbox.reconnect().await;
+    };
+    info!(
+        "{}, serial number {}, firmware {}",
+        info.product, info.serial_number, info.firmware_version
+    );
+
+    let mut hass = Hass::new(&cfg.hass, &info);
     hass.charging_current.max = Some(max_supported_current.into());
     info!("Max charging current = {}", max_supported_current);
+    let max_supported_current = max_supported_current.try_into().unwrap_or(u16::MAX);
 
     announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.charging_state).await?;
     announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.cable_state).await?;
@@ -91,6 +268,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         &hass.charging_current,
     )
     .await?;
+    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.phases).await?;
+    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.phase_mode).await?;
+    for sensor in hass.current_phases.iter().chain(&hass.voltage_phases) {
+        announce(&mqtt, &cfg.hass, &cfg.hass.object_id, sensor).await?;
+    }
+    publish_availability(&mqtt, &hass.availability_topic, true).await?;
+    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.error_code).await?;
+    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.error).await?;
+    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.power_factor).await?;
+    for sensor in [
+        &hass.session_energy,
+        &hass.last_session_energy,
+        &hass.last_session_start,
+        &hass.last_session_end,
+        &hass.last_session_peak_power,
+        &hass.last_session_rfid_card,
+    ] {
+        announce(&mqtt, &cfg.hass, &cfg.hass.object_id, sensor).await?;
+    }
+    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.energy_limit).await?;
+    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.unlock_plug).await?;
+    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass.command_result).await?;
 
     let commands = Commands::new(mqtt.clone());
     commands
@@ -105,16 +304,54 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             JsonDecoder(Command::SetChargingCurrent),
         )
         .await?;
+    commands
+        .cmd(
+            &hass.phase_mode.command_topic,
+            JsonDecoder(Command::SetPhaseMode),
+        )
+        .await?;
+    commands
+        .cmd(
+            hass.energy_limit.command_topic.as_ref().unwrap(),
+            JsonDecoder(Command::SetEnergy),
+        )
+        .await?;
+    commands
+        .cmd(
+            &hass.unlock_plug.command_topic,
+            TriggerDecoder(|| Command::UnlockPlug),
+        )
+        .await?;
 
     let state = Arc::new(State {
-        context: ctx,
+        wallbox,
+        available: AtomicBool::new(true),
+        status: StdMutex::new(None),
+        last_session: StdMutex::new(None),
+        sessions_file: cfg.sessions.as_ref().map(|sessions| sessions.file.clone()),
         enabled: AtomicBool::new(true),
         hass,
+        max_supported_current,
+        charging_current: StdMutex::new(None),
+        phase_mode: StdMutex::new(None),
+        phase_switching_cfg: cfg.phase_switching.clone(),
+        phase_switching: AtomicBool::new(false),
+        last_phase_switch: StdMutex::new(None),
     });
 
+    registry
+        .lock()
+        .unwrap()
+        .insert(cfg.hass.object_id.clone(), state.clone());
+
+    // The subscriptions only hold weak references so the state is
+    // dropped when the charging station is restarted.
     mqtt.sub(
         state.hass.enabled.state_topic.as_ref().unwrap(),
-        CallbackSubscriber::new(state.clone(), |state, publish| {
+        CallbackSubscriber::new(Arc::downgrade(&state), |state, publish| {
+            let Some(state) = state.upgrade() else {
+                return;
+            };
             if publish.retain {
                 let enabled = publish.payload.as_ref() != b"false";
                 info!("state.enabled = {}", enabled);
@@ -124,18 +361,73 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     )
     .await?;
 
-    tokio::spawn(process_commands(commands, state.clone()));
+    mqtt.sub(
+        state.hass.charging_current.state_topic.as_ref().unwrap(),
+        CallbackSubscriber::new(Arc::downgrade(&state), |state, publish| {
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            if publish.retain {
+                if let Ok(charging_current) = serde_json::from_slice(&publish.payload) {
+                    info!("state.charging_current = {}", charging_current);
+                    *state.charging_current.lock().unwrap() = Some(charging_current);
+                }
+            }
+        }),
+    )
+    .await?;
+
+    mqtt.sub(
+        state.hass.phase_mode.state_topic.as_ref().unwrap(),
+        CallbackSubscriber::new(Arc::downgrade(&state), |state, publish| {
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            if publish.retain {
+                if let Ok(phase_mode) = serde_json::from_slice::<PhaseMode>(&publish.payload) {
+                    info!("state.phase_mode = {}", phase_mode);
+                    *state.phase_mode.lock().unwrap() = Some(phase_mode);
+                }
+            }
+        }),
+    )
+    .await?;
+
+    // The tasks are aborted when the charging station fails
+    let mut tasks = JoinSet::new();
+    tasks.spawn(process_commands(commands, state.clone()).in_current_span());
+    if let Some(failsafe_cfg) = cfg.failsafe.clone() {
+        tasks.spawn(failsafe::heartbeat(state.clone(), failsafe_cfg).in_current_span());
+    }
 
     // Sleep 1s to ensure the enabled state is refreshed from MQTT
     tokio::time::sleep(Duration::from_secs(1)).await;
 
     let mut previous_charging_state: Option<ChargingState> = None;
+    let mut sessions = SessionTracker::default();
+    let mut previous_error_code: Option<ErrorCode> = None;
 
     loop {
-        let charging_state = read_register(&state.context, CHARGING_STATE).await?;
+        let status = match state.wallbox.status().await {
+            Ok(status) => status,
+            Err(e) => {
+                warn!("Polling failed: {}", e);
+                if state.available.swap(false, Ordering::Relaxed) {
+                    publish_availability(&mqtt, &state.hass.availability_topic, false).await?;
+                }
+                state.wallbox.reconnect().await;
+                continue;
+            }
+        };
+        if !state.available.swap(true, Ordering::Relaxed) {
+            publish_availability(&mqtt, &state.hass.availability_topic, true).await?;
+        }
+        *state.status.lock().unwrap() = Some(status.clone());
+
+        let charging_state = status.charging_state;
         publish_state(&mqtt, &state.hass.charging_state, charging_state.as_ref()).await?;
 
-        let cable_state = read_register(&state.context, CABLE_STATE).await?;
+        let cable_state = status.cable_state;
         publish_state(&mqtt, &state.hass.cable_state, cable_state.as_ref()).await?;
 
         // The max_charging_current lags behind the value set by set_charging_current
@@ -144,7 +436,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         //let max_charging_current = read_register(&ctx, MAX_CHARGING_CURRENT).await?;
         //publish_state(&mqtt, &hass.charging_current, max_charging_current).await?;
 
-        let active_power = read_register(&state.context, ACTIVE_POWER).await?;
+        let error_code = status.error_code;
+        publish_state(&mqtt, &state.hass.error_code, ErrorState::from(error_code)).await?;
+        if previous_error_code != Some(error_code) {
+            if !error_code.is_error() {
+                if previous_error_code.is_some() {
+                    info!("Error cleared");
+                }
+            } else {
+                warn!("Charging station reports {}", error_code);
+            }
+            previous_error_code = Some(error_code);
+        }
+
+        let active_power = status.active_power;
         publish_state(
             &mqtt,
             &state.hass.active_power,
@@ -152,44 +457,111 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .await?;
 
-        let total_energy = read_register(&state.context, TOTAL_ENERGY).await?;
+        let total_energy = status.total_energy;
         publish_state(&mqtt, &state.hass.total_energy, total_energy as f64 / 10.0).await?;
 
+        for (sensor, current) in state.hass.current_phases.iter().zip(status.currents) {
+            publish_state(&mqtt, sensor, current as f64 / 1000.0).await?;
+        }
+
+        for (sensor, voltage) in state.hass.voltage_phases.iter().zip(status.voltages) {
+            publish_state(&mqtt, sensor, voltage).await?;
+        }
+
+        let power_factor = status.power_factor;
+        publish_state(&mqtt, &state.hass.power_factor, power_factor as f64 / 10.0).await?;
+
+        let charged_energy = status.charged_energy as f64 / 10.0;
+        publish_state(&mqtt, &state.hass.session_energy, charged_energy).await?;
+
+        if let Some(session) = sessions.update(
+            Utc::now(),
+            cable_state,
+            charging_state,
+            charged_energy,
+            active_power as f64 / 1000.0,
+            status.rfid_card,
+        ) {
+            info!(
+                "Charging session finished: {:.3} kWh, {} - {}",
+                session.energy / 1000.0,
+                session.start,
+                session.end
+            );
+            if let Some(sessions_cfg) = &cfg.sessions {
+                if let Err(e) = session.append_to(&sessions_cfg.file) {
+                    error!("Writing session to {:?} failed: {}", sessions_cfg.file, e);
+                }
+            }
+            // All last session sensors share the same state topic
+            publish_state(&mqtt, &state.hass.last_session_energy, &session).await?;
+            *state.last_session.lock().unwrap() = Some(session);
+        }
+
         debug!(
             "{:.3} W, {:.3} kWh",
             active_power as f32 / 1000.0,
             total_energy as f32 / 10000.0
         );
 
+        let phases = status.phases;
+        publish_state(&mqtt, &state.hass.phases, phases.count()).await?;
+
         let enabled = state.enabled.load(Ordering::Relaxed);
         publish_state(&mqtt, &state.hass.enabled, enabled).await?;
 
-        if charging_state == ChargingState::Active && !enabled {
+        let phase_mode = *state.phase_mode.lock().unwrap();
+        let charging_current = *state.charging_current.lock().unwrap();
+        if let Some(requested_phases) = phase_mode.and_then(|mode| mode.phases(charging_current)) {
+            if requested_phases != phases && begin_phase_switch(&state) {
+                let state = state.clone();
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = switch_phases(&state, requested_phases).await {
+                            error!("Phase switch failed: {}", e);
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+        }
+
+        // The charging is paused and resumed by the phase switch itself.
+        let phase_switching = state.phase_switching.load(Ordering::Relaxed);
+
+        if charging_state == ChargingState::Active && !enabled && !phase_switching {
             info!("Vehicle connected, charging paused");
-            write_register(&state.context, ENABLE_CHARGING_STATION, false as u16)
-                .await
-                .unwrap()
+            enable_charging_station(&state, false).await;
         }
 
-        if charging_state == ChargingState::Suspended && enabled {
+        if charging_state == ChargingState::Suspended && enabled && !phase_switching {
             info!("Vehicle connected, charging resumed");
-            write_register(&state.context, ENABLE_CHARGING_STATION, true as u16)
-                .await
-                .unwrap()
+            enable_charging_station(&state, true).await;
         }
 
         if charging_state == ChargingState::NotReady
             && previous_charging_state != Some(ChargingState::NotReady)
         {
             info!("Vehicle disconnected, charging enabled");
-            write_register(&state.context, ENABLE_CHARGING_STATION, true as u16)
-                .await
-                .unwrap()
+            enable_charging_station(&state, true).await;
         }
 
         previous_charging_state = Some(charging_state);
 
-        sleep(cfg.modbus.poll_delay).await;
+        sleep(state.wallbox.poll_delay()).await;
+    }
+}
+
+async fn read_device_info(wallbox: &dyn Wallbox) -> Result<(DeviceInfo, u32), WallboxError> {
+    Ok((
+        wallbox.device_info().await?,
+        wallbox.max_supported_current().await?,
+    ))
+}
+
+async fn enable_charging_station(state: &State, enable: bool) {
+    if let Err(e) = state.wallbox.enable(enable).await {
+        error!("Enabling charging station failed: {}", e);
     }
 }
 
@@ -198,25 +570,111 @@ async fn process_commands(commands: Commands<Command>, state: Arc<State>) {
         let Some(cmd) = commands.next().await else {
             break;
         };
-        match cmd {
-            Command::SetEnabled(enabled) => {
-                state.enabled.store(enabled, Ordering::Relaxed);
-                publish_state(commands.client(), &state.hass.enabled, &enabled)
-                    .await
-                    .unwrap();
+        let name = cmd.name();
+        let result = execute(commands.client(), &state, cmd).await;
+        report(commands.client(), &state, name, result).await;
+    }
+}
+
+/// Execute a command received via MQTT or the REST API and publish the
+/// new state.
+async fn execute(
+    client: &AsyncClient,
+    state: &State,
+    cmd: Command,
+) -> Result<String, CommandError> {
+    match cmd {
+        Command::SetEnabled(enabled) => {
+            state.enabled.store(enabled, Ordering::Relaxed);
+            publish_state(client, &state.hass.enabled, &enabled).await?;
+            Ok(if enabled {
+                "Charging enabled".into()
+            } else {
+                "Charging disabled".into()
+            })
+        }
+        Command::SetChargingCurrent(charging_current) => {
+            let phase_mode = *state.phase_mode.lock().unwrap();
+            if charging_current < MIN_CHARGING_CURRENT && phase_mode != Some(PhaseMode::Auto) {
+                return Err(CommandError::Invalid(format!(
+                    "Charging currents below {} mA require the phase mode auto",
+                    MIN_CHARGING_CURRENT
+                )));
             }
-            Command::SetChargingCurrent(charging_current) => {
-                publish_state(
-                    commands.client(),
-                    &state.hass.charging_current,
-                    &charging_current,
-                )
-                .await
-                .unwrap();
-                write_register(&state.context, SET_CHARGING_CURRENT, charging_current)
-                    .await
-                    .unwrap();
+            *state.charging_current.lock().unwrap() = Some(charging_current);
+            publish_state(client, &state.hass.charging_current, &charging_current).await?;
+            apply_charging_current(state).await?;
+            Ok(format!("Charging current set to {} mA", charging_current))
+        }
+        Command::SetPhaseMode(phase_mode) => {
+            *state.phase_mode.lock().unwrap() = Some(phase_mode);
+            publish_state(client, &state.hass.phase_mode, &phase_mode).await?;
+            apply_charging_current(state).await?;
+            Ok(format!("Phase mode set to {}", phase_mode))
+        }
+        Command::SetEnergy(energy) => {
+            let message = set_energy(state, energy).await?;
+            publish_state(client, &state.hass.energy_limit, &energy).await?;
+            Ok(message)
+        }
+        Command::UnlockPlug => unlock_plug(state).await,
+    }
+}
+
+async fn set_energy(state: &State, energy: u32) -> Result<String, CommandError> {
+    if energy > MAX_ENERGY_LIMIT {
+        return Err(CommandError::Invalid(format!(
+            "Energy limit {} Wh exceeds the maximum of {} Wh",
+            energy, MAX_ENERGY_LIMIT
+        )));
+    }
+    // The Modbus register uses a resolution of 10 Wh
+    let energy = (energy + 5) / 10 * 10;
+    state.wallbox.set_energy(energy).await?;
+    Ok(if energy == 0 {
+        "Energy limit disabled".into()
+    } else {
+        format!("Energy limit set to {} Wh", energy)
+    })
+}
+
+async fn unlock_plug(state: &State) -> Result<String, CommandError> {
+    if state.wallbox.charging_state().await? == ChargingState::Active {
+        return Err(CommandError::Invalid(
+            "The plug can not be unlocked while charging".into(),
+        ));
+    }
+    state.wallbox.unlock_plug().await?;
+    Ok("Plug unlocked".into())
+}
+
+/// Log the result of a command and publish it via MQTT.
+async fn report(
+    client: &AsyncClient,
+    state: &State,
+    command: &'static str,
+    result: Result<String, CommandError>,
+) -> CommandResult {
+    let result = match result {
+        Ok(message) => {
+            info!("{}: {}", command, message);
+            CommandResult {
+                command,
+                success: true,
+                message,
+            }
+        }
+        Err(e) => {
+            error!("{} failed: {}", command, e);
+            CommandResult {
+                command,
+                success: false,
+                message: e.to_string(),
             }
         }
+    };
+    if let Err(e) = publish_state(client, &state.hass.command_result, &result).await {
+        error!("Publishing command result failed: {}", e);
     }
+    result
 }
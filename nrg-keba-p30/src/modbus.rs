@@ -1,82 +1,187 @@
-use std::marker::PhantomData;
+use std::time::Duration;
 
-use thiserror::Error;
+use async_trait::async_trait;
+use nrg_modbus::{connection::timeout, ModbusError, Register, RegisterBlock, Type};
 use tokio::sync::Mutex;
-use tokio_modbus::client::{Context, Reader, Writer};
+use tokio::time::sleep;
+use tokio_modbus::{
+    client::{tcp::connect_slave, Context},
+    Slave,
+};
+use tracing::info;
 
-pub trait Type: Sized {
-    const LEN: u16;
-    fn decode(data: &[u16]) -> Option<Self>;
-    fn encode(&self) -> Box<[u16]>;
-}
-
-impl Type for u16 {
-    const LEN: u16 = 1;
-    fn decode(data: &[u16]) -> Option<Self> {
-        let [w0] = *data else { return None };
-        Some(w0)
-    }
-    fn encode(&self) -> Box<[u16]> {
-        Box::new([*self])
-    }
-}
+use crate::{
+    config::ModbusConfig,
+    hass::{configuration_url, DeviceInfo},
+    registers::{
+        ChargingState, PhaseSwitchingSource, Phases, ACTIVE_POWER, CABLE_STATE, CHARGED_ENERGY,
+        CHARGING_CURRENT_PHASE_1, CHARGING_CURRENT_PHASE_2, CHARGING_CURRENT_PHASE_3,
+        CHARGING_STATE, ENABLE_CHARGING_STATION, ERROR_CODE, FAILSAFE_CURRENT,
+        FAILSAFE_CURRENT_SETTING, FAILSAFE_PERSIST, FAILSAFE_TIMEOUT, FAILSAFE_TIMEOUT_SETTING,
+        FIRMWARE_VERSION, MAX_SUPPORTED_CURRENT, PHASE_SWITCHING_SOURCE, PHASE_SWITCHING_STATE,
+        POWER_FACTOR, PRODUCT_TYPE_AND_FEATURES, RFID_CARD, SERIAL_NUMBER, SET_CHARGING_CURRENT,
+        SET_ENERGY, SET_PHASE_SWITCH_TOGGLE, TOTAL_ENERGY, TRIGGER_PHASE_SWITCH, UNLOCK_PLUG,
+        VOLTAGE_PHASE_1, VOLTAGE_PHASE_2, VOLTAGE_PHASE_3,
+    },
+    wallbox::{Status, Wallbox, WallboxError},
+};
 
-impl Type for u32 {
-    const LEN: u16 = 2;
-    fn decode(data: &[u16]) -> Option<Self> {
-        let [w1, w0] = *data else { return None };
-        Some(((w1 as u32) << 16) + (w0 as u32))
-    }
-    fn encode(&self) -> Box<[u16]> {
-        Box::new([(self << 16) as u16, *self as u16])
-    }
+/// Connect to the Modbus TCP server of the charging station
+async fn connect(cfg: &ModbusConfig) -> Context {
+    nrg_modbus::connection::connect(cfg.retry_delay, cfg.timeout, || {
+        info!("Connecting to charging station {:?}...", cfg.addr);
+        connect_slave(cfg.addr, Slave(cfg.slave))
+    })
+    .await
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Register<T: Type> {
-    pub name: &'static str,
-    pub addr: u16,
-    t: PhantomData<T>,
+/// Charging station controlled via Modbus TCP
+pub struct ModbusWallbox {
+    cfg: ModbusConfig,
+    context: Mutex<Context>,
+    poll_block: RegisterBlock,
 }
 
-impl<T: Type> Register<T> {
-    pub const fn new(name: &'static str, addr: u16) -> Self {
+impl ModbusWallbox {
+    pub async fn connect(cfg: ModbusConfig) -> Self {
+        let context = Mutex::new(connect(&cfg).await);
+        let mut poll_block = RegisterBlock::new(cfg.max_read_gap)
+            .with(CHARGING_STATE)
+            .with(CABLE_STATE)
+            .with(ACTIVE_POWER)
+            .with(TOTAL_ENERGY)
+            .with(POWER_FACTOR)
+            .with(PHASE_SWITCHING_STATE)
+            .with(ERROR_CODE)
+            .with(CHARGED_ENERGY)
+            .with(RFID_CARD);
+        for reg in CURRENT_REGS.into_iter().chain(VOLTAGE_REGS) {
+            poll_block.add(reg);
+        }
+        info!("Polling requires {} request(s)", poll_block.requests());
         Self {
-            name,
-            addr,
-            t: PhantomData,
+            cfg,
+            context,
+            poll_block,
         }
     }
-}
 
-pub async fn read_register<T: Type>(
-    ctx: &Mutex<Context>,
-    reg: Register<T>,
-) -> Result<T, ModbusError> {
-    let data = ctx
-        .lock()
-        .await
-        .read_holding_registers(reg.addr, T::LEN)
-        .await??;
-    Ok(T::decode(&data).unwrap())
-}
+    async fn read<T: Type>(&self, reg: Register<'_, T>) -> Result<T, ModbusError> {
+        let mut ctx = self.context.lock().await;
+        timeout(self.cfg.timeout, nrg_modbus::read(&mut *ctx, reg)).await
+    }
 
-#[derive(Debug, Error)]
-pub enum ModbusError {
-    #[error("Modbus error")]
-    Error(#[from] tokio_modbus::Error),
-    #[error("Modbus exception")]
-    Exception(#[from] tokio_modbus::ExceptionCode),
+    async fn write<T: Type>(&self, reg: Register<'_, T>, value: T) -> Result<(), ModbusError> {
+        let mut ctx = self.context.lock().await;
+        timeout(self.cfg.timeout, nrg_modbus::write(&mut *ctx, reg, &value)).await
+    }
 }
 
-pub async fn write_register(
-    ctx: &Mutex<Context>,
-    reg: Register<u16>,
-    value: u16,
-) -> Result<(), ModbusError> {
-    ctx.lock()
-        .await
-        .write_single_register(reg.addr, value)
-        .await??;
-    Ok(())
+const CURRENT_REGS: [Register<u32>; 3] = [
+    CHARGING_CURRENT_PHASE_1,
+    CHARGING_CURRENT_PHASE_2,
+    CHARGING_CURRENT_PHASE_3,
+];
+const VOLTAGE_REGS: [Register<u32>; 3] = [VOLTAGE_PHASE_1, VOLTAGE_PHASE_2, VOLTAGE_PHASE_3];
+
+#[async_trait]
+impl Wallbox for ModbusWallbox {
+    async fn device_info(&self) -> Result<DeviceInfo, WallboxError> {
+        // The Modbus interface is only available on the P30
+        let product_type = self.read(PRODUCT_TYPE_AND_FEATURES).await?;
+        Ok(DeviceInfo {
+            configuration_url: configuration_url(self.cfg.addr.ip()),
+            model: "KeContact P30".to_owned(),
+            product: format!("KeContact P30, product type {}", product_type),
+            hw_version: None,
+            serial_number: self.read(SERIAL_NUMBER).await?.to_string(),
+            firmware_version: format!("{:#010x}", self.read(FIRMWARE_VERSION).await?),
+        })
+    }
+    async fn max_supported_current(&self) -> Result<u32, WallboxError> {
+        Ok(self.read(MAX_SUPPORTED_CURRENT).await?)
+    }
+    async fn status(&self) -> Result<Status, WallboxError> {
+        let mut ctx = self.context.lock().await;
+        let regs = timeout(self.cfg.timeout, self.poll_block.read(&mut *ctx)).await?;
+        drop(ctx);
+        let mut currents = [0; 3];
+        for (current, reg) in currents.iter_mut().zip(CURRENT_REGS) {
+            *current = regs.value(reg)?;
+        }
+        let mut voltages = [0; 3];
+        for (voltage, reg) in voltages.iter_mut().zip(VOLTAGE_REGS) {
+            *voltage = regs.value(reg)?;
+        }
+        Ok(Status {
+            charging_state: regs.value(CHARGING_STATE)?,
+            cable_state: regs.value(CABLE_STATE)?,
+            error_code: regs.value(ERROR_CODE)?,
+            active_power: regs.value(ACTIVE_POWER)?,
+            total_energy: regs.value(TOTAL_ENERGY)?,
+            charged_energy: regs.value(CHARGED_ENERGY)?,
+            currents,
+            voltages,
+            power_factor: regs.value(POWER_FACTOR)?,
+            phases: regs.value(PHASE_SWITCHING_STATE)?,
+            rfid_card: regs.value(RFID_CARD)?,
+        })
+    }
+    async fn charging_state(&self) -> Result<ChargingState, WallboxError> {
+        Ok(self.read(CHARGING_STATE).await?)
+    }
+    async fn set_charging_current(&self, current: u16) -> Result<(), WallboxError> {
+        Ok(self.write(SET_CHARGING_CURRENT, current).await?)
+    }
+    async fn enable(&self, enable: bool) -> Result<(), WallboxError> {
+        Ok(self.write(ENABLE_CHARGING_STATION, enable as u16).await?)
+    }
+    async fn set_energy(&self, energy: u32) -> Result<(), WallboxError> {
+        // The register uses a resolution of 10 Wh
+        let value = (energy / 10).try_into().unwrap_or(u16::MAX);
+        Ok(self.write(SET_ENERGY, value).await?)
+    }
+    async fn unlock_plug(&self) -> Result<(), WallboxError> {
+        Ok(self.write(UNLOCK_PLUG, 0).await?)
+    }
+    async fn phases(&self) -> Result<Phases, WallboxError> {
+        Ok(self.read(PHASE_SWITCHING_STATE).await?)
+    }
+    async fn claim_phase_switching(&self) -> Result<(), WallboxError> {
+        let source = self.read(PHASE_SWITCHING_SOURCE).await?;
+        if source != PhaseSwitchingSource::Modbus {
+            info!("Phase switching source = {}, changing to Modbus", source);
+            self.write(SET_PHASE_SWITCH_TOGGLE, PhaseSwitchingSource::Modbus as u16)
+                .await?;
+        }
+        Ok(())
+    }
+    async fn trigger_phase_switch(&self, phases: Phases) -> Result<(), WallboxError> {
+        Ok(self.write(TRIGGER_PHASE_SWITCH, phases as u16).await?)
+    }
+    async fn set_failsafe(
+        &self,
+        current: u16,
+        timeout: u16,
+        persist: bool,
+    ) -> Result<(), WallboxError> {
+        self.write(FAILSAFE_CURRENT, current).await?;
+        self.write(FAILSAFE_TIMEOUT, timeout).await?;
+        self.write(FAILSAFE_PERSIST, persist as u16).await?;
+        Ok(())
+    }
+    async fn failsafe(&self) -> Result<(u32, u32), WallboxError> {
+        Ok((
+            self.read(FAILSAFE_CURRENT_SETTING).await?,
+            self.read(FAILSAFE_TIMEOUT_SETTING).await?,
+        ))
+    }
+    async fn reconnect(&self) {
+        sleep(self.cfg.retry_delay).await;
+        let ctx = connect(&self.cfg).await;
+        *self.context.lock().await = ctx;
+    }
+    fn poll_delay(&self) -> Duration {
+        self.cfg.poll_delay
+    }
 }
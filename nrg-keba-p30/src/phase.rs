@@ -0,0 +1,218 @@
+//! Switching between single and three phase charging.
+//!
+//! The charging station only accepts a phase switch while no charging
+//! process is active. Switching is therefore done by pausing the
+//! charging, triggering the switch and resuming the charging again.
+//! Since frequent switching wears out the contactors a cool-down time
+//! between two switches is enforced.
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use strum::{AsRefStr, Display};
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+
+use crate::{
+    registers::{ChargingState, Phases},
+    wallbox::WallboxError,
+    State,
+};
+
+/// The minimum charging current per phase in mA
+pub const MIN_CHARGING_CURRENT: u16 = 6000;
+
+#[derive(Copy, Clone, Debug, Display, AsRefStr, PartialEq, Serialize, Deserialize)]
+pub enum PhaseMode {
+    #[serde(rename = "1p")]
+    #[strum(serialize = "1p")]
+    One,
+    #[serde(rename = "3p")]
+    #[strum(serialize = "3p")]
+    Three,
+    /// Charging currents below the minimum charging current are
+    /// interpreted as three phase equivalent and result in single
+    /// phase charging with three times the current. This makes it
+    /// possible to charge with a PV surplus of less than 4.1 kW.
+    #[serde(rename = "auto")]
+    #[strum(serialize = "auto")]
+    Auto,
+}
+
+impl PhaseMode {
+    pub const OPTIONS: [PhaseMode; 3] = [PhaseMode::One, PhaseMode::Three, PhaseMode::Auto];
+
+    /// Phases which should be used by the charging station for the
+    /// given charging current.
+    pub fn phases(&self, charging_current: Option<u16>) -> Option<Phases> {
+        match self {
+            Self::One => Some(Phases::One),
+            Self::Three => Some(Phases::Three),
+            Self::Auto => charging_current.map(|current| {
+                if current < MIN_CHARGING_CURRENT {
+                    Phases::One
+                } else {
+                    Phases::Three
+                }
+            }),
+        }
+    }
+
+    /// Charging current which needs to be written to the charging
+    /// station for the given requested charging current.
+    pub fn charging_current(&self, phases: Phases, charging_current: u16, max: u16) -> u16 {
+        let current = match (self, phases) {
+            (Self::Auto, Phases::One) => charging_current.saturating_mul(3),
+            _ => charging_current,
+        };
+        current.clamp(MIN_CHARGING_CURRENT, max.max(MIN_CHARGING_CURRENT))
+    }
+}
+
+/// Write the requested charging current to the charging station
+/// taking the phase mode and current phases into account.
+pub async fn apply_charging_current(state: &State) -> Result<(), WallboxError> {
+    let Some(charging_current) = *state.charging_current.lock().unwrap() else {
+        return Ok(());
+    };
+    let mode = state.phase_mode.lock().unwrap().unwrap_or(PhaseMode::Three);
+    let phases = state.wallbox.phases().await?;
+    let current = mode.charging_current(phases, charging_current, state.max_supported_current);
+    state.wallbox.set_charging_current(current).await
+}
+
+/// Returns `true` if no phase switch is in progress and the cool-down
+/// time since the last phase switch has passed.
+pub fn phase_switch_ready(state: &State) -> bool {
+    if state.phase_switching.load(Ordering::Relaxed) {
+        return false;
+    }
+    match *state.last_phase_switch.lock().unwrap() {
+        Some(last) => last.elapsed() >= state.phase_switching_cfg.cooldown,
+        None => true,
+    }
+}
+
+/// Mark a phase switch as in progress. Returns `false` if the phase
+/// switch must not be started, see [`phase_switch_ready`].
+pub fn begin_phase_switch(state: &State) -> bool {
+    phase_switch_ready(state) && !state.phase_switching.swap(true, Ordering::Relaxed)
+}
+
+/// Switch the charging station to the given phases. If a charging
+/// process is active it is paused during the switch and resumed
+/// afterwards. The phase switch must have been started with
+/// [`begin_phase_switch`] so the poll loop does not interfere before
+/// this task runs.
+pub async fn switch_phases(state: &State, phases: Phases) -> Result<(), WallboxError> {
+    let result = switch_phases_inner(state, phases).await;
+    *state.last_phase_switch.lock().unwrap() = Some(Instant::now());
+    state.phase_switching.store(false, Ordering::Relaxed);
+    result
+}
+
+async fn switch_phases_inner(state: &State, phases: Phases) -> Result<(), WallboxError> {
+    let cfg = &state.phase_switching_cfg;
+
+    state.wallbox.claim_phase_switching().await?;
+
+    let charging_state = state.wallbox.charging_state().await?;
+    let paused = charging_state == ChargingState::Active;
+    if paused {
+        info!("Pausing charging for phase switch");
+        state.wallbox.enable(false).await?;
+        let deadline = Instant::now() + cfg.pause_timeout;
+        while state.wallbox.charging_state().await? == ChargingState::Active {
+            if Instant::now() >= deadline {
+                warn!("Charging did not pause within {:?}", cfg.pause_timeout);
+                break;
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    info!("Switching to {} phase(s)", phases.count());
+    state.wallbox.trigger_phase_switch(phases).await?;
+    sleep(cfg.settle_delay).await;
+
+    let new_phases = state.wallbox.phases().await?;
+    if new_phases != phases {
+        warn!(
+            "Phase switch failed: requested {} phase(s), station uses {} phase(s)",
+            phases.count(),
+            new_phases.count()
+        );
+    }
+
+    apply_charging_current(state).await?;
+
+    if paused && state.enabled.load(Ordering::Relaxed) {
+        info!("Resuming charging after phase switch");
+        state.wallbox.enable(true).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u16 = 16000;
+
+    #[test]
+    fn auto_below_minimum() {
+        let mode = PhaseMode::Auto;
+        assert_eq!(mode.phases(Some(5000)), Some(Phases::One));
+        assert_eq!(mode.charging_current(Phases::One, 5000, MAX), 15000);
+        // The station has not switched yet
+        assert_eq!(mode.charging_current(Phases::Three, 5000, MAX), 6000);
+    }
+
+    #[test]
+    fn auto_at_minimum() {
+        let mode = PhaseMode::Auto;
+        assert_eq!(mode.phases(Some(6000)), Some(Phases::Three));
+        assert_eq!(mode.charging_current(Phases::Three, 6000, MAX), 6000);
+    }
+
+    #[test]
+    fn auto_without_current() {
+        assert_eq!(PhaseMode::Auto.phases(None), None);
+    }
+
+    #[test]
+    fn above_maximum() {
+        let mode = PhaseMode::Auto;
+        assert_eq!(mode.phases(Some(20000)), Some(Phases::Three));
+        assert_eq!(mode.charging_current(Phases::Three, 20000, MAX), MAX);
+        assert_eq!(mode.charging_current(Phases::One, 5900, MAX), MAX);
+        assert_eq!(mode.charging_current(Phases::One, u16::MAX, MAX), MAX);
+    }
+
+    #[test]
+    fn fixed_one_phase() {
+        let mode = PhaseMode::One;
+        assert_eq!(mode.phases(Some(5000)), Some(Phases::One));
+        assert_eq!(mode.phases(Some(10000)), Some(Phases::One));
+        assert_eq!(mode.phases(None), Some(Phases::One));
+        assert_eq!(mode.charging_current(Phases::One, 5000, MAX), 6000);
+        assert_eq!(mode.charging_current(Phases::One, 10000, MAX), 10000);
+        assert_eq!(mode.charging_current(Phases::One, 20000, MAX), MAX);
+    }
+
+    #[test]
+    fn fixed_three_phases() {
+        let mode = PhaseMode::Three;
+        assert_eq!(mode.phases(Some(5000)), Some(Phases::Three));
+        assert_eq!(mode.phases(None), Some(Phases::Three));
+        assert_eq!(mode.charging_current(Phases::Three, 5000, MAX), 6000);
+        assert_eq!(mode.charging_current(Phases::Three, 10000, MAX), 10000);
+        assert_eq!(mode.charging_current(Phases::Three, 20000, MAX), MAX);
+    }
+
+    #[test]
+    fn maximum_below_minimum() {
+        assert_eq!(PhaseMode::Auto.charging_current(Phases::One, 5000, 0), 6000);
+    }
+}
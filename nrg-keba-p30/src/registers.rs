@@ -3,20 +3,21 @@
 //! https://www.keba.com/download/x/dea7ae6b84/kecontactp30modbustcp_pgen.pdf
 #![allow(dead_code)]
 
+use nrg_modbus::{Register, Type};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use strum::{AsRefStr, Display};
 
-use crate::modbus::{Register, Type};
-
 // Readable
 pub const CHARGING_STATE: Register<ChargingState> = Register::new("charging_state", 1000);
 pub const CABLE_STATE: Register<CableState> = Register::new("cable_state", 1004);
-pub const ERROR_CODE: Register<u32> = Register::new("cable_state", 1006);
+pub const ERROR_CODE: Register<ErrorCode> = Register::new("error_code", 1006);
 pub const CHARGING_CURRENT_PHASE_1: Register<u32> = Register::new("charging_current_phase_1", 1008);
 pub const CHARGING_CURRENT_PHASE_2: Register<u32> = Register::new("charging_current_phase_2", 1010);
 pub const CHARGING_CURRENT_PHASE_3: Register<u32> = Register::new("charging_current_phase_3", 1012);
 pub const SERIAL_NUMBER: Register<u32> = Register::new("serial_number", 1014);
+/// The guide does not document the encoding of the product type and
+/// the firmware version, both are used as they are.
 pub const PRODUCT_TYPE_AND_FEATURES: Register<u32> =
     Register::new("product_type_and_features", 1016);
 pub const FIRMWARE_VERSION: Register<u32> = Register::new("firmware_version", 1018);
@@ -29,9 +30,10 @@ pub const POWER_FACTOR: Register<u32> = Register::new("power_factor", 1046);
 pub const MAX_CHARGING_CURRENT: Register<u32> = Register::new("max_charging_current", 1100);
 pub const MAX_SUPPORTED_CURRENT: Register<u32> = Register::new("max_supported_current", 1110);
 pub const RFID_CARD: Register<u32> = Register::new("rfid_card", 1500);
-pub const CHARGED_ENERGY: Register<u32> = Register::new("charged_eneryg", 1502);
-pub const PHASE_SWITCHING_SOURCE: Register<u32> = Register::new("phase_switching_source", 1550);
-pub const PHASE_SWITCHING_STATE: Register<u32> = Register::new("phase_switching_state", 1552);
+pub const CHARGED_ENERGY: Register<u32> = Register::new("charged_energy", 1502);
+pub const PHASE_SWITCHING_SOURCE: Register<PhaseSwitchingSource> =
+    Register::new("phase_switching_source", 1550);
+pub const PHASE_SWITCHING_STATE: Register<Phases> = Register::new("phase_switching_state", 1552);
 pub const FAILSAFE_CURRENT_SETTING: Register<u32> = Register::new("failsafe_current_setting", 1600);
 pub const FAILSAFE_TIMEOUT_SETTING: Register<u32> = Register::new("failsafe_timeout_setting", 1602);
 
@@ -46,6 +48,10 @@ pub const FAILSAFE_CURRENT: Register<u16> = Register::new("failsafe_current", 50
 pub const FAILSAFE_TIMEOUT: Register<u16> = Register::new("failsafe_timeout", 5018);
 pub const FAILSAFE_PERSIST: Register<u16> = Register::new("failsafe_persist", 5020);
 
+/// The `SET_ENERGY` register is written in units of 10 Wh. This is
+/// the largest energy limit in Wh which can be set.
+pub const MAX_ENERGY_LIMIT: u32 = u16::MAX as u32 * 10;
+
 /// This register contains the state of the charging station.
 #[derive(Copy, Clone, FromPrimitive, Display, AsRefStr, PartialEq)]
 #[repr(u32)]
@@ -91,12 +97,19 @@ pub enum CableState {
     ChargingStationLocked = 3,
     /// Cable is connected to the charging station and the electric vehicle
     /// (not locked).
-    ElecticVehicle = 5,
+    ElectricVehicle = 5,
     /// Cable is connected to the charging station and the electric vehicle and
     /// locked (charging.
     ElectricVehicleLocked = 7,
 }
 
+impl CableState {
+    /// Returns `true` if the cable is connected to the electric vehicle.
+    pub fn vehicle_connected(&self) -> bool {
+        matches!(self, Self::ElectricVehicle | Self::ElectricVehicleLocked)
+    }
+}
+
 impl Type for CableState {
     const LEN: u16 = 2;
     fn decode(data: &[u16]) -> Option<Self> {
@@ -106,3 +119,112 @@ impl Type for CableState {
         (*self as u32).encode()
     }
 }
+
+/// This register contains the detail code of the error state
+/// (`ChargingState::Error`). The Modbus guide does not document the
+/// meaning of the codes, they are listed in the service manual of the
+/// charging station. Until that table is available the code is
+/// published as it is, without a description.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ErrorCode(pub u32);
+
+impl ErrorCode {
+    pub const NO_ERROR: ErrorCode = ErrorCode(0);
+
+    pub fn is_error(&self) -> bool {
+        *self != Self::NO_ERROR
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error code {}", self.0)
+    }
+}
+
+impl Type for ErrorCode {
+    const LEN: u16 = 2;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u32::decode(data).map(ErrorCode)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        self.0.encode()
+    }
+}
+
+/// This register contains the source which is allowed to toggle the
+/// phases of the charging station. The same values are used when
+/// writing the `SET_PHASE_SWITCH_TOGGLE` register.
+#[derive(Copy, Clone, FromPrimitive, Display, AsRefStr, PartialEq)]
+#[repr(u32)]
+pub enum PhaseSwitchingSource {
+    /// 0: No phase toggle source is available
+    None = 0,
+    /// 1: Toggle via OCPP
+    Ocpp = 1,
+    /// 2: Direct toggle command via REST API
+    RestApi = 2,
+    /// 3: Toggle via Modbus
+    Modbus = 3,
+    /// 4: Toggle via UDP
+    Udp = 4,
+}
+
+impl Type for PhaseSwitchingSource {
+    const LEN: u16 = 2;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u32::decode(data).and_then(PhaseSwitchingSource::from_u32)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        (*self as u32).encode()
+    }
+}
+
+/// This register contains the number of phases the charging station
+/// is currently using. The same values are used when writing the
+/// `TRIGGER_PHASE_SWITCH` register.
+#[derive(Copy, Clone, Debug, FromPrimitive, Display, AsRefStr, PartialEq)]
+#[repr(u32)]
+pub enum Phases {
+    /// 0: Charging with one phase
+    One = 0,
+    /// 1: Charging with three phases
+    Three = 1,
+}
+
+impl Phases {
+    pub fn count(&self) -> u16 {
+        match self {
+            Self::One => 1,
+            Self::Three => 3,
+        }
+    }
+}
+
+impl Type for Phases {
+    const LEN: u16 = 2;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u32::decode(data).and_then(Phases::from_u32)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        (*self as u32).encode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_code() {
+        let code = ErrorCode::decode(&[0, 0]).unwrap();
+        assert_eq!(code, ErrorCode::NO_ERROR);
+        assert!(!code.is_error());
+        // Codes are not interpreted, any value is kept as it is
+        let code = ErrorCode::decode(&[0x0001, 0x86a1]).unwrap();
+        assert_eq!(code, ErrorCode(100_001));
+        assert!(code.is_error());
+        assert_eq!(code.to_string(), "error code 100001");
+        assert_eq!(*code.encode(), [0x0001, 0x86a1]);
+    }
+}
@@ -0,0 +1,110 @@
+//! Detection and recording of charging sessions.
+//!
+//! A session starts when a vehicle is connected to the charging
+//! station and ends when it is disconnected again. The energy of a
+//! session is taken from the `CHARGED_ENERGY` register which is reset
+//! by the charging station whenever a new vehicle is connected.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::registers::{CableState, ChargingState};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Charged energy in Wh
+    pub energy: f64,
+    /// Peak charging power in W
+    pub peak_power: f64,
+    /// RFID card used to authorize the session
+    pub rfid_card: Option<String>,
+}
+
+impl Session {
+    /// Append the session to a JSON lines file.
+    pub fn append_to(&self, path: &Path) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+    }
+
+    /// Read all sessions from a JSON lines file. A missing file
+    /// contains no sessions.
+    pub fn read_all(path: &Path) -> io::Result<Vec<Session>> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        data.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+}
+
+struct ActiveSession {
+    start: DateTime<Utc>,
+    energy: f64,
+    peak_power: f64,
+    rfid_card: Option<String>,
+}
+
+#[derive(Default)]
+pub struct SessionTracker {
+    active: Option<ActiveSession>,
+}
+
+impl SessionTracker {
+    /// Feed the tracker with the current values of the charging station.
+    /// Returns the completed session when the vehicle got disconnected.
+    ///
+    /// - `charged_energy` is the energy of the current session in Wh.
+    /// - `power` is the current charging power in W.
+    /// - `rfid_card` is the raw value of the `RFID_CARD` register.
+    pub fn update(
+        &mut self,
+        now: DateTime<Utc>,
+        cable_state: CableState,
+        charging_state: ChargingState,
+        charged_energy: f64,
+        power: f64,
+        rfid_card: u32,
+    ) -> Option<Session> {
+        if !cable_state.vehicle_connected() {
+            let active = self.active.take()?;
+            return Some(Session {
+                start: active.start,
+                end: now,
+                energy: active.energy,
+                peak_power: active.peak_power,
+                rfid_card: active.rfid_card,
+            });
+        }
+        let active = self.active.get_or_insert(ActiveSession {
+            start: now,
+            energy: 0.0,
+            peak_power: 0.0,
+            rfid_card: None,
+        });
+        // The charged energy of the previous session is still reported
+        // until the charging station starts the new charging process.
+        if charging_state == ChargingState::Active || active.energy > 0.0 {
+            active.energy = active.energy.max(charged_energy);
+        }
+        active.peak_power = active.peak_power.max(power);
+        if rfid_card != 0 {
+            active.rfid_card = Some(format!("{:08X}", rfid_card));
+        }
+        None
+    }
+}
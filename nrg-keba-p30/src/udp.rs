@@ -0,0 +1,481 @@
+//! The KEBA UDP protocol is described in the
+//! "KeContact P20 / P30 UDP Programmers Guide V 2.01".
+//!
+//! Requests are plain text commands sent to port 7090 of the charging
+//! station. The charging station always sends its responses to port
+//! 7090 of the sender. All charging stations therefore share a single
+//! socket and the responses are dispatched by their source address.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use num_traits::FromPrimitive;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Mutex,
+    },
+    time::{sleep, sleep_until, timeout_at, Instant},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::UdpConfig,
+    hass::{configuration_url, DeviceInfo},
+    registers::{CableState, ChargingState, ErrorCode, PhaseSwitchingSource, Phases},
+    wallbox::{Status, Wallbox, WallboxError},
+};
+
+/// Minimum delay between two requests to the same charging station
+const REQUEST_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error)]
+pub enum UdpError {
+    #[error("UDP error: {0}")]
+    Io(#[from] io::Error),
+    #[error("No response to {0:?}")]
+    Timeout(String),
+    #[error("Command {0:?} failed: {1}")]
+    Rejected(String, String),
+    #[error("Invalid value for {0}")]
+    Decode(&'static str),
+    #[error("Phase switching is not supported by the firmware")]
+    PhaseSwitchingUnsupported,
+}
+
+struct Socket {
+    socket: UdpSocket,
+    peers: StdMutex<HashMap<SocketAddr, UnboundedSender<String>>>,
+}
+
+impl Socket {
+    async fn receive(self: Arc<Self>) {
+        let mut buf = [0; 1500];
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!("Receiving UDP message failed: {}", e);
+                    continue;
+                }
+            };
+            let msg = String::from_utf8_lossy(&buf[..len]).trim().to_owned();
+            match self.peers.lock().unwrap().get(&peer) {
+                Some(tx) => {
+                    let _ = tx.send(msg);
+                }
+                None => debug!("Ignoring UDP message from unknown peer {}", peer),
+            }
+        }
+    }
+}
+
+/// UDP sockets shared by all charging stations using the same local
+/// address
+#[derive(Default)]
+pub struct UdpSockets {
+    sockets: Mutex<HashMap<SocketAddr, Arc<Socket>>>,
+}
+
+impl UdpSockets {
+    async fn get(&self, bind: SocketAddr) -> io::Result<Arc<Socket>> {
+        let mut sockets = self.sockets.lock().await;
+        if let Some(socket) = sockets.get(&bind) {
+            return Ok(socket.clone());
+        }
+        let socket = Arc::new(Socket {
+            socket: UdpSocket::bind(bind).await?,
+            peers: StdMutex::new(HashMap::new()),
+        });
+        info!("Listening for UDP messages on {}", bind);
+        tokio::spawn(socket.clone().receive());
+        sockets.insert(bind, socket.clone());
+        Ok(socket)
+    }
+}
+
+/// Charging station controlled via the KEBA UDP protocol
+pub struct UdpWallbox {
+    cfg: UdpConfig,
+    peer: SocketAddr,
+    socket: Arc<Socket>,
+    /// Responses of the charging station and the time at which the
+    /// next request may be sent
+    rx: Mutex<(UnboundedReceiver<String>, Instant)>,
+}
+
+impl UdpWallbox {
+    pub async fn new(sockets: &UdpSockets, cfg: UdpConfig) -> Result<Self, UdpError> {
+        let socket = sockets.get(cfg.bind).await?;
+        let peer = SocketAddr::new(cfg.addr, cfg.port);
+        let (tx, rx) = unbounded_channel();
+        socket.peers.lock().unwrap().insert(peer, tx);
+        Ok(Self {
+            cfg,
+            peer,
+            socket,
+            rx: Mutex::new((rx, Instant::now())),
+        })
+    }
+
+    /// Send a request and wait for the first response accepted by
+    /// `parse`. Other messages, e.g. broadcasts of the charging
+    /// station, are skipped.
+    async fn request<T>(
+        &self,
+        request: &str,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<T, UdpError> {
+        let mut rx = self.rx.lock().await;
+        let (rx, next_request) = &mut *rx;
+        sleep_until(*next_request).await;
+        // Drop responses which arrived after a previous request timed out
+        while rx.try_recv().is_ok() {}
+        self.socket
+            .socket
+            .send_to(request.as_bytes(), self.peer)
+            .await?;
+        let deadline = Instant::now() + self.cfg.timeout;
+        let result = loop {
+            match timeout_at(deadline, rx.recv()).await {
+                Ok(Some(msg)) => {
+                    if let Some(value) = parse(&msg) {
+                        break Ok(value);
+                    }
+                    debug!("Skipping message {:?}", msg);
+                }
+                _ => break Err(UdpError::Timeout(request.to_owned())),
+            }
+        };
+        *next_request = Instant::now() + REQUEST_DELAY;
+        result
+    }
+
+    async fn report(&self, id: u8) -> Result<Report, UdpError> {
+        let id_str = id.to_string();
+        self.request(&format!("report {}", id), |msg| {
+            let value: Value = serde_json::from_str(msg).ok()?;
+            (value.get("ID")?.as_str()? == id_str).then_some(Report(value))
+        })
+        .await
+    }
+
+    async fn command(&self, command: String) -> Result<(), UdpError> {
+        let response = self
+            .request(&command, |msg| {
+                msg.starts_with("TCH-").then(|| msg.to_owned())
+            })
+            .await?;
+        if response.starts_with("TCH-OK") {
+            Ok(())
+        } else {
+            Err(UdpError::Rejected(command, response))
+        }
+    }
+}
+
+/// Response to a `report` request
+struct Report(Value);
+
+impl Report {
+    fn get<T: DeserializeOwned>(&self, name: &'static str) -> Result<T, UdpError> {
+        self.0
+            .get(name)
+            .and_then(|value| T::deserialize(value).ok())
+            .ok_or(UdpError::Decode(name))
+    }
+    fn get_enum<T: FromPrimitive>(&self, name: &'static str) -> Result<T, UdpError> {
+        T::from_u32(self.get(name)?).ok_or(UdpError::Decode(name))
+    }
+    /// Phases used by the charging station. Firmware versions without
+    /// phase switching support do not report it and always use three
+    /// phases.
+    fn phases(&self) -> Result<Phases, UdpError> {
+        match self.0.get("X2 phaseSwitch") {
+            Some(_) => self.get_enum("X2 phaseSwitch"),
+            None => Ok(Phases::Three),
+        }
+    }
+}
+
+#[async_trait]
+impl Wallbox for UdpWallbox {
+    async fn device_info(&self) -> Result<DeviceInfo, WallboxError> {
+        let report = self.report(1).await?;
+        // e.g. "KC-P30-EC240122-E0R"
+        let product: String = report.get("Product")?;
+        // e.g. "P30 v 3.10.16 (160603-105409)"
+        let firmware: String = report.get("Firmware")?;
+        Ok(DeviceInfo {
+            configuration_url: configuration_url(self.cfg.addr),
+            model: match product.split('-').nth(1) {
+                Some(family) => format!("KeContact {}", family),
+                None => product.clone(),
+            },
+            hw_version: Some(product.clone()),
+            product,
+            serial_number: report.get("Serial")?,
+            firmware_version: firmware
+                .split(" v ")
+                .nth(1)
+                .and_then(|version| version.split_whitespace().next())
+                .unwrap_or(&firmware)
+                .to_owned(),
+        })
+    }
+    async fn max_supported_current(&self) -> Result<u32, WallboxError> {
+        Ok(self.report(2).await?.get("Curr HW")?)
+    }
+    async fn status(&self) -> Result<Status, WallboxError> {
+        let report2 = self.report(2).await?;
+        let report3 = self.report(3).await?;
+        Ok(Status {
+            charging_state: report2.get_enum::<ChargingState>("State")?,
+            cable_state: report2.get_enum::<CableState>("Plug")?,
+            error_code: ErrorCode(report2.get("Error1")?),
+            active_power: report3.get("P")?,
+            total_energy: report3.get("E total")?,
+            charged_energy: report3.get("E pres")?,
+            currents: [report3.get("I1")?, report3.get("I2")?, report3.get("I3")?],
+            voltages: [report3.get("U1")?, report3.get("U2")?, report3.get("U3")?],
+            power_factor: report3.get("PF")?,
+            phases: report2.phases()?,
+            rfid_card: 0,
+        })
+    }
+    async fn charging_state(&self) -> Result<ChargingState, WallboxError> {
+        Ok(self.report(2).await?.get_enum("State")?)
+    }
+    async fn set_charging_current(&self, current: u16) -> Result<(), WallboxError> {
+        Ok(self.command(format!("curr {}", current)).await?)
+    }
+    async fn enable(&self, enable: bool) -> Result<(), WallboxError> {
+        Ok(self.command(format!("ena {}", enable as u8)).await?)
+    }
+    async fn set_energy(&self, energy: u32) -> Result<(), WallboxError> {
+        // The energy limit is given in 0.1 Wh
+        Ok(self
+            .command(format!("setenergy {}", u64::from(energy) * 10))
+            .await?)
+    }
+    async fn unlock_plug(&self) -> Result<(), WallboxError> {
+        Ok(self.command("unlock".into()).await?)
+    }
+    async fn phases(&self) -> Result<Phases, WallboxError> {
+        Ok(self.report(2).await?.phases()?)
+    }
+    async fn claim_phase_switching(&self) -> Result<(), WallboxError> {
+        let report = self.report(2).await?;
+        // Missing on firmware versions without phase switching support
+        if report.0.get("X2 phaseSwitch source").is_none() {
+            return Err(UdpError::PhaseSwitchingUnsupported.into());
+        }
+        let source: PhaseSwitchingSource = report.get_enum("X2 phaseSwitch source")?;
+        if source != PhaseSwitchingSource::Udp {
+            info!("Phase switching source = {}, changing to UDP", source);
+            self.command(format!("x2src {}", PhaseSwitchingSource::Udp as u8))
+                .await?;
+        }
+        Ok(())
+    }
+    async fn trigger_phase_switch(&self, phases: Phases) -> Result<(), WallboxError> {
+        Ok(self.command(format!("x2 {}", phases as u8)).await?)
+    }
+    async fn set_failsafe(
+        &self,
+        current: u16,
+        timeout: u16,
+        persist: bool,
+    ) -> Result<(), WallboxError> {
+        Ok(self
+            .command(format!(
+                "failsafe {} {} {}",
+                timeout, current, persist as u8
+            ))
+            .await?)
+    }
+    async fn failsafe(&self) -> Result<(u32, u32), WallboxError> {
+        let report = self.report(2).await?;
+        Ok((report.get("Curr FS")?, report.get("Tmo FS")?))
+    }
+    async fn reconnect(&self) {
+        // UDP is connectionless. Just wait a moment before the
+        // charging station is asked again.
+        sleep(self.cfg.retry_delay).await;
+    }
+    fn poll_delay(&self) -> Duration {
+        self.cfg.poll_delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const REPORT_1: &str = r#"{"ID": "1", "Product": "KC-P30-EC240122-E0R", "Serial": "16314582", "Firmware": "P30 v 3.10.16 (160603-105409)"}"#;
+    const REPORT_2: &str = r#"{"ID": "2", "State": 3, "Error1": 0, "Error2": 0, "Plug": 7, "Enable sys": 1, "Curr HW": 32000, "Curr user": 16000, "Curr FS": 6000, "Tmo FS": 60, "X2 phaseSwitch source": 4, "X2 phaseSwitch": 0}"#;
+    /// Report 2 of a firmware without phase switching support
+    const REPORT_2_WITHOUT_PHASE_SWITCHING: &str = r#"{"ID": "2", "State": 3, "Error1": 0, "Error2": 0, "Plug": 7, "Enable sys": 1, "Curr HW": 32000, "Curr user": 16000, "Curr FS": 6000, "Tmo FS": 60}"#;
+    const REPORT_3: &str = r#"{"ID": "3", "U1": 230, "U2": 231, "U3": 229, "I1": 16000, "I2": 0, "I3": 0, "P": 3643000, "PF": 990, "E pres": 12345, "E total": 9876543}"#;
+
+    /// Local stand-in for a charging station. Reports are answered
+    /// with the messages above, commands are recorded and answered
+    /// with `TCH-OK` unless they start with `unlock`. Every response is
+    /// preceded by a broadcast which has to be skipped.
+    struct Station {
+        addr: SocketAddr,
+        commands: Arc<StdMutex<Vec<String>>>,
+    }
+
+    impl Station {
+        async fn start() -> Self {
+            Self::with_report_2(REPORT_2).await
+        }
+
+        async fn with_report_2(report_2: &'static str) -> Self {
+            let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let commands = Arc::new(StdMutex::new(Vec::new()));
+            let recorded = commands.clone();
+            tokio::spawn(async move {
+                let mut buf = [0; 1500];
+                loop {
+                    let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                    let request = String::from_utf8_lossy(&buf[..len]).into_owned();
+                    let response = match request.as_str() {
+                        "report 1" => REPORT_1.to_owned(),
+                        "report 2" => report_2.to_owned(),
+                        "report 3" => REPORT_3.to_owned(),
+                        "timeout" => continue,
+                        command => {
+                            recorded.lock().unwrap().push(command.to_owned());
+                            if command.starts_with("unlock") {
+                                "TCH-ERR:unknown command\n".to_owned()
+                            } else {
+                                "TCH-OK :done\n".to_owned()
+                            }
+                        }
+                    };
+                    socket.send_to(br#"{"State": 2}"#, peer).await.unwrap();
+                    socket.send_to(response.as_bytes(), peer).await.unwrap();
+                }
+            });
+            Self { addr, commands }
+        }
+
+        async fn wallbox(&self) -> UdpWallbox {
+            let cfg = UdpConfig {
+                addr: self.addr.ip(),
+                port: self.addr.port(),
+                bind: (Ipv4Addr::LOCALHOST, 0).into(),
+                timeout: Duration::from_millis(500),
+                retry_delay: Duration::from_secs(1),
+                poll_delay: Duration::from_secs(1),
+            };
+            UdpWallbox::new(&UdpSockets::default(), cfg).await.unwrap()
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn device_info() {
+        let station = Station::start().await;
+        let info = station.wallbox().await.device_info().await.unwrap();
+        assert_eq!(info.model, "KeContact P30");
+        assert_eq!(info.hw_version.as_deref(), Some("KC-P30-EC240122-E0R"));
+        assert_eq!(info.serial_number, "16314582");
+        assert_eq!(info.firmware_version, "3.10.16");
+        assert_eq!(info.configuration_url, "http://127.0.0.1/");
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let station = Station::start().await;
+        let wallbox = station.wallbox().await;
+        assert_eq!(wallbox.max_supported_current().await.unwrap(), 32000);
+        let status = wallbox.status().await.unwrap();
+        assert!(status.charging_state == ChargingState::Active);
+        assert!(status.cable_state == CableState::ElectricVehicleLocked);
+        assert!(!status.error_code.is_error());
+        assert_eq!(status.active_power, 3_643_000);
+        assert_eq!(status.total_energy, 9_876_543);
+        assert_eq!(status.charged_energy, 12345);
+        assert_eq!(status.currents, [16000, 0, 0]);
+        assert_eq!(status.voltages, [230, 231, 229]);
+        assert_eq!(status.power_factor, 990);
+        assert!(status.phases == Phases::One);
+        // Not reported via UDP
+        assert_eq!(status.rfid_card, 0);
+        assert_eq!(wallbox.failsafe().await.unwrap(), (6000, 60));
+    }
+
+    #[tokio::test]
+    async fn commands() {
+        let station = Station::start().await;
+        let wallbox = station.wallbox().await;
+        wallbox.set_charging_current(10000).await.unwrap();
+        wallbox.enable(false).await.unwrap();
+        wallbox.enable(true).await.unwrap();
+        wallbox.set_energy(5000).await.unwrap();
+        wallbox.claim_phase_switching().await.unwrap();
+        wallbox.trigger_phase_switch(Phases::Three).await.unwrap();
+        wallbox.set_failsafe(6000, 60, false).await.unwrap();
+        assert_eq!(
+            station.commands(),
+            [
+                "curr 10000",
+                "ena 0",
+                "ena 1",
+                "setenergy 50000",
+                "x2 1",
+                "failsafe 60 6000 0"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn without_phase_switching() {
+        let station = Station::with_report_2(REPORT_2_WITHOUT_PHASE_SWITCHING).await;
+        let wallbox = station.wallbox().await;
+        assert!(wallbox.status().await.unwrap().phases == Phases::Three);
+        assert!(wallbox.phases().await.unwrap() == Phases::Three);
+        let result = wallbox.claim_phase_switching().await;
+        assert!(matches!(
+            result,
+            Err(WallboxError::Udp(UdpError::PhaseSwitchingUnsupported))
+        ));
+        assert!(station.commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_command() {
+        let station = Station::start().await;
+        let result = station.wallbox().await.unlock_plug().await;
+        assert!(matches!(
+            result,
+            Err(WallboxError::Udp(UdpError::Rejected(command, _))) if command == "unlock"
+        ));
+    }
+
+    #[tokio::test]
+    async fn no_response() {
+        let station = Station::start().await;
+        let result = station.wallbox().await.command("timeout".into()).await;
+        assert!(matches!(result, Err(UdpError::Timeout(_))));
+    }
+}
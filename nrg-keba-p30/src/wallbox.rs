@@ -0,0 +1,89 @@
+//! Protocol independent interface of a charging station.
+//!
+//! KEBA charging stations are either controlled via Modbus TCP
+//! (x-series) or via the KEBA UDP protocol (c-series and older
+//! models). Both protocols provide mostly the same information so the
+//! rest of this service only talks to the `Wallbox` trait.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use nrg_modbus::ModbusError;
+use thiserror::Error;
+
+use crate::{
+    hass::DeviceInfo,
+    registers::{CableState, ChargingState, ErrorCode, Phases},
+    udp::UdpError,
+};
+
+#[derive(Debug, Error)]
+pub enum WallboxError {
+    #[error("{0}")]
+    Modbus(#[from] ModbusError),
+    #[error("{0}")]
+    Udp(#[from] UdpError),
+}
+
+/// Values read from the charging station with every poll. The units
+/// are the same for both protocols.
+#[derive(Clone)]
+pub struct Status {
+    pub charging_state: ChargingState,
+    pub cable_state: CableState,
+    pub error_code: ErrorCode,
+    /// Active power in mW
+    pub active_power: u32,
+    /// Total energy in 0.1 Wh
+    pub total_energy: u32,
+    /// Energy of the current charging session in 0.1 Wh
+    pub charged_energy: u32,
+    /// Charging current per phase in mA
+    pub currents: [u32; 3],
+    /// Voltage per phase in V
+    pub voltages: [u32; 3],
+    /// Power factor in 0.1 %
+    pub power_factor: u32,
+    pub phases: Phases,
+    /// RFID card used to authorize the current session, 0 if none
+    /// was used. The UDP protocol does not report the card, so it is
+    /// always 0 there.
+    pub rfid_card: u32,
+}
+
+#[async_trait]
+pub trait Wallbox: Send + Sync {
+    async fn device_info(&self) -> Result<DeviceInfo, WallboxError>;
+    /// Maximum charging current supported by the hardware in mA
+    async fn max_supported_current(&self) -> Result<u32, WallboxError>;
+    /// Current state of the station. Via UDP [`Status::rfid_card`] is
+    /// always 0.
+    async fn status(&self) -> Result<Status, WallboxError>;
+    async fn charging_state(&self) -> Result<ChargingState, WallboxError>;
+    /// Charging current in mA
+    async fn set_charging_current(&self, current: u16) -> Result<(), WallboxError>;
+    async fn enable(&self, enable: bool) -> Result<(), WallboxError>;
+    /// Energy limit of the current session in Wh. 0 disables the limit.
+    async fn set_energy(&self, energy: u32) -> Result<(), WallboxError>;
+    async fn unlock_plug(&self) -> Result<(), WallboxError>;
+    async fn phases(&self) -> Result<Phases, WallboxError>;
+    /// Make this protocol the source for phase switching unless it
+    /// already is.
+    async fn claim_phase_switching(&self) -> Result<(), WallboxError>;
+    async fn trigger_phase_switch(&self, phases: Phases) -> Result<(), WallboxError>;
+    /// Write the failsafe settings. `timeout` is given in seconds.
+    async fn set_failsafe(
+        &self,
+        current: u16,
+        timeout: u16,
+        persist: bool,
+    ) -> Result<(), WallboxError>;
+    /// Failsafe current in mA and timeout in seconds as currently
+    /// used by the charging station
+    async fn failsafe(&self) -> Result<(u32, u32), WallboxError>;
+    /// Called after a failed request. Returns once the charging
+    /// station can be talked to again.
+    async fn reconnect(&self);
+    /// Delay between two polls
+    fn poll_delay(&self) -> Duration;
+}
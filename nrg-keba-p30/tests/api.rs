@@ -0,0 +1,101 @@
+//! Use the REST API against the simulated charging station.
+
+use std::net::{SocketAddr, TcpListener};
+
+use common::{topic, Broker, Service, Simulator};
+use nrg_keba_p30_simulator::{station, Vehicle};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+mod common;
+
+const TOKEN: &str = "secret";
+
+struct Api {
+    addr: SocketAddr,
+    _service: Service,
+}
+
+impl Api {
+    async fn start(broker: &Broker, simulator: &Simulator) -> Self {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let api_cfg = format!("[api]\nlisten = \"{}\"\ntoken = \"{}\"\n", addr, TOKEN);
+        let service = Service::start(broker, simulator, &api_cfg);
+        broker
+            .wait_for_payload(&topic("availability"), "online")
+            .await;
+        Self {
+            addr,
+            _service: service,
+        }
+    }
+
+    /// Send a request and return the status and the body of the
+    /// response
+    async fn request(&self, method: &str, path: &str, token: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(self.addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            token,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (header, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = header[9..12].parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn api() {
+    let broker = Broker::start().await;
+    let simulator = Simulator::start().await;
+    let api = Api::start(&broker, &simulator).await;
+    {
+        let mut station = simulator.station.lock().unwrap();
+        station.plug(0);
+        station.set_vehicle(Vehicle::Charging);
+    }
+    simulator.wait_for(station::CHARGING_STATE, 3).await;
+
+    let (status, _) = api.request("GET", "/stations", "wrong", "").await;
+    assert_eq!(status, 401);
+
+    let (status, stations) = api.request("GET", "/stations", TOKEN, "").await;
+    assert_eq!(status, 200);
+    assert_eq!(stations[0]["id"], "wallbox");
+    assert_eq!(stations[0]["available"], true);
+
+    let (status, result) = api
+        .request("PUT", "/stations/wallbox/charging-current", TOKEN, "10000")
+        .await;
+    assert_eq!(status, 200, "{}", result);
+    assert_eq!(result["success"], true);
+    simulator
+        .wait_for(station::MAX_CHARGING_CURRENT, 10000)
+        .await;
+
+    let (status, station) = api.request("GET", "/stations/wallbox", TOKEN, "").await;
+    assert_eq!(status, 200);
+    assert_eq!(station["charging_current"], 10000);
+
+    let (status, result) = api
+        .request("POST", "/stations/wallbox/unlock-plug", TOKEN, "")
+        .await;
+    assert_eq!(status, 400);
+    assert_eq!(result["success"], false);
+
+    let (status, _) = api.request("GET", "/stations/other", TOKEN, "").await;
+    assert_eq!(status, 404);
+}
@@ -0,0 +1,299 @@
+//! Test environment consisting of the simulated charging station, a
+//! minimal MQTT broker and the service itself.
+
+// Every test crate uses a different part of the environment
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    net::SocketAddr,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use nrg_keba_p30_simulator::{serve, simulate, Station};
+use rumqttc::{
+    matches, ConnAck, ConnectReturnCode, Packet, PingResp, PubAck, Publish, QoS, SubAck,
+    SubscribeReasonCode,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    time::{sleep, timeout, Instant},
+};
+
+/// Maximum time to wait for the service to react
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+/// Simulated charging station served on a random port
+pub struct Simulator {
+    pub addr: SocketAddr,
+    pub station: Arc<Mutex<Station>>,
+}
+
+impl Simulator {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let station = Arc::new(Mutex::new(Station::default()));
+        tokio::spawn(simulate(station.clone()));
+        tokio::spawn(serve(listener, station.clone()));
+        Self { addr, station }
+    }
+
+    /// Read a register of the station
+    pub fn read(&self, addr: u16) -> u32 {
+        self.station.lock().unwrap().read(addr).unwrap()
+    }
+
+    /// Wait until a register of the station has the expected value
+    pub async fn wait_for(&self, addr: u16, expected: u32) {
+        let deadline = Instant::now() + TIMEOUT;
+        while self.read(addr) != expected {
+            assert!(
+                Instant::now() < deadline,
+                "Register {} is {} instead of {}",
+                addr,
+                self.read(addr),
+                expected
+            );
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+type Subscribers = Vec<(String, mpsc::UnboundedSender<Publish>)>;
+
+#[derive(Default)]
+struct Topics {
+    subscribers: Subscribers,
+    /// Latest payload of every topic
+    latest: HashMap<String, String>,
+    retained: HashMap<String, Publish>,
+}
+
+/// MQTT broker which supports just enough of MQTT 3.1.1 for the service:
+/// QoS 0 and 1, retained messages and wildcard subscriptions.
+pub struct Broker {
+    pub port: u16,
+    topics: Arc<Mutex<Topics>>,
+    changed: watch::Sender<()>,
+}
+
+impl Broker {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let topics = Arc::new(Mutex::new(Topics::default()));
+        let (changed, _) = watch::channel(());
+        let broker = Self {
+            port,
+            topics: topics.clone(),
+            changed: changed.clone(),
+        };
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle_client(stream, topics.clone(), changed.clone()));
+            }
+        });
+        broker
+    }
+
+    /// Publish a message to all subscribers
+    pub fn publish(&self, topic: &str, payload: &str) {
+        publish(
+            &self.topics,
+            &self.changed,
+            Publish::new(topic, QoS::AtMostOnce, payload),
+        );
+    }
+
+    /// Wait until a message matching `pred` is published on `topic`
+    /// and return its payload
+    pub async fn wait_for(&self, topic: &str, pred: impl Fn(&str) -> bool) -> String {
+        let mut changed = self.changed.subscribe();
+        let wait = async {
+            loop {
+                if let Some(payload) = self.topics.lock().unwrap().latest.get(topic) {
+                    if pred(payload) {
+                        return payload.clone();
+                    }
+                }
+                changed.changed().await.unwrap();
+            }
+        };
+        match timeout(TIMEOUT, wait).await {
+            Ok(payload) => payload,
+            Err(_) => panic!(
+                "No matching message on {}, latest: {:?}",
+                topic,
+                self.topics.lock().unwrap().latest.get(topic)
+            ),
+        }
+    }
+
+    /// Wait until `payload` is published on `topic`
+    pub async fn wait_for_payload(&self, topic: &str, payload: &str) {
+        self.wait_for(topic, |p| p == payload).await;
+    }
+}
+
+fn publish(topics: &Mutex<Topics>, changed: &watch::Sender<()>, mut publish: Publish) {
+    let mut topics = topics.lock().unwrap();
+    let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+    topics.latest.insert(publish.topic.clone(), payload);
+    if publish.retain {
+        topics
+            .retained
+            .insert(publish.topic.clone(), publish.clone());
+    }
+    publish.qos = QoS::AtMostOnce;
+    publish.pkid = 0;
+    publish.retain = false;
+    topics.subscribers.retain(|(filter, subscriber)| {
+        !matches(&publish.topic, filter) || subscriber.send(publish.clone()).is_ok()
+    });
+    changed.send_replace(());
+}
+
+async fn handle_client(stream: TcpStream, topics: Arc<Mutex<Topics>>, changed: watch::Sender<()>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Publish>();
+    let (ack_tx, mut ack_rx) = mpsc::unbounded_channel::<BytesMut>();
+    tokio::spawn(async move {
+        let mut buf = BytesMut::new();
+        loop {
+            tokio::select! {
+                Some(publish) = rx.recv() => { publish.write(&mut buf).unwrap(); }
+                Some(ack) = ack_rx.recv() => buf.extend_from_slice(&ack),
+                else => break,
+            }
+            if writer.write_all(&buf).await.is_err() {
+                break;
+            }
+            buf.clear();
+        }
+    });
+
+    let mut buf = BytesMut::new();
+    loop {
+        let packet = match rumqttc::read(&mut buf, MAX_PACKET_SIZE) {
+            Ok(packet) => packet,
+            Err(rumqttc::Error::InsufficientBytes(_)) => match reader.read_buf(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => continue,
+            },
+            Err(e) => panic!("Invalid MQTT packet: {:?}", e),
+        };
+        let mut ack = BytesMut::new();
+        match packet {
+            Packet::Connect(_) => {
+                ConnAck::new(ConnectReturnCode::Success, false)
+                    .write(&mut ack)
+                    .unwrap();
+            }
+            Packet::Subscribe(subscribe) => {
+                let codes = subscribe
+                    .filters
+                    .iter()
+                    .map(|_| SubscribeReasonCode::Success(QoS::AtMostOnce))
+                    .collect();
+                SubAck::new(subscribe.pkid, codes).write(&mut ack).unwrap();
+                let mut topics = topics.lock().unwrap();
+                for filter in subscribe.filters {
+                    for retained in topics.retained.values() {
+                        if matches(&retained.topic, &filter.path) {
+                            let mut publish = retained.clone();
+                            publish.qos = QoS::AtMostOnce;
+                            publish.pkid = 0;
+                            let _ = tx.send(publish);
+                        }
+                    }
+                    topics.subscribers.push((filter.path, tx.clone()));
+                }
+            }
+            Packet::Publish(p) => {
+                if p.qos == QoS::AtLeastOnce {
+                    PubAck::new(p.pkid).write(&mut ack).unwrap();
+                }
+                publish(&topics, &changed, p);
+            }
+            Packet::PingReq => {
+                PingResp.write(&mut ack).unwrap();
+            }
+            Packet::Disconnect => break,
+            _ => {}
+        }
+        if !ack.is_empty() && ack_tx.send(ack).is_err() {
+            break;
+        }
+    }
+}
+
+/// The service running as a child process. It is killed when dropped.
+pub struct Service {
+    child: Child,
+    config_file: PathBuf,
+}
+
+impl Service {
+    /// Start the service with a configuration file consisting of the
+    /// given station settings and the MQTT and Modbus settings of the
+    /// test environment.
+    pub fn start(broker: &Broker, simulator: &Simulator, station_cfg: &str) -> Self {
+        let config = format!(
+            r#"
+            [mqtt]
+            host = "127.0.0.1"
+            port = {}
+            client_id = "nrg-keba-p30-test-{}"
+            topic_prefix = "nrg"
+
+            [[station]]
+            [station.modbus]
+            addr = "{}"
+            slave = 255
+            retry_delay = {{ secs = 1, nanos = 0 }}
+            poll_delay = {{ secs = 1, nanos = 0 }}
+
+            [station.home-assistant]
+            discovery_prefix = "homeassistant"
+            object_id = "wallbox"
+            name = "Wallbox"
+
+            {}
+            "#,
+            broker.port, broker.port, simulator.addr, station_cfg
+        );
+        let config_file =
+            env::temp_dir().join(format!("nrg-keba-p30-test-{}.toml", simulator.addr.port()));
+        fs::write(&config_file, config).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_nrg-keba-p30"))
+            .arg(&config_file)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        Self { child, config_file }
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.config_file);
+    }
+}
+
+/// Topic of the service for the charging station used in the tests
+pub fn topic(name: &str) -> String {
+    format!("nrg/charging_station/wallbox/{}", name)
+}
@@ -0,0 +1,173 @@
+//! Run the service against the simulated charging station.
+
+use std::time::Duration;
+
+use common::{topic, Broker, Service, Simulator};
+use nrg_keba_p30_simulator::{station, Vehicle};
+use serde_json::Value;
+use tokio::time::sleep;
+
+mod common;
+
+const PHASE_SWITCHING: &str = r#"
+[station.phase-switching]
+cooldown = { secs = 0, nanos = 0 }
+settle_delay = { secs = 1, nanos = 0 }
+"#;
+
+const FAILSAFE: &str = r#"
+[station.failsafe]
+current = 6000
+timeout = { secs = 3, nanos = 0 }
+"#;
+
+async fn start(station_cfg: &str) -> (Broker, Simulator, Service) {
+    let broker = Broker::start().await;
+    let simulator = Simulator::start().await;
+    let service = Service::start(&broker, &simulator, station_cfg);
+    broker
+        .wait_for_payload(&topic("availability"), "online")
+        .await;
+    (broker, simulator, service)
+}
+
+fn plug_and_charge(simulator: &Simulator) {
+    let mut station = simulator.station.lock().unwrap();
+    station.plug(0x1234);
+    station.set_vehicle(Vehicle::Charging);
+}
+
+/// Send a command via MQTT and return whether it succeeded
+async fn command(broker: &Broker, name: &str, payload: &str) -> bool {
+    // Clear the previous result so it is not mistaken for this one
+    broker.publish(&topic("command_result"), "");
+    broker.publish(&topic(&format!("set_{}", name)), payload);
+    let result = broker
+        .wait_for(&topic("command_result"), |result| !result.is_empty())
+        .await;
+    let result: Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(result["command"], name);
+    result["success"].as_bool().unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn device_info() {
+    let (broker, _simulator, _service) = start("").await;
+    let config = broker
+        .wait_for(
+            "homeassistant/sensor/wallbox/wallbox_charging_state/config",
+            |config| !config.is_empty(),
+        )
+        .await;
+    let config: Value = serde_json::from_str(&config).unwrap();
+    let device = &config["device"];
+    assert_eq!(device["model"], "KeContact P30");
+    assert_eq!(device["serial_number"], "22123456");
+    // The product type and the firmware version are not decoded
+    assert_eq!(device["sw_version"], "0x030a1b00");
+    assert_eq!(device.get("hw_version"), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn polling() {
+    let (broker, simulator, _service) = start("").await;
+    broker
+        .wait_for_payload(&topic("charging_state"), r#""NotReady""#)
+        .await;
+
+    plug_and_charge(&simulator);
+    broker
+        .wait_for_payload(&topic("charging_state"), r#""Active""#)
+        .await;
+    broker
+        .wait_for_payload(&topic("cable_state"), r#""ElectricVehicleLocked""#)
+        .await;
+    broker
+        .wait_for_payload(&topic("phase_switching_state"), "3")
+        .await;
+    // 3 × 32 A × 230 V × 0.99
+    broker
+        .wait_for_payload(&topic("active_power"), "21859.2")
+        .await;
+
+    simulator.station.lock().unwrap().unplug();
+    broker
+        .wait_for_payload(&topic("charging_state"), r#""NotReady""#)
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn charging_current() {
+    let (broker, simulator, _service) = start("").await;
+    plug_and_charge(&simulator);
+    broker
+        .wait_for_payload(&topic("charging_state"), r#""Active""#)
+        .await;
+
+    assert!(command(&broker, "charging_current", "10000").await);
+    simulator
+        .wait_for(station::MAX_CHARGING_CURRENT, 10000)
+        .await;
+    broker
+        .wait_for_payload(&topic("charging_current"), "10000")
+        .await;
+
+    // Below the minimum only in the automatic phase mode
+    assert!(!command(&broker, "charging_current", "3000").await);
+    assert_eq!(simulator.read(station::MAX_CHARGING_CURRENT), 10000);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn phase_switch() {
+    let (broker, simulator, _service) = start(PHASE_SWITCHING).await;
+    plug_and_charge(&simulator);
+    broker
+        .wait_for_payload(&topic("charging_state"), r#""Active""#)
+        .await;
+    assert_eq!(simulator.read(station::PHASE_SWITCHING_STATE), 1);
+
+    // 3 A on three phases become 9 A on a single phase
+    assert!(command(&broker, "phase_mode", r#""auto""#).await);
+    assert!(command(&broker, "charging_current", "3000").await);
+    simulator.wait_for(station::PHASE_SWITCHING_STATE, 0).await;
+    broker
+        .wait_for_payload(&topic("phase_switching_state"), "1")
+        .await;
+    simulator.wait_for(station::CHARGING_STATE, 3).await;
+    simulator
+        .wait_for(station::MAX_CHARGING_CURRENT, 9000)
+        .await;
+
+    // Back to three phases
+    assert!(command(&broker, "phase_mode", r#""3p""#).await);
+    simulator.wait_for(station::PHASE_SWITCHING_STATE, 1).await;
+    simulator.wait_for(station::CHARGING_STATE, 3).await;
+    simulator
+        .wait_for(station::MAX_CHARGING_CURRENT, 6000)
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failsafe() {
+    let (broker, simulator, service) = start(FAILSAFE).await;
+    simulator
+        .wait_for(station::FAILSAFE_TIMEOUT_SETTING, 3)
+        .await;
+    assert_eq!(simulator.read(station::FAILSAFE_CURRENT_SETTING), 6000);
+
+    plug_and_charge(&simulator);
+    assert!(command(&broker, "charging_current", "10000").await);
+    simulator
+        .wait_for(station::MAX_CHARGING_CURRENT, 10000)
+        .await;
+
+    // The heartbeat keeps the charging current beyond the timeout
+    sleep(Duration::from_secs(5)).await;
+    assert_eq!(simulator.read(station::MAX_CHARGING_CURRENT), 10000);
+
+    // Without the service the station falls back to the failsafe current
+    drop(service);
+    simulator
+        .wait_for(station::MAX_CHARGING_CURRENT, 6000)
+        .await;
+}
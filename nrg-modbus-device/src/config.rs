@@ -0,0 +1,45 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use nrg_hass::config::HomeAssistantConfig;
+use nrg_mqtt::config::MqttConfig;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub mqtt: MqttConfig,
+    #[serde(rename = "home-assistant")]
+    pub hass: HomeAssistantConfig,
+    pub modbus: ModbusConfig,
+    /// Path of the register map. Relative paths are resolved relative
+    /// to the directory of the config file.
+    pub register_map: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModbusConfig {
+    /// Either a `[modbus.tcp]` or a `[modbus.rtu]` table
+    #[serde(flatten)]
+    pub transport: TransportConfig,
+    pub slave: u8,
+    pub retry_delay: Duration,
+    /// Poll delay of registers without a `poll_interval`
+    pub poll_delay: Duration,
+    /// Maximum time to wait for a response
+    #[serde(default = "nrg_modbus::connection::default_timeout")]
+    pub timeout: Duration,
+    /// Maximum number of unused registers between two registers
+    /// which are still read with a single request.
+    #[serde(default = "default_max_read_gap")]
+    pub max_read_gap: u16,
+}
+
+fn default_max_read_gap() -> u16 {
+    16
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportConfig {
+    Tcp { addr: SocketAddr },
+    Rtu { device: String, baud: u32 },
+}
@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use nrg_hass::{
+    config::HomeAssistantConfig,
+    discovery::announce,
+    models::{
+        binary_sensor::BinarySensor, device::Device, number::Number, sensor::Sensor, switch::Switch,
+    },
+    state::publish_state,
+};
+use rumqttc::AsyncClient;
+
+use crate::map::{DeviceConfig, Point, Value};
+
+/// The Home Assistant entity of a register. Numeric values become
+/// sensors or numbers and `bool` values binary sensors or switches
+/// depending on whether the register is writable.
+pub enum Entity {
+    Sensor(Sensor),
+    BinarySensor(BinarySensor),
+    Number(Number),
+    Switch(Switch),
+}
+
+pub fn availability_topic(cfg: &HomeAssistantConfig) -> String {
+    format!("nrg/modbus-device/{}/availability", cfg.object_id)
+}
+
+pub fn device(cfg: &HomeAssistantConfig, device: &DeviceConfig) -> Arc<Device> {
+    let mut builder = Device::builder();
+    builder
+        .identifiers(vec![cfg.object_id.clone()])
+        .name(&cfg.name);
+    if let Some(manufacturer) = &device.manufacturer {
+        builder.manufacturer(manufacturer);
+    }
+    if let Some(model) = &device.model {
+        builder.model(model);
+    }
+    Arc::new(builder.build().unwrap())
+}
+
+impl Entity {
+    pub fn new(cfg: &HomeAssistantConfig, device: &Arc<Device>, point: &Point) -> Self {
+        let reg = &point.cfg;
+        let name = format!("{} {}", cfg.name, reg.name);
+        let object_id = format!("{}_{}", cfg.object_id, reg.id);
+        let state_topic = format!("nrg/modbus-device/{}/{}", cfg.object_id, reg.id);
+        let command_topic = format!("{}/set", state_topic);
+        let availability_topic = availability_topic(cfg);
+        match (point.is_bool(), reg.writable) {
+            (false, false) => {
+                let mut builder = Sensor::builder();
+                builder
+                    .name(name)
+                    .object_id(&object_id)
+                    .unique_id(&object_id)
+                    .state_topic(state_topic)
+                    .availability_topic(availability_topic)
+                    .device(device.clone());
+                if let Some(unit) = reg.unit {
+                    builder.unit_of_measurement(unit);
+                }
+                if let Some(device_class) = reg.device_class {
+                    builder.device_class(device_class);
+                }
+                if let Some(state_class) = reg.state_class {
+                    builder.state_class(state_class);
+                }
+                if let Some(icon) = &reg.icon {
+                    builder.icon(icon);
+                }
+                if let Some(precision) = reg.precision {
+                    builder.suggested_display_precision(precision);
+                }
+                Self::Sensor(builder.build().unwrap())
+            }
+            (false, true) => {
+                let mut builder = Number::builder();
+                builder
+                    .name(name)
+                    .object_id(object_id)
+                    .state_topic(state_topic)
+                    .command_topic(command_topic)
+                    .availability_topic(availability_topic)
+                    .device(device.clone());
+                if let Some(unit) = reg.unit {
+                    builder.unit_of_measurement(unit);
+                }
+                if let Some(device_class) = reg.device_class {
+                    builder.device_class(device_class);
+                }
+                if let Some(icon) = &reg.icon {
+                    builder.icon(icon);
+                }
+                if let Some(min) = reg.min {
+                    builder.min(min);
+                }
+                if let Some(max) = reg.max {
+                    builder.max(max);
+                }
+                if let Some(step) = reg.step {
+                    builder.step(step);
+                }
+                Self::Number(builder.build().unwrap())
+            }
+            (true, false) => {
+                let mut builder = BinarySensor::builder();
+                builder
+                    .name(name)
+                    .object_id(&object_id)
+                    .unique_id(&object_id)
+                    .state_topic(state_topic)
+                    .payload_off("false")
+                    .payload_on("true")
+                    .availability_topic(availability_topic)
+                    .device(device.clone());
+                if let Some(icon) = &reg.icon {
+                    builder.icon(icon);
+                }
+                Self::BinarySensor(builder.build().unwrap())
+            }
+            (true, true) => {
+                let mut builder = Switch::builder();
+                builder
+                    .name(name)
+                    .object_id(&object_id)
+                    .unique_id(&object_id)
+                    .state_topic(state_topic)
+                    .command_topic(command_topic)
+                    .state_off("false")
+                    .state_on("true")
+                    .payload_off("false")
+                    .payload_on("true")
+                    .availability_topic(availability_topic)
+                    .device(device.clone());
+                if let Some(icon) = &reg.icon {
+                    builder.icon(icon);
+                }
+                Self::Switch(builder.build().unwrap())
+            }
+        }
+    }
+    /// Topic of write commands. `None` for read-only registers.
+    pub fn command_topic(&self) -> Option<&str> {
+        match self {
+            Self::Sensor(_) | Self::BinarySensor(_) => None,
+            Self::Number(number) => number.command_topic.as_deref(),
+            Self::Switch(switch) => Some(&switch.command_topic),
+        }
+    }
+    pub async fn announce(
+        &self,
+        client: &AsyncClient,
+        cfg: &HomeAssistantConfig,
+    ) -> Result<(), rumqttc::ClientError> {
+        match self {
+            Self::Sensor(entity) => announce(client, cfg, &cfg.object_id, entity).await,
+            Self::BinarySensor(entity) => announce(client, cfg, &cfg.object_id, entity).await,
+            Self::Number(entity) => announce(client, cfg, &cfg.object_id, entity).await,
+            Self::Switch(entity) => announce(client, cfg, &cfg.object_id, entity).await,
+        }
+    }
+    pub async fn publish(
+        &self,
+        client: &AsyncClient,
+        value: Value,
+    ) -> Result<(), rumqttc::ClientError> {
+        match self {
+            Self::Sensor(entity) => publish_state(client, entity, value).await,
+            Self::BinarySensor(entity) => publish_state(client, entity, value).await,
+            Self::Number(entity) => publish_state(client, entity, value).await,
+            Self::Switch(entity) => publish_state(client, entity, value).await,
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Generic Modbus device service. The registers of the device are
+//! described by a register map and exposed as MQTT topics and Home
+//! Assistant entities.
+
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::Parser;
+use config::{Config, ModbusConfig, TransportConfig};
+use hass::{availability_topic, Entity};
+use map::{Point, RegisterMap, Value};
+use nrg_hass::availability::publish_availability;
+use nrg_modbus::{connection::timeout, ModbusError, RegisterBlock};
+use nrg_mqtt::{
+    client::MqttClient,
+    command::{Commands, Decoder},
+};
+use tokio::time::{sleep, sleep_until, Instant};
+use tokio_modbus::{client::Context, Slave};
+use tokio_serial::SerialStream;
+use tracing::{info, warn, Level};
+use tracing_subscriber::FmtSubscriber;
+
+mod config;
+mod hass;
+mod map;
+
+#[derive(Parser)]
+struct Args {
+    config_file: PathBuf,
+}
+
+/// A value received via the command topic of a writable register
+struct Write {
+    /// Index into the registers of the register map
+    index: usize,
+    value: Value,
+}
+
+struct WriteDecoder(usize);
+
+impl Decoder<Write> for WriteDecoder {
+    fn decode(&self, data: &[u8]) -> anyhow::Result<Write> {
+        Ok(Write {
+            index: self.0,
+            value: serde_json::from_slice(data)?,
+        })
+    }
+}
+
+/// Registers sharing the same poll interval
+struct PollGroup {
+    interval: Duration,
+    block: RegisterBlock,
+    points: Vec<usize>,
+    next: Instant,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    let args = Args::parse();
+
+    let data = fs::read(&args.config_file).expect("Could not read config.toml");
+    let data = String::from_utf8(data).expect("Config file contains non-utf8 characters");
+    let cfg: Config = toml::from_str(&data).expect("Error in config file");
+
+    let map_path = match args.config_file.parent() {
+        Some(dir) => dir.join(&cfg.register_map),
+        None => cfg.register_map.clone(),
+    };
+    let map = RegisterMap::load(&map_path)?;
+    let device = hass::device(&cfg.hass, &map.device);
+    let points: Vec<Point> = map.registers.into_iter().map(Point::new).collect();
+    let entities: Vec<Entity> = points
+        .iter()
+        .map(|point| Entity::new(&cfg.hass, &device, point))
+        .collect();
+
+    let mut groups: Vec<PollGroup> = Vec::new();
+    for (index, point) in points.iter().enumerate() {
+        let interval = point.cfg.poll_interval.unwrap_or(cfg.modbus.poll_delay);
+        let group = match groups.iter_mut().position(|g| g.interval == interval) {
+            Some(pos) => &mut groups[pos],
+            None => {
+                groups.push(PollGroup {
+                    interval,
+                    block: RegisterBlock::new(cfg.modbus.max_read_gap),
+                    points: Vec::new(),
+                    next: Instant::now(),
+                });
+                groups.last_mut().unwrap()
+            }
+        };
+        point.add_to(&mut group.block);
+        group.points.push(index);
+    }
+    for group in &groups {
+        info!(
+            "Polling {} register(s) every {:?} requires {} request(s)",
+            group.points.len(),
+            group.interval,
+            group.block.requests()
+        );
+    }
+
+    let mqtt = Arc::new(MqttClient::new(&cfg.mqtt));
+    let availability_topic = availability_topic(&cfg.hass);
+    let commands = Commands::new(mqtt.clone());
+    for (index, entity) in entities.iter().enumerate() {
+        entity.announce(&mqtt, &cfg.hass).await?;
+        if let Some(topic) = entity.command_topic() {
+            commands.cmd(topic, WriteDecoder(index)).await?;
+        }
+    }
+
+    let mut ctx = connect(&cfg.modbus).await;
+    publish_availability(&mqtt, &availability_topic, true).await?;
+
+    loop {
+        // There is always at least one group as empty register maps
+        // are rejected.
+        let next = groups.iter().map(|group| group.next).min().unwrap();
+        let result = tokio::select! {
+            _ = sleep_until(next) => {
+                poll(&mqtt, &mut ctx, &cfg.modbus, &mut groups, &points, &entities).await?
+            }
+            Some(write) = commands.next() => {
+                let point = &points[write.index];
+                match timeout(cfg.modbus.timeout, point.write(&mut ctx, write.value)).await {
+                    Ok(true) => {
+                        info!("{} = {:?}", point.cfg.id, write.value);
+                        // Publish the value as read back from the device
+                        match timeout(cfg.modbus.timeout, point.read(&mut ctx)).await {
+                            Ok(value) => {
+                                entities[write.index].publish(&mqtt, value).await?;
+                                Ok(())
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Ok(false) => {
+                        warn!("Invalid value for {}: {:?}", point.cfg.id, write.value);
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        match result {
+            Ok(()) => {}
+            // Exceptions and invalid values are reported by the device
+            // so the connection is still fine.
+            Err(e @ (ModbusError::Exception(_) | ModbusError::Decode(_))) => {
+                warn!("Modbus request failed: {}", e);
+            }
+            Err(e) => {
+                warn!("Modbus connection failed: {}", e);
+                publish_availability(&mqtt, &availability_topic, false).await?;
+                sleep(cfg.modbus.retry_delay).await;
+                ctx = connect(&cfg.modbus).await;
+                publish_availability(&mqtt, &availability_topic, true).await?;
+            }
+        }
+    }
+}
+
+/// Read all groups which are due and publish their values. Registers
+/// containing invalid values are skipped.
+async fn poll(
+    mqtt: &MqttClient,
+    ctx: &mut Context,
+    cfg: &ModbusConfig,
+    groups: &mut [PollGroup],
+    points: &[Point],
+    entities: &[Entity],
+) -> Result<Result<(), ModbusError>, rumqttc::ClientError> {
+    let now = Instant::now();
+    for group in groups.iter_mut().filter(|group| group.next <= now) {
+        group.next = (group.next + group.interval).max(now);
+        let regs = match timeout(cfg.timeout, group.block.read(ctx)).await {
+            Ok(regs) => regs,
+            Err(e) => return Ok(Err(e)),
+        };
+        for &index in &group.points {
+            match points[index].decode(&regs) {
+                Ok(value) => entities[index].publish(mqtt, value).await?,
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Connect to the device
+async fn connect(cfg: &ModbusConfig) -> Context {
+    nrg_modbus::connection::connect(cfg.retry_delay, cfg.timeout, || async {
+        match &cfg.transport {
+            TransportConfig::Tcp { addr } => {
+                info!("Connecting to {}...", addr);
+                tokio_modbus::client::tcp::connect_slave(*addr, Slave(cfg.slave)).await
+            }
+            TransportConfig::Rtu { device, baud } => {
+                info!("Opening {}...", device);
+                let stream = SerialStream::open(&tokio_serial::new(device, *baud))?;
+                Ok(tokio_modbus::client::rtu::attach_slave(
+                    stream,
+                    Slave(cfg.slave),
+                ))
+            }
+        }
+    })
+    .await
+}
@@ -0,0 +1,362 @@
+//! Register maps describe the registers of a device model and how
+//! they are exposed to Home Assistant.
+
+use std::{fs, io, path::Path, time::Duration};
+
+use nrg_hass::models::{
+    device_class::DeviceClass, state_class::StateClass, unit::UnitOfMeasurement,
+};
+use nrg_modbus::{
+    ByteOrder, Kind, ModbusError, Register, RegisterBlock, Registers, Type, WordOrder,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_modbus::client::{Reader, Writer};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterMap {
+    #[serde(default)]
+    pub device: DeviceConfig,
+    /// Registers configured as `[[register]]` tables
+    #[serde(rename = "register")]
+    pub registers: Vec<RegisterConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterConfig {
+    /// Appended to the object id of the device
+    pub id: String,
+    /// Appended to the name of the device
+    pub name: String,
+    pub address: u16,
+    #[serde(default = "default_kind")]
+    pub kind: Kind,
+    #[serde(rename = "type", default = "default_value_type")]
+    pub value_type: ValueType,
+    #[serde(default = "default_word_order")]
+    pub word_order: WordOrder,
+    #[serde(default = "default_byte_order")]
+    pub byte_order: ByteOrder,
+    /// Factor applied to the raw value. Ignored for `bool` values.
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    pub unit: Option<UnitOfMeasurement>,
+    /// Device class of numeric values
+    pub device_class: Option<DeviceClass>,
+    pub state_class: Option<StateClass>,
+    pub icon: Option<String>,
+    /// Number of decimals shown by Home Assistant
+    pub precision: Option<u32>,
+    /// Defaults to the `poll_delay` of the Modbus config
+    pub poll_interval: Option<Duration>,
+    /// Writable registers are exposed as `number` or `switch` entities.
+    /// Only holding registers and coils can be writable.
+    #[serde(default)]
+    pub writable: bool,
+    /// Limits of writable numeric values
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub step: Option<f64>,
+}
+
+fn default_kind() -> Kind {
+    Kind::Holding
+}
+
+fn default_value_type() -> ValueType {
+    ValueType::U16
+}
+
+fn default_word_order() -> WordOrder {
+    WordOrder::BigEndian
+}
+
+fn default_byte_order() -> ByteOrder {
+    ByteOrder::BigEndian
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Error)]
+pub enum MapError {
+    #[error("Reading register map failed")]
+    Io(#[from] io::Error),
+    #[error("Invalid register map")]
+    Toml(#[from] toml::de::Error),
+    #[error("Register {0} is configured twice")]
+    Duplicate(String),
+    #[error("Register {0} can not be writable")]
+    NotWritable(String),
+    #[error("The register map contains no registers")]
+    Empty,
+}
+
+impl RegisterMap {
+    pub fn load(path: &Path) -> Result<Self, MapError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+    fn parse(data: &str) -> Result<Self, MapError> {
+        let map: Self = toml::from_str(data)?;
+        if map.registers.is_empty() {
+            return Err(MapError::Empty);
+        }
+        for (i, reg) in map.registers.iter().enumerate() {
+            if map.registers[..i].iter().any(|other| other.id == reg.id) {
+                return Err(MapError::Duplicate(reg.id.clone()));
+            }
+            if reg.writable && !matches!(reg.kind, Kind::Holding | Kind::Coil) {
+                return Err(MapError::NotWritable(reg.id.clone()));
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    Bool,
+}
+
+/// A decoded and scaled value as published via MQTT
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Number(f64),
+}
+
+/// Conversion between the raw register content and `Value`
+trait PointType: Type {
+    fn to_value(self, scale: f64) -> Value;
+    fn from_value(value: Value, scale: f64) -> Option<Self>;
+}
+
+macro_rules! impl_point_type {
+    ($($t:ty),*) => {
+        $(impl PointType for $t {
+            fn to_value(self, scale: f64) -> Value {
+                Value::Number(self as f64 * scale)
+            }
+            fn from_value(value: Value, scale: f64) -> Option<Self> {
+                let Value::Number(value) = value else {
+                    return None;
+                };
+                let raw = (value / scale).round();
+                (raw >= <$t>::MIN as f64 && raw <= <$t>::MAX as f64).then_some(raw as $t)
+            }
+        })*
+    };
+}
+
+impl_point_type!(u16, i16, u32, i32, u64, i64);
+
+impl PointType for f32 {
+    fn to_value(self, scale: f64) -> Value {
+        Value::Number(self as f64 * scale)
+    }
+    fn from_value(value: Value, scale: f64) -> Option<Self> {
+        let Value::Number(value) = value else {
+            return None;
+        };
+        Some((value / scale) as f32)
+    }
+}
+
+impl PointType for f64 {
+    fn to_value(self, scale: f64) -> Value {
+        Value::Number(self * scale)
+    }
+    fn from_value(value: Value, scale: f64) -> Option<Self> {
+        let Value::Number(value) = value else {
+            return None;
+        };
+        Some(value / scale)
+    }
+}
+
+impl PointType for bool {
+    fn to_value(self, _scale: f64) -> Value {
+        Value::Bool(self)
+    }
+    fn from_value(value: Value, _scale: f64) -> Option<Self> {
+        match value {
+            Value::Bool(value) => Some(value),
+            Value::Number(value) => Some(value != 0.0),
+        }
+    }
+}
+
+/// Evaluate `$body` with `$t` being the Rust type of `$value_type`
+macro_rules! with_type {
+    ($value_type:expr, $t:ident => $body:expr) => {
+        match $value_type {
+            ValueType::U16 => {
+                type $t = u16;
+                $body
+            }
+            ValueType::I16 => {
+                type $t = i16;
+                $body
+            }
+            ValueType::U32 => {
+                type $t = u32;
+                $body
+            }
+            ValueType::I32 => {
+                type $t = i32;
+                $body
+            }
+            ValueType::U64 => {
+                type $t = u64;
+                $body
+            }
+            ValueType::I64 => {
+                type $t = i64;
+                $body
+            }
+            ValueType::F32 => {
+                type $t = f32;
+                $body
+            }
+            ValueType::F64 => {
+                type $t = f64;
+                $body
+            }
+            ValueType::Bool => {
+                type $t = bool;
+                $body
+            }
+        }
+    };
+}
+
+/// A configured register
+pub struct Point {
+    pub cfg: RegisterConfig,
+}
+
+impl Point {
+    pub fn new(cfg: RegisterConfig) -> Self {
+        Self { cfg }
+    }
+    fn register<T: Type>(&self) -> Register<'_, T> {
+        Register::new(&self.cfg.id, self.cfg.address)
+            .kind(self.cfg.kind)
+            .word_order(self.cfg.word_order)
+            .byte_order(self.cfg.byte_order)
+            .scale(self.cfg.scale)
+    }
+    pub fn is_bool(&self) -> bool {
+        self.cfg.value_type == ValueType::Bool
+    }
+    pub fn add_to(&self, block: &mut RegisterBlock) {
+        with_type!(self.cfg.value_type, T => block.add(self.register::<T>()))
+    }
+    pub fn decode(&self, regs: &Registers) -> Result<Value, ModbusError> {
+        with_type!(self.cfg.value_type, T => {
+            let reg = self.register::<T>();
+            Ok(regs.value(reg)?.to_value(reg.scale))
+        })
+    }
+    pub async fn read(&self, ctx: &mut impl Reader) -> Result<Value, ModbusError> {
+        with_type!(self.cfg.value_type, T => {
+            let reg = self.register::<T>();
+            Ok(nrg_modbus::read(ctx, reg).await?.to_value(reg.scale))
+        })
+    }
+    /// Write the value. Returns `Ok(false)` if the value can not be
+    /// represented by the register.
+    pub async fn write(&self, ctx: &mut impl Writer, value: Value) -> Result<bool, ModbusError> {
+        with_type!(self.cfg.value_type, T => {
+            let reg = self.register::<T>();
+            let Some(raw) = T::from_value(value, reg.scale) else {
+                return Ok(false);
+            };
+            nrg_modbus::write(ctx, reg, &raw).await?;
+            Ok(true)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("maps");
+        let mut count = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let map =
+                RegisterMap::load(&path).unwrap_or_else(|e| panic!("{}: {:?}", path.display(), e));
+            assert!(map.device.model.is_some(), "{}", path.display());
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn invalid_maps() {
+        let register = "[[register]]\nid = \"power\"\nname = \"Leistung\"\naddress = 0\n";
+        assert!(matches!(RegisterMap::parse(""), Err(MapError::Toml(_))));
+        assert!(matches!(
+            RegisterMap::parse(&register.repeat(2)),
+            Err(MapError::Duplicate(id)) if id == "power"
+        ));
+        let input = format!("{}kind = \"input\"\nwritable = true\n", register);
+        assert!(matches!(
+            RegisterMap::parse(&input),
+            Err(MapError::NotWritable(id)) if id == "power"
+        ));
+        let map = RegisterMap::parse(register).unwrap();
+        let reg = &map.registers[0];
+        assert_eq!(reg.kind, Kind::Holding);
+        assert_eq!(reg.value_type, ValueType::U16);
+        assert_eq!(reg.scale, 1.0);
+    }
+
+    #[test]
+    fn scaling() {
+        assert_eq!(1234u16.to_value(0.1), Value::Number(123.4));
+        assert_eq!(u16::from_value(Value::Number(123.4), 0.1), Some(1234));
+        assert_eq!((-200i16).to_value(0.5), Value::Number(-100.0));
+        assert_eq!(i16::from_value(Value::Number(-100.0), 0.5), Some(-200));
+        assert_eq!(u32::from_value(Value::Number(2.5), 1000.0), Some(0));
+        assert_eq!(u64::from_value(Value::Number(1e6), 1.0), Some(1_000_000));
+        assert_eq!(f32::from_value(Value::Number(3.0), 2.0), Some(1.5));
+        assert_eq!(2.0f64.to_value(0.25), Value::Number(0.5));
+        // The scale does not apply to bool values
+        assert_eq!(true.to_value(0.1), Value::Bool(true));
+        assert_eq!(bool::from_value(Value::Number(2.0), 0.1), Some(true));
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(u16::from_value(Value::Number(-1.0), 1.0), None);
+        assert_eq!(u16::from_value(Value::Number(6553.6), 0.1), None);
+        assert_eq!(i16::from_value(Value::Number(-32769.0), 1.0), None);
+        assert_eq!(u32::from_value(Value::Bool(true), 1.0), None);
+        assert_eq!(f64::from_value(Value::Bool(true), 1.0), None);
+    }
+}
@@ -0,0 +1,224 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio_modbus::client::Reader;
+use tracing::warn;
+
+use crate::{
+    register::{read_raw, Raw},
+    Kind, ModbusError, Register, Type,
+};
+
+/// Maximum number of registers which can be read with a single request
+const MAX_READ_REGISTERS: u16 = 125;
+
+/// Maximum number of coils which can be read with a single request
+const MAX_READ_COILS: u16 = 2000;
+
+fn max_read_len(kind: Kind) -> u16 {
+    if kind.is_bit() {
+        MAX_READ_COILS
+    } else {
+        MAX_READ_REGISTERS
+    }
+}
+
+/// A set of registers which is read with as few requests as possible.
+/// Registers of the same kind which are located next to each other are
+/// coalesced into a single request. Registers which are separated by at
+/// most `max_gap` unused registers are coalesced as well.
+///
+/// If the server rejects a coalesced request with an exception the
+/// registers of that range are read one by one from then on.
+pub struct RegisterBlock {
+    max_gap: u16,
+    ranges: Vec<ReadRange>,
+}
+
+struct ReadRange {
+    kind: Kind,
+    addr: u16,
+    len: u16,
+    /// Address and length of the registers contained in this range
+    regs: Vec<(u16, u16)>,
+    split: AtomicBool,
+}
+
+impl ReadRange {
+    /// End of the range. Computed in `u32` as ranges may end at the top
+    /// of the address space.
+    fn end(&self) -> u32 {
+        end(self.addr, self.len)
+    }
+}
+
+fn end(addr: u16, len: u16) -> u32 {
+    u32::from(addr) + u32::from(len)
+}
+
+impl RegisterBlock {
+    pub fn new(max_gap: u16) -> Self {
+        Self {
+            max_gap,
+            ranges: Vec::new(),
+        }
+    }
+    pub fn with<T: Type>(mut self, reg: Register<'_, T>) -> Self {
+        self.add(reg);
+        self
+    }
+    pub fn add<T: Type>(&mut self, reg: Register<'_, T>) {
+        let mut regs: Vec<(Kind, u16, u16)> = self
+            .ranges
+            .drain(..)
+            .flat_map(|range| {
+                let kind = range.kind;
+                range
+                    .regs
+                    .into_iter()
+                    .map(move |(addr, len)| (kind, addr, len))
+            })
+            .chain([(reg.kind, reg.addr, reg.count())])
+            .collect();
+        regs.sort_unstable();
+        regs.dedup();
+        for (kind, addr, len) in regs {
+            match self.ranges.last_mut() {
+                Some(range)
+                    if range.kind == kind
+                        && u32::from(addr) <= range.end() + u32::from(self.max_gap)
+                        && end(addr, len).max(range.end()) - u32::from(range.addr)
+                            <= u32::from(max_read_len(kind)) =>
+                {
+                    // Bounded by the maximum read length
+                    range.len = (end(addr, len).max(range.end()) - u32::from(range.addr)) as u16;
+                    range.regs.push((addr, len));
+                }
+                _ => self.ranges.push(ReadRange {
+                    kind,
+                    addr,
+                    len,
+                    regs: vec![(addr, len)],
+                    split: AtomicBool::new(false),
+                }),
+            }
+        }
+    }
+    /// Number of requests needed to read all registers
+    pub fn requests(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|range| {
+                if range.split.load(Ordering::Relaxed) {
+                    range.regs.len()
+                } else {
+                    1
+                }
+            })
+            .sum()
+    }
+    pub async fn read(&self, ctx: &mut impl Reader) -> Result<Registers, ModbusError> {
+        let mut segments = Vec::with_capacity(self.ranges.len());
+        for range in &self.ranges {
+            if !range.split.load(Ordering::Relaxed) {
+                match read_raw(ctx, range.kind, range.addr, range.len).await {
+                    Ok(data) => {
+                        segments.push((range.kind, range.addr, data));
+                        continue;
+                    }
+                    Err(ModbusError::Exception(e)) if range.regs.len() > 1 => {
+                        warn!(
+                            "Reading {:?} registers {}..{} failed ({}), reading them one by one",
+                            range.kind,
+                            range.addr,
+                            range.end(),
+                            e
+                        );
+                        range.split.store(true, Ordering::Relaxed);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            for &(addr, len) in &range.regs {
+                segments.push((
+                    range.kind,
+                    addr,
+                    read_raw(ctx, range.kind, addr, len).await?,
+                ));
+            }
+        }
+        Ok(Registers { segments })
+    }
+}
+
+/// The result of reading a `RegisterBlock`.
+pub struct Registers {
+    segments: Vec<(Kind, u16, Raw)>,
+}
+
+impl Registers {
+    /// Decode the value of a register contained in this block.
+    pub fn get<T: Type>(&self, reg: Register<'_, T>) -> Option<T> {
+        self.find(&reg)?.ok()
+    }
+    /// Like `get` but returns an error if the register was not
+    /// contained in the block or contains an invalid value.
+    pub fn value<T: Type>(&self, reg: Register<'_, T>) -> Result<T, ModbusError> {
+        self.find(&reg)
+            .unwrap_or_else(|| Err(ModbusError::Decode(reg.name.to_owned())))
+    }
+    /// Like `value` but applies the scaling factor of the register.
+    pub fn scaled<T: Type + Into<f64>>(&self, reg: Register<'_, T>) -> Result<f64, ModbusError> {
+        Ok(reg.scaled(self.value(reg)?))
+    }
+    fn find<T: Type>(&self, reg: &Register<'_, T>) -> Option<Result<T, ModbusError>> {
+        self.segments
+            .iter()
+            .filter(|(kind, addr, data)| {
+                *kind == reg.kind
+                    && *addr <= reg.addr
+                    && usize::from(reg.addr - addr) + usize::from(reg.count()) <= data.len()
+            })
+            .find_map(|(_, addr, data)| data.decode(reg, usize::from(reg.addr - addr)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesce() {
+        let block = RegisterBlock::new(2)
+            .with(Register::<u32>::new("a", 100))
+            .with(Register::<u16>::new("b", 102))
+            .with(Register::<u16>::new("c", 105))
+            .with(Register::<u16>::new("d", 110))
+            .with(Register::<u16>::input("e", 103));
+        assert_eq!(block.requests(), 3);
+        assert_eq!(block.ranges[0].addr, 100);
+        assert_eq!(block.ranges[0].len, 6);
+        assert_eq!(block.ranges[1].addr, 110);
+        assert_eq!(block.ranges[2].kind, Kind::Input);
+    }
+
+    #[test]
+    fn coalesce_at_end_of_address_space() {
+        let block = RegisterBlock::new(0)
+            .with(Register::<u32>::new("a", 0xFFFC))
+            .with(Register::<u16>::new("b", 0xFFFF))
+            .with(Register::<u16>::new("c", 0xFFFE));
+        assert_eq!(block.requests(), 1);
+        assert_eq!(block.ranges[0].addr, 0xFFFC);
+        assert_eq!(block.ranges[0].len, 4);
+    }
+
+    #[test]
+    fn split_at_max_read_len() {
+        let block = RegisterBlock::new(MAX_READ_REGISTERS)
+            .with(Register::<u16>::new("a", 0))
+            .with(Register::<u16>::new("b", MAX_READ_REGISTERS - 1))
+            .with(Register::<u16>::new("c", MAX_READ_REGISTERS));
+        assert_eq!(block.requests(), 2);
+        assert_eq!(block.ranges[0].len, MAX_READ_REGISTERS);
+    }
+}
@@ -0,0 +1,61 @@
+//! Connecting to Modbus devices and bounding the time of requests.
+//!
+//! A dead RTU slave or a half-open TCP connection never answers, so
+//! every request is wrapped in [`timeout`] to detect the failure and
+//! reconnect with [`connect`].
+
+use std::{fmt::Display, future::Future, time::Duration};
+
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::ModbusError;
+
+/// Upper limit for the delay between two connection attempts
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Maximum time to wait for a response if not configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default of the `timeout` option of the services
+pub fn default_timeout() -> Duration {
+    DEFAULT_TIMEOUT
+}
+
+/// Call `connect` until it succeeds. Each attempt may take at most
+/// `timeout`. Failed attempts are retried starting with `retry_delay`
+/// and doubling the delay up to `MAX_RETRY_DELAY`.
+pub async fn connect<T, E, F>(
+    retry_delay: Duration,
+    timeout: Duration,
+    mut connect: impl FnMut() -> F,
+) -> T
+where
+    E: Display,
+    F: Future<Output = Result<T, E>>,
+{
+    let mut delay = retry_delay;
+    loop {
+        match tokio::time::timeout(timeout, connect()).await {
+            Ok(Ok(connection)) => {
+                info!("Connected.");
+                return connection;
+            }
+            Ok(Err(e)) => warn!("Connection failed: {}, retrying in {:?}", e, delay),
+            Err(_) => warn!("Connection timed out, retrying in {:?}", delay),
+        }
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY.max(retry_delay));
+    }
+}
+
+/// Fail with `ModbusError::Timeout` if `request` does not complete
+/// within `timeout`.
+pub async fn timeout<T>(
+    timeout: Duration,
+    request: impl Future<Output = Result<T, ModbusError>>,
+) -> Result<T, ModbusError> {
+    tokio::time::timeout(timeout, request)
+        .await
+        .map_err(|_| ModbusError::Timeout)?
+}
@@ -0,0 +1,32 @@
+//! Typed access to Modbus registers.
+//!
+//! A `Register<T>` describes the location, kind and encoding of a value
+//! of type `T`. Registers can be read and written one by one or grouped
+//! into a `RegisterBlock` which is read with as few requests as
+//! possible. The `connection` module contains the reconnect and
+//! timeout handling shared by the services.
+
+pub mod block;
+pub mod connection;
+pub mod register;
+pub mod types;
+
+use thiserror::Error;
+
+pub use block::{RegisterBlock, Registers};
+pub use register::{read, write, ByteOrder, Kind, Register, WordOrder};
+pub use types::{Bitfield16, Bitfield32, Str, Type};
+
+#[derive(Debug, Error)]
+pub enum ModbusError {
+    #[error("Modbus error")]
+    Error(#[from] tokio_modbus::Error),
+    #[error("Modbus exception")]
+    Exception(#[from] tokio_modbus::ExceptionCode),
+    #[error("Invalid value in register {0}")]
+    Decode(String),
+    #[error("Register {0} is not writable")]
+    NotWritable(String),
+    #[error("Modbus request timed out")]
+    Timeout,
+}
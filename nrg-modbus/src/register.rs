@@ -0,0 +1,323 @@
+use std::marker::PhantomData;
+
+use serde::Deserialize;
+use tokio_modbus::client::{Reader, Writer};
+
+use crate::{ModbusError, Type};
+
+/// The four data tables of the Modbus data model
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Read-write 16 bit registers
+    Holding,
+    /// Read-only 16 bit registers
+    Input,
+    /// Read-write single bits
+    Coil,
+    /// Read-only single bits
+    Discrete,
+}
+
+impl Kind {
+    /// `true` for coils and discrete inputs
+    pub const fn is_bit(&self) -> bool {
+        matches!(self, Self::Coil | Self::Discrete)
+    }
+}
+
+/// Order of the registers of values spanning multiple registers
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WordOrder {
+    /// Most significant word first
+    BigEndian,
+    /// Least significant word first
+    LittleEndian,
+}
+
+/// Order of the two bytes within a register
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ByteOrder {
+    /// Most significant byte first as defined by the Modbus
+    /// specification
+    BigEndian,
+    /// Least significant byte first
+    LittleEndian,
+}
+
+#[derive(Debug)]
+pub struct Register<'a, T: Type> {
+    pub name: &'a str,
+    pub addr: u16,
+    pub kind: Kind,
+    pub word_order: WordOrder,
+    pub byte_order: ByteOrder,
+    /// Factor which is applied by `Register::scaled`
+    pub scale: f64,
+    t: PhantomData<T>,
+}
+
+// Deriving would require `T: Copy`
+impl<T: Type> Clone for Register<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Type> Copy for Register<'_, T> {}
+
+impl<'a, T: Type> Register<'a, T> {
+    /// Holding register using big-endian word and byte order
+    pub const fn new(name: &'a str, addr: u16) -> Self {
+        Self {
+            name,
+            addr,
+            kind: Kind::Holding,
+            word_order: WordOrder::BigEndian,
+            byte_order: ByteOrder::BigEndian,
+            scale: 1.0,
+            t: PhantomData,
+        }
+    }
+    pub const fn input(name: &'a str, addr: u16) -> Self {
+        Self::new(name, addr).kind(Kind::Input)
+    }
+    pub const fn coil(name: &'a str, addr: u16) -> Self {
+        Self::new(name, addr).kind(Kind::Coil)
+    }
+    pub const fn discrete(name: &'a str, addr: u16) -> Self {
+        Self::new(name, addr).kind(Kind::Discrete)
+    }
+    pub const fn kind(mut self, kind: Kind) -> Self {
+        self.kind = kind;
+        self
+    }
+    pub const fn word_order(mut self, word_order: WordOrder) -> Self {
+        self.word_order = word_order;
+        self
+    }
+    pub const fn byte_order(mut self, byte_order: ByteOrder) -> Self {
+        self.byte_order = byte_order;
+        self
+    }
+    pub const fn scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+    /// Number of registers or coils occupied by this register
+    pub const fn count(&self) -> u16 {
+        if self.kind.is_bit() {
+            T::BITS
+        } else {
+            T::LEN
+        }
+    }
+    /// Convert between the big-endian words used by `Type` and the
+    /// order used by the device. The conversion is its own inverse.
+    fn reorder(&self, data: &mut [u16]) {
+        if self.byte_order == ByteOrder::LittleEndian {
+            for word in data.iter_mut() {
+                *word = word.swap_bytes();
+            }
+        }
+        if self.word_order == WordOrder::LittleEndian {
+            data.reverse();
+        }
+    }
+    /// Decode the raw registers as read from the device.
+    pub fn decode(&self, data: &[u16]) -> Result<T, ModbusError> {
+        let mut data = data.to_vec();
+        self.reorder(&mut data);
+        T::decode(&data).ok_or_else(|| ModbusError::Decode(self.name.to_owned()))
+    }
+    /// Decode coils as read from the device.
+    pub fn decode_bits(&self, bits: &[bool]) -> Result<T, ModbusError> {
+        if bits.len() != usize::from(T::BITS) {
+            return Err(ModbusError::Decode(self.name.to_owned()));
+        }
+        self.decode(&pack_bits(bits, T::LEN))
+    }
+    /// Encode the value into the raw registers written to the device.
+    pub fn encode(&self, value: &T) -> Vec<u16> {
+        let mut data = value.encode().into_vec();
+        self.reorder(&mut data);
+        data
+    }
+    /// Apply the scaling factor to a value read from this register.
+    pub fn scaled(&self, value: T) -> f64
+    where
+        T: Into<f64>,
+    {
+        value.into() * self.scale
+    }
+}
+
+/// Pack coils into big-endian words. The first coil ends up in the
+/// least significant bit of the last word.
+fn pack_bits(bits: &[bool], len: u16) -> Vec<u16> {
+    let mut data = vec![0u16; usize::from(len)];
+    for (i, _) in bits.iter().enumerate().filter(|(_, bit)| **bit) {
+        if let Some(word) = data.len().checked_sub(1 + i / 16) {
+            data[word] |= 1 << (i % 16);
+        }
+    }
+    data
+}
+
+fn unpack_bits(data: &[u16], count: u16) -> Vec<bool> {
+    (0..usize::from(count))
+        .map(|i| match data.len().checked_sub(1 + i / 16) {
+            Some(word) => data[word] & (1 << (i % 16)) != 0,
+            None => false,
+        })
+        .collect()
+}
+
+/// Read the raw content of `count` registers or coils.
+pub(crate) async fn read_raw(
+    ctx: &mut impl Reader,
+    kind: Kind,
+    addr: u16,
+    count: u16,
+) -> Result<Raw, ModbusError> {
+    Ok(match kind {
+        Kind::Holding => Raw::Words(ctx.read_holding_registers(addr, count).await??),
+        Kind::Input => Raw::Words(ctx.read_input_registers(addr, count).await??),
+        Kind::Coil => Raw::Bits(ctx.read_coils(addr, count).await??),
+        Kind::Discrete => Raw::Bits(ctx.read_discrete_inputs(addr, count).await??),
+    })
+}
+
+/// Raw content of registers or coils
+pub(crate) enum Raw {
+    Words(Vec<u16>),
+    Bits(Vec<bool>),
+}
+
+impl Raw {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Words(words) => words.len(),
+            Self::Bits(bits) => bits.len(),
+        }
+    }
+    /// Decode `reg` located at `offset` within this data.
+    pub(crate) fn decode<T: Type>(
+        &self,
+        reg: &Register<'_, T>,
+        offset: usize,
+    ) -> Option<Result<T, ModbusError>> {
+        let range = offset..offset + usize::from(reg.count());
+        Some(match self {
+            Self::Words(words) => reg.decode(words.get(range)?),
+            Self::Bits(bits) => reg.decode_bits(bits.get(range)?),
+        })
+    }
+}
+
+pub async fn read<T: Type>(ctx: &mut impl Reader, reg: Register<'_, T>) -> Result<T, ModbusError> {
+    let raw = read_raw(ctx, reg.kind, reg.addr, reg.count()).await?;
+    raw.decode(&reg, 0)
+        .unwrap_or_else(|| Err(ModbusError::Decode(reg.name.to_owned())))
+}
+
+/// Write a value using a single request. Values occupying multiple
+/// registers or coils are written with one multi-write request.
+pub async fn write<T: Type>(
+    ctx: &mut impl Writer,
+    reg: Register<'_, T>,
+    value: &T,
+) -> Result<(), ModbusError> {
+    let data = reg.encode(value);
+    match reg.kind {
+        Kind::Holding => match *data {
+            [word] => ctx.write_single_register(reg.addr, word).await??,
+            _ => ctx.write_multiple_registers(reg.addr, &data).await??,
+        },
+        Kind::Coil => match *unpack_bits(&data, T::BITS) {
+            [bit] => ctx.write_single_coil(reg.addr, bit).await??,
+            ref bits => ctx.write_multiple_coils(reg.addr, bits).await??,
+        },
+        Kind::Input | Kind::Discrete => return Err(ModbusError::NotWritable(reg.name.to_owned())),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Bitfield16;
+
+    const VALUE: Register<u32> = Register::new("value", 100);
+
+    #[test]
+    fn word_and_byte_order() {
+        for (reg, words) in [
+            (VALUE, [0x1234, 0x5678]),
+            (VALUE.word_order(WordOrder::LittleEndian), [0x5678, 0x1234]),
+            (VALUE.byte_order(ByteOrder::LittleEndian), [0x3412, 0x7856]),
+            (
+                VALUE
+                    .word_order(WordOrder::LittleEndian)
+                    .byte_order(ByteOrder::LittleEndian),
+                [0x7856, 0x3412],
+            ),
+        ] {
+            assert_eq!(reg.encode(&0x1234_5678), words);
+            assert_eq!(reg.decode(&words).unwrap(), 0x1234_5678);
+        }
+    }
+
+    #[test]
+    fn float_word_order() {
+        let reg = Register::<f32>::new("power", 0).word_order(WordOrder::LittleEndian);
+        assert_eq!(reg.encode(&-230.25), [0x4000, 0xC366]);
+        assert_eq!(reg.decode(&[0x4000, 0xC366]).unwrap(), -230.25);
+    }
+
+    #[test]
+    fn signed_scaled() {
+        let reg = Register::<i16>::input("temperature", 0).scale(0.1);
+        let value = reg.decode(&[0xFF38]).unwrap();
+        assert_eq!(value, -200);
+        assert_eq!(reg.scaled(value), -20.0);
+    }
+
+    #[test]
+    fn decode_error() {
+        assert!(matches!(
+            VALUE.decode(&[1]),
+            Err(ModbusError::Decode(name)) if name == "value"
+        ));
+    }
+
+    #[test]
+    fn pack_and_unpack_bits() {
+        let mut bits = vec![false; 32];
+        bits[0] = true;
+        bits[17] = true;
+        bits[31] = true;
+        let words = pack_bits(&bits, 2);
+        assert_eq!(words, [0x8002, 0x0001]);
+        assert_eq!(unpack_bits(&words, 32), bits);
+        // Bits beyond the data read as false
+        assert!(!unpack_bits(&[1], 17)[16]);
+    }
+
+    #[test]
+    fn coils() {
+        let reg = Register::<Bitfield16>::coil("flags", 0);
+        assert_eq!(reg.count(), 16);
+        let mut bits = vec![false; 16];
+        bits[2] = true;
+        assert_eq!(reg.decode_bits(&bits).unwrap(), Bitfield16(0x0004));
+        assert!(reg.decode_bits(&bits[..8]).is_err());
+
+        let flag = Register::<bool>::coil("flag", 0);
+        assert_eq!(flag.count(), 1);
+        assert!(flag.decode_bits(&[true]).unwrap());
+        assert_eq!(unpack_bits(&flag.encode(&true), 1), [true]);
+    }
+}
@@ -0,0 +1,276 @@
+//! Value types which can be stored in Modbus registers.
+//!
+//! All types decode from and encode to big-endian words with the most
+//! significant word first. Other word and byte orders are handled by
+//! the `Register` before the data is passed to the type.
+
+/// A value stored in one or more consecutive registers
+pub trait Type: Sized {
+    /// Number of registers occupied by the value
+    const LEN: u16;
+    /// Number of coils occupied by the value if it is stored in coils
+    /// or discrete inputs. The first coil is mapped to the least
+    /// significant bit of the value.
+    const BITS: u16 = Self::LEN * 16;
+    fn decode(data: &[u16]) -> Option<Self>;
+    fn encode(&self) -> Box<[u16]>;
+}
+
+impl Type for u16 {
+    const LEN: u16 = 1;
+    fn decode(data: &[u16]) -> Option<Self> {
+        let [w0] = *data else { return None };
+        Some(w0)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        Box::new([*self])
+    }
+}
+
+impl Type for i16 {
+    const LEN: u16 = 1;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u16::decode(data).map(|value| value as i16)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        (*self as u16).encode()
+    }
+}
+
+impl Type for u32 {
+    const LEN: u16 = 2;
+    fn decode(data: &[u16]) -> Option<Self> {
+        let [w1, w0] = *data else { return None };
+        Some(((w1 as u32) << 16) + (w0 as u32))
+    }
+    fn encode(&self) -> Box<[u16]> {
+        Box::new([(self >> 16) as u16, *self as u16])
+    }
+}
+
+impl Type for i32 {
+    const LEN: u16 = 2;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u32::decode(data).map(|value| value as i32)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        (*self as u32).encode()
+    }
+}
+
+impl Type for u64 {
+    const LEN: u16 = 4;
+    fn decode(data: &[u16]) -> Option<Self> {
+        let [w3, w2, w1, w0] = *data else { return None };
+        Some(((w3 as u64) << 48) + ((w2 as u64) << 32) + ((w1 as u64) << 16) + (w0 as u64))
+    }
+    fn encode(&self) -> Box<[u16]> {
+        Box::new([
+            (self >> 48) as u16,
+            (self >> 32) as u16,
+            (self >> 16) as u16,
+            *self as u16,
+        ])
+    }
+}
+
+impl Type for i64 {
+    const LEN: u16 = 4;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u64::decode(data).map(|value| value as i64)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        (*self as u64).encode()
+    }
+}
+
+/// IEEE 754 single precision float
+impl Type for f32 {
+    const LEN: u16 = 2;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u32::decode(data).map(f32::from_bits)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        self.to_bits().encode()
+    }
+}
+
+/// IEEE 754 double precision float
+impl Type for f64 {
+    const LEN: u16 = 4;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u64::decode(data).map(f64::from_bits)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        self.to_bits().encode()
+    }
+}
+
+/// A single coil or a register which is `true` if it is not zero
+impl Type for bool {
+    const LEN: u16 = 1;
+    const BITS: u16 = 1;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u16::decode(data).map(|value| value != 0)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        (*self as u16).encode()
+    }
+}
+
+/// A string of `N` registers containing two characters each. Trailing
+/// NUL characters and spaces are removed when decoding and the string
+/// is padded with NUL characters when encoding.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Str<const N: u16>(pub String);
+
+impl<const N: u16> Type for Str<N> {
+    const LEN: u16 = N;
+    fn decode(data: &[u16]) -> Option<Self> {
+        if data.len() != usize::from(N) {
+            return None;
+        }
+        let bytes: Vec<u8> = data.iter().flat_map(|word| word.to_be_bytes()).collect();
+        let s = String::from_utf8(bytes).ok()?;
+        Some(Self(s.trim_end_matches(['\0', ' ']).to_owned()))
+    }
+    fn encode(&self) -> Box<[u16]> {
+        let mut bytes = self.0.as_bytes().to_vec();
+        bytes.resize(usize::from(N) * 2, 0);
+        bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect()
+    }
+}
+
+impl<const N: u16> std::fmt::Display for Str<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A register or 16 coils used as individual flags
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Bitfield16(pub u16);
+
+impl Bitfield16 {
+    pub fn get(&self, bit: u8) -> bool {
+        self.0 & (1 << bit) != 0
+    }
+    pub fn set(&mut self, bit: u8, value: bool) {
+        if value {
+            self.0 |= 1 << bit;
+        } else {
+            self.0 &= !(1 << bit);
+        }
+    }
+}
+
+impl Type for Bitfield16 {
+    const LEN: u16 = 1;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u16::decode(data).map(Self)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        self.0.encode()
+    }
+}
+
+/// Two registers or 32 coils used as individual flags
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Bitfield32(pub u32);
+
+impl Bitfield32 {
+    pub fn get(&self, bit: u8) -> bool {
+        self.0 & (1 << bit) != 0
+    }
+    pub fn set(&mut self, bit: u8, value: bool) {
+        if value {
+            self.0 |= 1 << bit;
+        } else {
+            self.0 &= !(1 << bit);
+        }
+    }
+}
+
+impl Type for Bitfield32 {
+    const LEN: u16 = 2;
+    fn decode(data: &[u16]) -> Option<Self> {
+        u32::decode(data).map(Self)
+    }
+    fn encode(&self) -> Box<[u16]> {
+        self.0.encode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Type + PartialEq + std::fmt::Debug>(value: T, words: &[u16]) {
+        assert_eq!(&*value.encode(), words);
+        assert_eq!(T::decode(words), Some(value));
+    }
+
+    #[test]
+    fn integers() {
+        round_trip(0x1234u16, &[0x1234]);
+        round_trip(-2i16, &[0xFFFE]);
+        round_trip(0x1234_5678u32, &[0x1234, 0x5678]);
+        round_trip(-2i32, &[0xFFFF, 0xFFFE]);
+        round_trip(i32::MIN, &[0x8000, 0x0000]);
+        round_trip(0x1234_5678_9ABC_DEF0u64, &[0x1234, 0x5678, 0x9ABC, 0xDEF0]);
+        round_trip(-2i64, &[0xFFFF, 0xFFFF, 0xFFFF, 0xFFFE]);
+    }
+
+    #[test]
+    fn floats() {
+        round_trip(1.5f32, &[0x3FC0, 0x0000]);
+        round_trip(-230.25f32, &[0xC366, 0x4000]);
+        round_trip(1.5f64, &[0x3FF8, 0x0000, 0x0000, 0x0000]);
+        assert!(f32::decode(&[0x7FC0, 0x0000]).unwrap().is_nan());
+    }
+
+    #[test]
+    fn bool() {
+        round_trip(true, &[1]);
+        round_trip(false, &[0]);
+        assert_eq!(bool::decode(&[0x0100]), Some(true));
+    }
+
+    #[test]
+    fn wrong_length() {
+        assert_eq!(u16::decode(&[]), None);
+        assert_eq!(u32::decode(&[1]), None);
+        assert_eq!(u64::decode(&[1, 2, 3]), None);
+        assert_eq!(Str::<2>::decode(&[0x4142]), None);
+    }
+
+    #[test]
+    fn str_padding() {
+        round_trip(Str::<3>("ABC".into()), &[0x4142, 0x4300, 0x0000]);
+        // Trailing spaces are removed as well
+        assert_eq!(Str::<2>::decode(&[0x4142, 0x2020]), Some(Str("AB".into())));
+        assert_eq!(Str::<2>::decode(&[0, 0]), Some(Str(String::new())));
+        assert_eq!(Str::<1>::decode(&[0xFF00]), None);
+    }
+
+    #[test]
+    fn bitfields() {
+        let mut bits = Bitfield16::default();
+        bits.set(0, true);
+        bits.set(15, true);
+        bits.set(3, true);
+        bits.set(3, false);
+        assert!(bits.get(0) && bits.get(15) && !bits.get(3));
+        round_trip(bits, &[0x8001]);
+
+        let mut bits = Bitfield32::default();
+        bits.set(31, true);
+        bits.set(16, true);
+        bits.set(1, true);
+        round_trip(bits, &[0x8001, 0x0002]);
+        assert!(bits.get(16) && !bits.get(15));
+    }
+}
@@ -0,0 +1,129 @@
+//! Reconnecting to a local Modbus TCP server which drops connections or
+//! stops answering.
+
+use std::{
+    future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use nrg_modbus::{connection, ModbusError, Register};
+use tokio::{
+    net::TcpListener,
+    time::{sleep, Instant},
+};
+use tokio_modbus::{
+    client::{tcp, Context},
+    prelude::*,
+    server::{
+        tcp::{accept_tcp_connection, Server},
+        Service,
+    },
+};
+
+const VALUE: Register<u32> = Register::new("value", 100);
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+struct ValueService;
+
+impl Service for ValueService {
+    type Request = Request<'static>;
+    type Response = Response;
+    type Exception = ExceptionCode;
+    type Future = future::Ready<Result<Self::Response, Self::Exception>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        future::ready(match req {
+            Request::ReadHoldingRegisters(100, 2) => {
+                Ok(Response::ReadHoldingRegisters(vec![0x1234, 0x5678]))
+            }
+            _ => Err(ExceptionCode::IllegalDataAddress),
+        })
+    }
+}
+
+/// Serve `VALUE` on `listener`. The first `drop` connections are closed
+/// right after they have been accepted. Returns the number of accepted
+/// connections.
+fn serve(listener: TcpListener, drop: usize) -> Arc<AtomicUsize> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        let on_connected = |stream, socket_addr| {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                accept_tcp_connection(stream, socket_addr, |_| {
+                    Ok((count > drop).then_some(ValueService))
+                })
+            }
+        };
+        let _ = Server::new(listener)
+            .serve(&on_connected, |e| panic!("{}", e))
+            .await;
+    });
+    connections
+}
+
+async fn listen() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+async fn connect(addr: SocketAddr) -> Context {
+    connection::connect(RETRY_DELAY, TIMEOUT, || tcp::connect(addr)).await
+}
+
+async fn read(ctx: &mut Context) -> Result<u32, ModbusError> {
+    connection::timeout(TIMEOUT, nrg_modbus::read(ctx, VALUE)).await
+}
+
+#[tokio::test]
+async fn reconnect_after_dropped_connection() {
+    let (listener, addr) = listen().await;
+    let connections = serve(listener, 1);
+
+    let mut ctx = connect(addr).await;
+    assert!(matches!(read(&mut ctx).await, Err(ModbusError::Error(_))));
+
+    let mut ctx = connect(addr).await;
+    assert_eq!(read(&mut ctx).await.unwrap(), 0x1234_5678);
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn retry_until_server_is_listening() {
+    let (listener, addr) = listen().await;
+    drop(listener);
+    tokio::spawn(async move {
+        sleep(RETRY_DELAY * 3).await;
+        serve(TcpListener::bind(addr).await.unwrap(), 0);
+    });
+
+    let start = Instant::now();
+    let mut ctx = connect(addr).await;
+    assert!(start.elapsed() >= RETRY_DELAY * 3);
+    assert_eq!(read(&mut ctx).await.unwrap(), 0x1234_5678);
+}
+
+#[tokio::test]
+async fn timeout_if_server_does_not_answer() {
+    let (listener, addr) = listen().await;
+    // Accept connections but never answer, like a half-open connection
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+
+    let mut ctx = connect(addr).await;
+    let start = Instant::now();
+    assert!(matches!(read(&mut ctx).await, Err(ModbusError::Timeout)));
+    assert!(start.elapsed() >= TIMEOUT);
+}
@@ -1,8 +1,8 @@
 use std::{ops::Deref, sync::Arc};
 
-use rumqttc::{AsyncClient, ClientError, EventLoop, Packet, Publish, QoS};
+use rumqttc::{AsyncClient, ClientError, Event, EventLoop, Outgoing, Packet, Publish, QoS};
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::{sync::Mutex, task::JoinHandle};
 
 use crate::{
     config::MqttConfig,
@@ -45,6 +45,7 @@ type Subscriptions = Arc<Mutex<Vec<Subscription>>>;
 pub struct MqttClient {
     client: AsyncClient,
     subscriptions: Subscriptions,
+    eventloop: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl MqttClient {
@@ -52,13 +53,22 @@ impl MqttClient {
         let (client, eventloop) = config.client();
 
         let subscriptions = Arc::new(Mutex::new(Vec::new()));
-        tokio::spawn(run_eventloop(eventloop, subscriptions.clone()));
+        let eventloop = tokio::spawn(run_eventloop(eventloop, subscriptions.clone()));
 
         Self {
             client,
             subscriptions,
+            eventloop: Mutex::new(Some(eventloop)),
         }
     }
+    /// Disconnect once all pending messages are sent
+    pub async fn shutdown(&self) -> Result<(), ClientError> {
+        self.client.disconnect().await?;
+        if let Some(eventloop) = self.eventloop.lock().await.take() {
+            let _ = eventloop.await;
+        }
+        Ok(())
+    }
     pub async fn sub(
         &self,
         topic: &str,
@@ -82,8 +92,11 @@ impl Deref for MqttClient {
 
 async fn run_eventloop(mut eventloop: EventLoop, subscriptions: Subscriptions) {
     while let Ok(notification) = eventloop.poll().await {
-        let rumqttc::Event::Incoming(Packet::Publish(publish)) = notification else {
-            continue;
+        let publish = match notification {
+            Event::Incoming(Packet::Publish(publish)) => publish,
+            // Sent by `shutdown` after all pending messages
+            Event::Outgoing(Outgoing::Disconnect) => break,
+            _ => continue,
         };
         for subscription in subscriptions.lock().await.iter() {
             if subscription.pattern.matches(&publish.topic) {
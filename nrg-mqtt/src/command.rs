@@ -81,3 +81,12 @@ where
         Ok(self.0(serde_json::from_slice(data)?))
     }
 }
+
+/// Decoder for commands without arguments. The payload is ignored.
+pub struct TriggerDecoder<T>(pub fn() -> T);
+
+impl<T> Decoder<T> for TriggerDecoder<T> {
+    fn decode(&self, _data: &[u8]) -> Result<T> {
+        Ok(self.0())
+    }
+}
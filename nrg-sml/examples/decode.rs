@@ -0,0 +1,86 @@
+//! Print the readings contained in a recorded SML or D0 stream, e.g.
+//! `cargo run --example decode -- telegrams/emh-ehz.sml`.
+
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use clap::Parser;
+use nrg_sml::{
+    config::{MeterId, Protocol},
+    d0::{self, D0Error},
+    obis::ObisCode,
+    telegram::{SmlDecoder, Telegram, Value},
+};
+use tokio::io::BufReader;
+
+#[derive(Parser)]
+struct Args {
+    /// Defaults to `d0` for files ending in `.d0`
+    #[arg(long, value_parser = parse_protocol)]
+    protocol: Option<Protocol>,
+    file: PathBuf,
+}
+
+fn parse_protocol(s: &str) -> Result<Protocol, String> {
+    match s {
+        "sml" => Ok(Protocol::Sml),
+        "d0" => Ok(Protocol::D0),
+        _ => Err(format!("Unknown protocol: {}", s)),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let data = fs::read(&args.file)?;
+    let protocol =
+        args.protocol.unwrap_or_else(
+            || match args.file.extension().and_then(|ext| ext.to_str()) {
+                Some("d0") => Protocol::D0,
+                _ => Protocol::Sml,
+            },
+        );
+    match protocol {
+        Protocol::Sml => {
+            let mut decoder = SmlDecoder::new();
+            for &byte in &data {
+                match decoder.push_byte(byte) {
+                    Ok(None) => {}
+                    Ok(Some(telegrams)) => telegrams.iter().for_each(print),
+                    Err(e) => println!("{}", e),
+                }
+            }
+        }
+        Protocol::D0 => {
+            let mut reader = BufReader::new(&data[..]);
+            loop {
+                match d0::read_telegram(&mut reader).await {
+                    Ok((identification, telegram)) => {
+                        println!("{:?}", identification);
+                        print(&telegram);
+                    }
+                    Err(D0Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(e) => println!("{}", e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print(telegram: &Telegram) {
+    match &telegram.server_id {
+        Some(id) => println!("Telegram of {}", id),
+        None => println!("Telegram without server ID"),
+    }
+    for reading in &telegram.readings {
+        let code = ObisCode::from(reading.obj_name);
+        match &reading.value {
+            Value::Number(v) => println!("  {} = {}", code, v),
+            Value::Bool(v) => println!("  {} = {}", code, v),
+            Value::Bytes(v) if v.iter().all(|c| c.is_ascii_graphic() || *c == b' ') => {
+                println!("  {} = {:?}", code, String::from_utf8_lossy(v))
+            }
+            Value::Bytes(v) => println!("  {} = {}", code, MeterId(v.clone())),
+        }
+    }
+}
@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nrg_sml::d0::{self, D0Error};
+use tokio::io::BufReader;
+
+fuzz_target!(|data: &[u8]| {
+    d0::parse(data);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mut reader = BufReader::new(data);
+        loop {
+            match d0::read_telegram(&mut reader).await {
+                Ok((_, telegram)) => {
+                    telegram.ids();
+                }
+                Err(D0Error::Io(_)) => break,
+                Err(_) => {}
+            }
+        }
+    });
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nrg_sml::{obis::default_mappings, telegram::SmlDecoder};
+
+fuzz_target!(|data: &[u8]| {
+    let mappings = default_mappings();
+    let mut decoder = SmlDecoder::new();
+    for &byte in data {
+        let Ok(Some(telegrams)) = decoder.push_byte(byte) else {
+            continue;
+        };
+        for telegram in telegrams {
+            telegram.ids();
+            for reading in &telegram.readings {
+                for mapping in &mappings {
+                    if mapping.code.matches(&reading.obj_name) {
+                        mapping.convert(&reading.value);
+                    }
+                }
+            }
+        }
+    }
+});
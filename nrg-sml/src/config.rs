@@ -1,23 +1,338 @@
-use nrg_hass::config::HomeAssistantConfig;
+use std::{fmt, path::PathBuf, time::Duration};
+
+use nrg_hass::{config::HomeAssistantConfig, policy::PublishPolicy};
 use nrg_mqtt::config::MqttConfig;
 
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::{
+    d0::Mode,
+    obis::{default_mappings, ObisMapping},
+};
 
 #[derive(Debug, Deserialize)]
+#[serde(try_from = "ConfigFile")]
 pub struct Config {
-    pub serial: SerialConfig,
     pub mqtt: MqttConfig,
+    /// IR heads configured as `[[input]]` tables. All inputs are read
+    /// concurrently.
+    #[serde(rename = "input")]
+    pub inputs: Vec<InputConfig>,
+    /// Meters configured as `[[meter]]` tables
+    #[serde(rename = "meter")]
+    pub meters: Vec<MeterConfig>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("No input configured")]
+    NoInput,
+    #[error("No meter configured")]
+    NoMeter,
+    #[error(
+        "A meter configured without [[input]] and [[meter]] requires [serial] and [home-assistant]"
+    )]
+    IncompleteMeter,
+}
+
+/// Layout of the config file. A single meter configured with the
+/// top-level `[serial]` and `[home-assistant]` tables of earlier
+/// versions is accepted as well. It is read as SML with the default
+/// OBIS mapping, which publishes the same topics as before.
+#[derive(Deserialize)]
+struct ConfigFile {
+    mqtt: MqttConfig,
+    #[serde(default, rename = "input")]
+    inputs: Vec<InputConfig>,
+    #[serde(default, rename = "meter")]
+    meters: Vec<MeterConfig>,
+    serial: Option<SerialConfig>,
     #[serde(rename = "home-assistant")]
-    pub hass: HomeAssistantConfig,
+    hass: Option<HomeAssistantConfig>,
+}
+
+impl TryFrom<ConfigFile> for Config {
+    type Error = ConfigError;
+
+    fn try_from(file: ConfigFile) -> Result<Self, Self::Error> {
+        let (mut inputs, mut meters) = (file.inputs, file.meters);
+        match (file.serial, file.hass) {
+            (Some(serial), Some(hass)) => {
+                inputs.push(InputConfig::Serial(serial));
+                meters.push(MeterConfig {
+                    id: None,
+                    hass,
+                    mappings: default_mappings(),
+                    power_window: default_power_window(),
+                    publish: PublishPolicy::default(),
+                });
+            }
+            (None, None) => {}
+            _ => return Err(ConfigError::IncompleteMeter),
+        }
+        if inputs.is_empty() {
+            return Err(ConfigError::NoInput);
+        }
+        if meters.is_empty() {
+            return Err(ConfigError::NoMeter);
+        }
+        Ok(Self {
+            mqtt: file.mqtt,
+            inputs,
+            meters,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputConfig {
+    /// IR head connected via USB or UART
+    Serial(SerialConfig),
+    /// Network IR reader sending the raw SML stream, e.g. Tasmota,
+    /// ser2net or Hichi WiFi
+    Tcp(TcpConfig),
+    /// Raw SML stream recorded to a file
+    File(FileConfig),
+    /// TCP or UDP traffic of a network IR reader recorded by tcpdump
+    Pcap(PcapConfig),
+}
+
+/// Protocol spoken by the meter
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// Smart Message Language as sent by most modern meters
+    #[default]
+    Sml,
+    /// IEC 62056-21 ASCII telegrams of older meters
+    D0,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct SerialConfig {
     pub device: String,
-    #[serde(default = "default_baud")]
-    pub baud: u32,
+    /// Defaults to 9600 baud or 300 baud if a D0 `mode` is configured
+    pub baud: Option<u32>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Request D0 telegrams from the meter instead of waiting for
+    /// telegrams sent by the meter. Implies `protocol = "d0"`.
+    pub mode: Option<Mode>,
+    /// Delay between two requests if `mode` is set
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: Duration,
+}
+
+fn default_poll_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl SerialConfig {
+    pub fn baud(&self) -> u32 {
+        match (self.baud, self.mode) {
+            (Some(baud), _) => baud,
+            (None, Some(_)) => 300,
+            (None, None) => 9600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TcpConfig {
+    /// `host:port` of the IR reader
+    pub addr: String,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Time without data after which the connection is opened again.
+    /// Meters send a telegram every few seconds, so a longer silence
+    /// means the reader dropped off without closing the connection.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: Duration,
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FileConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Start over at the end of the file
+    #[serde(default)]
+    pub repeat: bool,
 }
 
-fn default_baud() -> u32 {
-    9600
+#[derive(Debug, Deserialize)]
+pub struct PcapConfig {
+    pub path: PathBuf,
+    /// Only use packets sent from this port
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Delay the packets like they were captured
+    #[serde(default)]
+    pub realtime: bool,
+    /// Start over at the end of the file
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MeterConfig {
+    /// Server ID of the meter as sent in every SML response or as
+    /// OBIS value 96.1.0. A meter without an ID receives all telegrams
+    /// which do not belong to any other meter.
+    pub id: Option<MeterId>,
+    #[serde(rename = "home-assistant")]
+    pub hass: HomeAssistantConfig,
+    /// Values published via MQTT configured as `[[meter.obis]]`
+    /// tables. Replaces the default mapping if present.
+    #[serde(rename = "obis", default = "default_mappings")]
+    pub mappings: Vec<ObisMapping>,
+    /// Window over which the power is averaged if it has to be derived
+    /// from the energy counters because the meter does not send 16.7.0
+    #[serde(default = "default_power_window")]
+    pub power_window: Duration,
+    /// When the values are published. Can be overridden per OBIS
+    /// mapping. By default every telegram is published.
+    #[serde(default)]
+    pub publish: PublishPolicy,
+}
+
+fn default_power_window() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Meter identification written as hex string. Spaces, colons and
+/// dashes between the bytes are ignored, e.g. `0a 01 45 4d 48 00 00 12
+/// 34 56`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MeterId(pub Vec<u8>);
+
+impl fmt::Display for MeterId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for MeterId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let digits: Vec<u8> = s
+            .bytes()
+            .filter(|c| !matches!(c, b' ' | b':' | b'-'))
+            .collect();
+        if digits.is_empty() || !digits.len().is_multiple_of(2) {
+            return Err(de::Error::custom(format!("Invalid meter id: {}", s)));
+        }
+        digits
+            .chunks(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .map(Self)
+            .ok_or_else(|| de::Error::custom(format!("Invalid meter id: {}", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MQTT: &str = r#"
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        client_id = "nrg-sml"
+        topic_prefix = "nrg"
+    "#;
+
+    const HASS: &str = r#"
+        discovery_prefix = "homeassistant"
+        object_id = "grid"
+        name = "Netz"
+    "#;
+
+    #[test]
+    fn inputs_and_meters() {
+        let cfg: Config = toml::from_str(&format!(
+            "{}
+            [[input]]
+            [input.serial]
+            device = \"/dev/ttyUSB0\"
+
+            [[input]]
+            [input.tcp]
+            addr = \"192.168.1.50:8888\"
+
+            [[meter]]
+            id = \"0a 01 45 4d 48 00 00 12 34 56\"
+            [meter.home-assistant]
+            {}",
+            MQTT, HASS
+        ))
+        .unwrap();
+        assert_eq!(cfg.inputs.len(), 2);
+        assert_eq!(
+            cfg.meters[0].id,
+            Some(MeterId(vec![
+                0x0a, 0x01, 0x45, 0x4d, 0x48, 0, 0, 0x12, 0x34, 0x56
+            ]))
+        );
+    }
+
+    #[test]
+    fn single_meter_layout() {
+        let cfg: Config = toml::from_str(&format!(
+            "{}
+            [serial]
+            device = \"/dev/ttyUSB0\"
+            baud = 9600
+
+            [home-assistant]
+            {}",
+            MQTT, HASS
+        ))
+        .unwrap();
+        let [InputConfig::Serial(serial)] = &cfg.inputs[..] else {
+            panic!("Expected a single serial input");
+        };
+        assert_eq!(serial.device, "/dev/ttyUSB0");
+        assert_eq!(serial.protocol, Protocol::Sml);
+        let [meter] = &cfg.meters[..] else {
+            panic!("Expected a single meter");
+        };
+        assert_eq!(meter.id, None);
+        assert_eq!(meter.hass.object_id, "grid");
+        assert_eq!(meter.mappings.len(), default_mappings().len());
+    }
+
+    #[test]
+    fn incomplete_single_meter() {
+        let result = toml::from_str::<Config>(&format!(
+            "{}
+            [serial]
+            device = \"/dev/ttyUSB0\"",
+            MQTT
+        ));
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains(&ConfigError::IncompleteMeter.to_string()));
+        let result = toml::from_str::<Config>(MQTT);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains(&ConfigError::NoInput.to_string()));
+    }
 }
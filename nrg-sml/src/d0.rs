@@ -0,0 +1,393 @@
+//! Decoder for the ASCII protocol of IEC 62056-21 (D0) used by older
+//! meters.
+//!
+//! Telegrams sent by the meter without request as well as data
+//! readouts in mode A and C are supported. In mode C the baud rate
+//! offered by the meter is acknowledged and used for the readout.
+//!
+//! Values are converted to the units used by SML meters, e.g. `kWh` is
+//! published as `Wh`, so the same OBIS mappings can be used for both
+//! protocols.
+
+use std::{io, time::Duration};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    time::sleep,
+};
+use tokio_serial::{SerialPort, SerialStream};
+
+use crate::{
+    config::MeterId,
+    telegram::{Reading, Telegram, Value},
+};
+
+const STX: u8 = 0x02;
+const ETX: u8 = 0x03;
+const ACK: u8 = 0x06;
+
+/// Request message without device address
+const REQUEST: &[u8] = b"/?!\r\n";
+
+/// Telegrams are discarded if they are longer than this
+const MAX_LEN: usize = 16384;
+
+/// Maximum duration of a readout. Mode A readouts of meters with many
+/// values take some time at 300 baud.
+pub const READOUT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Protocol mode used to request a readout
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// The data is sent at the initial baud rate
+    A,
+    /// The data is sent at the baud rate offered by the meter after
+    /// it was acknowledged
+    C,
+}
+
+#[derive(Debug, Error)]
+pub enum D0Error {
+    #[error("Checksum mismatch")]
+    Checksum,
+    #[error("Telegram exceeds {MAX_LEN} bytes")]
+    Overflow,
+    #[error("Unsupported baud rate identifier: {0:?}")]
+    Baud(char),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Identification message sent by the meter, e.g. `/EMH5\@01LZQJL0014F`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identification {
+    /// Three letter manufacturer ID
+    pub manufacturer: String,
+    /// Baud rate identifier
+    pub baud: char,
+    pub model: String,
+}
+
+impl Identification {
+    /// Parse the identification without the leading `/` and CR LF
+    pub fn parse(line: &[u8]) -> Option<Self> {
+        let line = std::str::from_utf8(line).ok()?;
+        let manufacturer = line.get(..3)?;
+        let mut rest = line[3..].chars();
+        let baud = rest.next()?;
+        let rest = rest.as_str();
+        // Enhanced capabilities, e.g. `\2`
+        let model = match rest.strip_prefix('\\') {
+            Some(rest) => rest.get(1..).unwrap_or_default(),
+            None => rest,
+        };
+        Some(Self {
+            manufacturer: manufacturer.to_owned(),
+            baud,
+            model: model.to_owned(),
+        })
+    }
+
+    /// Baud rate offered for mode C
+    pub fn baud_rate(&self) -> Option<u32> {
+        Some(match self.baud {
+            '0' => 300,
+            '1' => 600,
+            '2' => 1200,
+            '3' => 2400,
+            '4' => 4800,
+            '5' => 9600,
+            '6' => 19200,
+            _ => return None,
+        })
+    }
+}
+
+/// Read the next telegram sent by the meter without request
+pub async fn read_telegram(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> Result<(Identification, Telegram), D0Error> {
+    let identification = read_identification(reader).await?;
+    let checked = peek_byte(reader).await? == STX;
+    if checked {
+        reader.consume(1);
+    }
+    let data = read_data(reader, checked).await?;
+    Ok((identification, parse(&data)))
+}
+
+/// Request a readout. The port is reset to the initial baud rate first
+/// as the previous readout may have switched it.
+pub async fn readout(
+    port: &mut SerialStream,
+    mode: Mode,
+    baud: u32,
+) -> Result<(Identification, Telegram), D0Error> {
+    port.set_baud_rate(baud).map_err(io::Error::from)?;
+    port.write_all(REQUEST).await?;
+    let mut reader = BufReader::new(port);
+    let identification = read_identification(&mut reader).await?;
+    if mode == Mode::C {
+        let rate = identification
+            .baud_rate()
+            .ok_or(D0Error::Baud(identification.baud))?;
+        // Protocol control character 0 (normal protocol) and mode
+        // control character 0 (data readout)
+        let ack = [ACK, b'0', identification.baud as u8, b'0', b'\r', b'\n'];
+        let port = reader.get_mut();
+        port.write_all(&ack).await?;
+        // Each character consists of 10 bits using 7E1
+        sleep(Duration::from_secs(ack.len() as u64 * 10) / baud).await;
+        port.set_baud_rate(rate).map_err(io::Error::from)?;
+    }
+    // Skip the echo of optical heads
+    while read_byte(&mut reader).await? != STX {}
+    let data = read_data(&mut reader, true).await?;
+    Ok((identification, parse(&data)))
+}
+
+/// Read a byte removing the parity bit. This allows reading meters
+/// via network IR readers configured for 8 data bits.
+async fn read_byte(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<u8> {
+    Ok(reader.read_u8().await? & 0x7f)
+}
+
+async fn peek_byte(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<u8> {
+    match reader.fill_buf().await?.first() {
+        Some(byte) => Ok(byte & 0x7f),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Read a line up to LF. Returns the line without CR LF.
+async fn read_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    line: &mut Vec<u8>,
+) -> Result<(), D0Error> {
+    loop {
+        match read_byte(reader).await? {
+            b'\n' => break,
+            _ if line.len() >= MAX_LEN => return Err(D0Error::Overflow),
+            byte => line.push(byte),
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(())
+}
+
+async fn read_identification(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> Result<Identification, D0Error> {
+    loop {
+        while read_byte(reader).await? != b'/' {}
+        let mut line = Vec::new();
+        read_line(reader, &mut line).await?;
+        // Echo of the request message
+        if line.starts_with(b"?") {
+            continue;
+        }
+        if let Some(identification) = Identification::parse(&line) {
+            return Ok(identification);
+        }
+    }
+}
+
+/// Read the data block up to the end line `!`. If the block started
+/// with STX it is followed by ETX and the block check character.
+async fn read_data(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    checked: bool,
+) -> Result<Vec<u8>, D0Error> {
+    let mut data = Vec::new();
+    loop {
+        let start = data.len();
+        read_line(reader, &mut data).await?;
+        data.extend_from_slice(b"\r\n");
+        if data[start..].starts_with(b"!") {
+            break;
+        }
+    }
+    if checked {
+        let etx = read_byte(reader).await?;
+        let bcc = read_byte(reader).await?;
+        // The BCC covers everything after STX including ETX
+        let expected = data.iter().fold(etx, |bcc, byte| bcc ^ byte);
+        if etx != ETX || bcc != expected {
+            return Err(D0Error::Checksum);
+        }
+    }
+    Ok(data)
+}
+
+/// Parse the data sets of a data block, e.g. `1.8.0(001234.5678*kWh)`.
+/// Data sets which cannot be parsed are ignored.
+pub fn parse(data: &[u8]) -> Telegram {
+    let mut telegram = Telegram::default();
+    let mut rest = data;
+    loop {
+        rest = rest.trim_ascii_start();
+        if rest.is_empty() || rest.starts_with(b"!") {
+            break;
+        }
+        let Some(open) = rest.iter().position(|&c| c == b'(') else {
+            break;
+        };
+        let address = &rest[..open];
+        let Some(close) = rest[open..].iter().position(|&c| c == b')') else {
+            break;
+        };
+        let value = &rest[open + 1..open + close];
+        rest = &rest[open + close + 1..];
+        // Further values of the same address, e.g. load profiles
+        while rest.starts_with(b"(") {
+            match rest.iter().position(|&c| c == b')') {
+                Some(close) => rest = &rest[close + 1..],
+                None => rest = &[],
+            }
+        }
+        let Some(obj_name) = parse_address(address) else {
+            continue;
+        };
+        let value = parse_value(obj_name, value);
+        // Device address
+        if obj_name[2..5] == [0, 0, 0] && telegram.server_id.is_none() {
+            if let Value::Bytes(id) = &value {
+                telegram.server_id = Some(MeterId(id.clone()));
+            }
+        }
+        telegram.readings.push(Reading { obj_name, value });
+    }
+    telegram
+}
+
+/// Parse an address like `1-0:1.8.0*255`, `1.8.0` or `C.1.0`. Missing
+/// groups are filled in like SML meters send them.
+fn parse_address(address: &[u8]) -> Option<[u8; 6]> {
+    let address = std::str::from_utf8(address).ok()?.trim();
+    let group = |s: &str| match s {
+        "C" => Some(96),
+        "F" => Some(97),
+        "L" => Some(98),
+        "P" => Some(99),
+        s => s.parse::<u8>().ok(),
+    };
+    let (ab, rest) = match address.split_once(':') {
+        Some((ab, rest)) => (Some(ab.split_once('-')?), rest),
+        None => (None, address),
+    };
+    let (cde, f) = match rest.split_once('*') {
+        Some((cde, f)) => (cde, group(f)?),
+        None => (rest, 255),
+    };
+    let mut cde = cde.split('.');
+    let c = group(cde.next()?)?;
+    let d = group(cde.next()?)?;
+    let e = cde.next().map_or(Some(0), group)?;
+    if cde.next().is_some() {
+        return None;
+    }
+    let (a, b) = match ab {
+        Some((a, b)) => (group(a)?, group(b)?),
+        // Abstract objects use medium 0, all others are electricity
+        None if c == 0 || c >= 96 => (0, 0),
+        None => (1, 0),
+    };
+    Some([a, b, c, d, e, f])
+}
+
+/// Parse a value like `001234.5678*kWh`. Identifications are kept as
+/// bytes even if they are numeric.
+fn parse_value(obj_name: [u8; 6], value: &[u8]) -> Value {
+    let identification = matches!(obj_name[2..4], [0, 0] | [96, 1]);
+    let (number, unit) = match value.iter().position(|&c| c == b'*') {
+        Some(pos) => (&value[..pos], &value[pos + 1..]),
+        None => (value, &b""[..]),
+    };
+    let number = std::str::from_utf8(number)
+        .ok()
+        .and_then(|number| number.trim().parse::<f64>().ok());
+    match number {
+        Some(number) if !identification => Value::Number(match unit {
+            b"kW" | b"kWh" | b"kvar" | b"kvarh" | b"kVA" | b"kVAh" => number * 1000.0,
+            _ => number,
+        }),
+        _ => Value::Bytes(value.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all(mut data: &[u8]) -> Vec<(Identification, Telegram)> {
+        let mut telegrams = Vec::new();
+        loop {
+            match read_telegram(&mut data).await {
+                Ok(telegram) => telegrams.push(telegram),
+                Err(D0Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        telegrams
+    }
+
+    fn number(telegram: &Telegram, obj_name: [u8; 6]) -> Option<f64> {
+        telegram.readings.iter().find_map(|r| match r.value {
+            Value::Number(v) if r.obj_name == obj_name => Some(v),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn easymeter_q3b() {
+        let telegrams = read_all(include_bytes!("../telegrams/easymeter-q3b.d0")).await;
+        assert_eq!(telegrams.len(), 3);
+        let (identification, telegram) = &telegrams[0];
+        assert_eq!(
+            identification,
+            &Identification {
+                manufacturer: "ESY".to_owned(),
+                baud: '5',
+                model: "Q3DA1024 V3.04".to_owned(),
+            }
+        );
+        assert_eq!(identification.baud_rate(), Some(9600));
+        assert_eq!(
+            telegram.server_id,
+            Some(MeterId(b"1ESY1160112345".to_vec()))
+        );
+        // kWh are converted to Wh
+        assert_eq!(number(telegram, [1, 0, 1, 8, 0, 255]), Some(12345678.9));
+        assert_eq!(number(telegram, [1, 0, 2, 8, 0, 255]), Some(0.0));
+        assert_eq!(number(telegram, [1, 0, 1, 7, 255, 255]), Some(181.46));
+        assert_eq!(number(telegram, [1, 0, 21, 7, 255, 255]), Some(123.45));
+        assert_eq!(
+            number(&telegrams[2].1, [1, 0, 1, 8, 0, 255]),
+            Some(12345679.1)
+        );
+    }
+
+    #[tokio::test]
+    async fn emh_lzqj() {
+        let telegrams = read_all(include_bytes!("../telegrams/emh-lzqj.d0")).await;
+        assert_eq!(telegrams.len(), 2);
+        let (identification, telegram) = &telegrams[0];
+        assert_eq!(identification.manufacturer, "EMH");
+        assert_eq!(identification.model, "01LZQJL0014F");
+        assert_eq!(telegram.server_id, Some(MeterId(b"10212345".to_vec())));
+        assert_eq!(number(telegram, [1, 0, 1, 8, 0, 255]), Some(12345678.0));
+        assert_eq!(number(telegram, [1, 0, 1, 8, 2, 255]), Some(2345678.0));
+        assert_eq!(number(telegram, [1, 0, 2, 8, 0, 255]), Some(123456.0));
+        assert_eq!(number(telegram, [1, 0, 16, 7, 0, 255]), Some(1234.0));
+        assert_eq!(number(telegram, [1, 0, 32, 7, 0, 255]), Some(230.1));
+        assert_eq!(
+            number(&telegrams[1].1, [1, 0, 1, 8, 0, 255]),
+            Some(12345679.0)
+        );
+    }
+}
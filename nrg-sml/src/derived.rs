@@ -0,0 +1,165 @@
+//! Power derived from the energy counters for meters which do not send
+//! the instantaneous power, e.g. in reduced data mode.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Time of a sample. The seconds index of the meter is preferred as
+/// telegrams may be delayed by the IR head or replayed from a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timestamp {
+    SecIndex(u32),
+    Received(Instant),
+}
+
+impl Timestamp {
+    /// Time elapsed since an earlier timestamp of the same kind
+    fn since(self, earlier: Self) -> Option<Duration> {
+        match (self, earlier) {
+            (Self::SecIndex(now), Self::SecIndex(earlier)) => now
+                .checked_sub(earlier)
+                .map(|secs| Duration::from_secs(secs.into())),
+            (Self::Received(now), Self::Received(earlier)) => now.checked_duration_since(earlier),
+            _ => None,
+        }
+    }
+}
+
+struct Sample {
+    time: Timestamp,
+    /// Counter of the consumed energy (1.8.0) in Wh
+    import: f64,
+    /// Counter of the returned energy (2.8.0) in Wh
+    export: f64,
+}
+
+/// Average net power over a sliding window. Positive values are
+/// consumption, negative values feed-in like 16.7.0.
+pub struct DerivedPower {
+    window: Duration,
+    samples: VecDeque<Sample>,
+}
+
+impl DerivedPower {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Add the current counter values and return the power in W once
+    /// the samples cover the window. The samples are discarded if a
+    /// counter or the time went backwards, e.g. because the meter was
+    /// replaced.
+    pub fn update(&mut self, time: Timestamp, import: f64, export: f64) -> Option<f64> {
+        if let Some(last) = self.samples.back() {
+            // Meters may send several telegrams within one second
+            if time == last.time {
+                return None;
+            }
+            if time.since(last.time).is_none() || import < last.import || export < last.export {
+                self.samples.clear();
+            }
+        }
+        self.samples.push_back(Sample {
+            time,
+            import,
+            export,
+        });
+        // Keep the newest sample which is at least `window` old so the
+        // average covers the whole window even if the meter sends less
+        // often.
+        while self.samples.len() > 2
+            && time
+                .since(self.samples[1].time)
+                .is_some_and(|age| age >= self.window)
+        {
+            self.samples.pop_front();
+        }
+        let first = self.samples.front()?;
+        let last = self.samples.back()?;
+        let span = last.time.since(first.time)?;
+        if span < self.window {
+            return None;
+        }
+        let hours = span.as_secs_f64() / 3600.0;
+        let energy = (last.import - first.import) - (last.export - first.export);
+        Some((energy / hours * 10.0).round() / 10.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn secs(secs: u32) -> Timestamp {
+        Timestamp::SecIndex(secs)
+    }
+
+    #[test]
+    fn power_from_two_samples() {
+        let mut power = DerivedPower::new(WINDOW);
+        assert_eq!(power.update(secs(100), 1000.0, 0.0), None);
+        assert_eq!(power.update(secs(130), 1012.5, 0.0), None);
+        // 25 Wh within a minute
+        assert_eq!(power.update(secs(160), 1025.0, 0.0), Some(1500.0));
+    }
+
+    #[test]
+    fn feed_in_is_negative() {
+        let mut power = DerivedPower::new(WINDOW);
+        power.update(secs(0), 1000.0, 500.0);
+        assert_eq!(power.update(secs(60), 1001.0, 511.0), Some(-600.0));
+    }
+
+    #[test]
+    fn window_slides() {
+        let mut power = DerivedPower::new(WINDOW);
+        power.update(secs(0), 0.0, 0.0);
+        assert_eq!(power.update(secs(60), 10.0, 0.0), Some(600.0));
+        // The first sample is dropped once the second one covers the
+        // window
+        assert_eq!(power.update(secs(120), 40.0, 0.0), Some(1800.0));
+    }
+
+    #[test]
+    fn same_time_is_ignored() {
+        let mut power = DerivedPower::new(WINDOW);
+        power.update(secs(0), 0.0, 0.0);
+        assert_eq!(power.update(secs(60), 10.0, 0.0), Some(600.0));
+        assert_eq!(power.update(secs(60), 11.0, 0.0), None);
+    }
+
+    #[test]
+    fn counter_reset_clears_window() {
+        let mut power = DerivedPower::new(WINDOW);
+        power.update(secs(0), 5000.0, 0.0);
+        assert_eq!(power.update(secs(60), 5010.0, 0.0), Some(600.0));
+        // New meter with lower counter values
+        assert_eq!(power.update(secs(120), 10.0, 0.0), None);
+        assert_eq!(power.update(secs(150), 15.0, 0.0), None);
+        assert_eq!(power.update(secs(180), 20.0, 0.0), Some(600.0));
+    }
+
+    #[test]
+    fn time_going_backwards_clears_window() {
+        let mut power = DerivedPower::new(WINDOW);
+        power.update(secs(1000), 0.0, 0.0);
+        assert_eq!(power.update(secs(10), 10.0, 0.0), None);
+        assert_eq!(power.update(secs(70), 20.0, 0.0), Some(600.0));
+    }
+
+    #[test]
+    fn received_time() {
+        let start = Instant::now();
+        let mut power = DerivedPower::new(WINDOW);
+        power.update(Timestamp::Received(start), 0.0, 0.0);
+        let time = Timestamp::Received(start + Duration::from_secs(90));
+        assert_eq!(power.update(time, 30.0, 0.0), Some(1200.0));
+    }
+}
@@ -0,0 +1,279 @@
+//! Sources of the raw SML byte stream.
+
+use std::{
+    fmt,
+    future::Future,
+    io::{self, Cursor, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    time::{sleep, sleep_until, timeout, Instant, Sleep},
+};
+use tokio_serial::{DataBits, Parity, SerialStream};
+
+use crate::{
+    config::{InputConfig, PcapConfig, Protocol, SerialConfig},
+    pcap,
+};
+
+pub type Input = Pin<Box<dyn AsyncRead + Send>>;
+
+impl InputConfig {
+    pub async fn open(&self) -> io::Result<Input> {
+        Ok(match self {
+            Self::Serial(cfg) => Box::pin(cfg.open()?),
+            Self::Tcp(cfg) => {
+                let stream = timeout(cfg.idle_timeout, TcpStream::connect(&cfg.addr))
+                    .await
+                    .map_err(|_| io::Error::new(ErrorKind::TimedOut, "Connecting timed out"))??;
+                Box::pin(IdleTimeout::new(stream, cfg.idle_timeout))
+            }
+            Self::File(cfg) => Box::pin(tokio::fs::File::open(&cfg.path).await?),
+            Self::Pcap(cfg) => replay(cfg).await?,
+        })
+    }
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            Self::Serial(cfg) => cfg.protocol(),
+            Self::Tcp(cfg) => cfg.protocol,
+            Self::File(cfg) => cfg.protocol,
+            Self::Pcap(cfg) => cfg.protocol,
+        }
+    }
+    /// Recordings end at the end of the file unless `repeat` is set.
+    /// All other inputs are opened again if they fail.
+    pub fn is_recording(&self) -> bool {
+        matches!(self, Self::File(_) | Self::Pcap(_))
+    }
+    pub fn repeat(&self) -> bool {
+        match self {
+            Self::Serial(_) | Self::Tcp(_) => true,
+            Self::File(cfg) => cfg.repeat,
+            Self::Pcap(cfg) => cfg.repeat,
+        }
+    }
+}
+
+impl SerialConfig {
+    pub fn protocol(&self) -> Protocol {
+        if self.mode.is_some() {
+            Protocol::D0
+        } else {
+            self.protocol
+        }
+    }
+    /// D0 uses 7 data bits with even parity, SML 8 data bits without
+    /// parity.
+    pub fn open(&self) -> io::Result<SerialStream> {
+        let builder = tokio_serial::new(&self.device, self.baud());
+        let builder = match self.protocol() {
+            Protocol::Sml => builder,
+            Protocol::D0 => builder.data_bits(DataBits::Seven).parity(Parity::Even),
+        };
+        Ok(SerialStream::open(&builder)?)
+    }
+}
+
+impl fmt::Display for InputConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Serial(cfg) => write!(f, "{}", cfg.device),
+            Self::Tcp(cfg) => write!(f, "tcp://{}", cfg.addr),
+            Self::File(cfg) => write!(f, "{}", cfg.path.display()),
+            Self::Pcap(cfg) => write!(f, "{}", cfg.path.display()),
+        }
+    }
+}
+
+/// Fails with `TimedOut` if no data was received within `timeout`
+struct IdleTimeout<R> {
+    inner: R,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<R> IdleTimeout<R> {
+    fn new(inner: R, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: Box::pin(sleep(timeout)),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for IdleTimeout<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.sleep.as_mut().reset(Instant::now() + this.timeout);
+                Poll::Ready(result)
+            }
+            Poll::Pending => match this.sleep.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("No data received within {:?}", this.timeout),
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+/// Replay the payloads of a pcap file. With `realtime` the payloads
+/// are delayed like they were captured.
+async fn replay(cfg: &PcapConfig) -> io::Result<Input> {
+    let packets = pcap::read(&tokio::fs::read(&cfg.path).await?, cfg.port)?;
+    if !cfg.realtime {
+        let data: Vec<u8> = packets.into_iter().flat_map(|p| p.payload).collect();
+        return Ok(Box::pin(Cursor::new(data)));
+    }
+    let (mut tx, rx) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let start = Instant::now();
+        for packet in packets {
+            sleep_until(start + packet.offset).await;
+            if tx.write_all(&packet.payload).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Box::pin(rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+    use crate::config::{FileConfig, TcpConfig};
+
+    const SERVER_PORT: u16 = 8888;
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("nrg-sml-{}-{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// IPv4 packet with a TCP segment as captured with linktype RAW
+    fn tcp_packet(src_port: u16, payload: &[u8]) -> Vec<u8> {
+        let total_len = (40 + payload.len()) as u16;
+        let mut packet = vec![0x45, 0];
+        packet.extend(total_len.to_be_bytes());
+        packet.extend([0, 0, 0, 0, 64, 6, 0, 0, 192, 168, 1, 50, 192, 168, 1, 10]);
+        packet.extend(src_port.to_be_bytes());
+        packet.extend(40000u16.to_be_bytes());
+        packet.extend([0, 0, 0, 0, 0, 0, 0, 0, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend(payload);
+        packet
+    }
+
+    fn pcap(packets: &[(u32, u16, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in [0xa1b2c3d4, 0x0004_0002, 0, 0, 65535, 101u32] {
+            data.extend(value.to_le_bytes());
+        }
+        for (secs, src_port, payload) in packets {
+            let packet = tcp_packet(*src_port, payload);
+            let len = packet.len() as u32;
+            for value in [*secs, 0, len, len] {
+                data.extend(value.to_le_bytes());
+            }
+            data.extend(packet);
+        }
+        data
+    }
+
+    fn pcap_input(name: &str, realtime: bool) -> InputConfig {
+        let data = pcap(&[
+            (100, SERVER_PORT, b"ab"),
+            (100, 40000, b"xx"),
+            (101, SERVER_PORT, b"cd"),
+        ]);
+        InputConfig::Pcap(PcapConfig {
+            path: temp_file(name, &data),
+            port: Some(SERVER_PORT),
+            protocol: Protocol::Sml,
+            realtime,
+            repeat: false,
+        })
+    }
+
+    /// Read a recording until its end and remove it afterwards
+    async fn read_all(cfg: &InputConfig) -> Vec<u8> {
+        let mut data = Vec::new();
+        cfg.open()
+            .await
+            .unwrap()
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        if let InputConfig::File(FileConfig { path, .. })
+        | InputConfig::Pcap(PcapConfig { path, .. }) = cfg
+        {
+            fs::remove_file(path).unwrap();
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn file() {
+        let path = temp_file("file.sml", b"\x1b\x1b\x1b\x1b");
+        let cfg = InputConfig::File(FileConfig {
+            path,
+            protocol: Protocol::Sml,
+            repeat: false,
+        });
+        assert_eq!(read_all(&cfg).await, b"\x1b\x1b\x1b\x1b");
+    }
+
+    #[tokio::test]
+    async fn pcap_payloads() {
+        let cfg = pcap_input("payloads.pcap", false);
+        assert_eq!(read_all(&cfg).await, b"abcd");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pcap_realtime() {
+        let cfg = pcap_input("realtime.pcap", true);
+        let start = Instant::now();
+        assert_eq!(read_all(&cfg).await, b"abcd");
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn tcp_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"ab").await.unwrap();
+            // Keep the connection open without sending anything
+            sleep(Duration::from_secs(10)).await;
+        });
+        let cfg = InputConfig::Tcp(TcpConfig {
+            addr: addr.to_string(),
+            protocol: Protocol::Sml,
+            idle_timeout: Duration::from_millis(200),
+        });
+        let mut input = cfg.open().await.unwrap();
+        let mut buf = [0; 2];
+        input.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ab");
+        let e = input.read_u8().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+}
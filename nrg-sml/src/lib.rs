@@ -0,0 +1,13 @@
+//! Reading of electricity meters via SML and IEC 62056-21 (D0).
+//!
+//! The binary publishes the values via MQTT. The decoding of the
+//! telegrams is available as library, e.g. for the fuzz targets.
+
+pub mod config;
+pub mod d0;
+pub mod derived;
+pub mod input;
+pub mod meter;
+pub mod obis;
+pub mod pcap;
+pub mod telegram;
@@ -1,29 +1,35 @@
-use std::fs;
-
-use nrg_hass::{
-    discovery::announce,
-    models::{device_class::DeviceClass, state_class::StateClass, unit::UnitOfMeasurement},
-    state::publish_state,
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
+
+use clap::Parser;
 use nrg_mqtt::client::MqttClient;
-use sml_rs::{
-    parser::{common::Value, complete},
-    transport::Decoder,
-    util::ArrayBuf,
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, BufReader},
+    sync::Mutex,
+    time::{sleep, timeout},
 };
-use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
-use tokio_serial::SerialStream;
-use tracing::Level;
+use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
-use config::{Config, SerialConfig};
+use nrg_sml::{
+    config::{Config, InputConfig, Protocol, SerialConfig},
+    d0::{self, D0Error},
+    meter::Meters,
+    telegram::SmlDecoder,
+};
 
-pub mod config;
+/// Delay before an input which failed is opened again
+const RETRY_DELAY: Duration = Duration::from_secs(10);
 
-pub(crate) fn uart_ir_sensor_data_stream(config: SerialConfig) -> impl AsyncRead {
-    let ttys_location = config.device;
-    let serial = tokio_serial::new(ttys_location, config.baud);
-    SerialStream::open(&serial).unwrap()
+#[derive(Parser)]
+struct Args {
+    #[arg(default_value = "nrg-sml.toml")]
+    config_file: PathBuf,
 }
 
 #[tokio::main(worker_threads = 2)]
@@ -34,123 +40,168 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    let data = fs::read("nrg-sml.toml").expect("Could not read config.toml");
+    let args = Args::parse();
+
+    let data = fs::read(args.config_file).expect("Could not read config.toml");
     let data = String::from_utf8(data).expect("Config file contains non-utf8 characters");
     let cfg: Config = toml::from_str(&data).expect("Error in config file");
 
-    let mqtt = MqttClient::new(&cfg.mqtt);
-
-    let hass_wh = nrg_hass::models::sensor::Sensor::builder()
-        .name(format!("{} Verbrauch", cfg.hass.name))
-        .object_id(format!("{}.{}", cfg.hass.object_id, "wh"))
-        .state_topic(format!("nrg/energy-meter/{}/{}", cfg.hass.object_id, "wh"))
-        .unique_id(format!("{}.{}", cfg.hass.object_id, "wh"))
-        .device_class(DeviceClass::Energy)
-        .state_class(StateClass::TotalIncreasing)
-        .unit_of_measurement(UnitOfMeasurement::WattHours)
-        .icon("mdi:transmission-tower-import")
-        .build()
-        .unwrap();
-
-    let hass_wh_return = nrg_hass::models::sensor::Sensor::builder()
-        .name(format!("{} Einspeisung", cfg.hass.name))
-        .object_id(format!("{}.{}", cfg.hass.object_id, "wh_return"))
-        .state_topic(format!(
-            "nrg/energy-meter/{}/{}",
-            cfg.hass.object_id, "wh_return"
-        ))
-        .unique_id(format!("{}.{}", cfg.hass.object_id, "wh_return"))
-        .device_class(DeviceClass::Energy)
-        .state_class(StateClass::TotalIncreasing)
-        .unit_of_measurement(UnitOfMeasurement::WattHours)
-        .icon("mdi:transmission-tower-export")
-        .build()
-        .unwrap();
-
-    let hass_w = nrg_hass::models::sensor::Sensor::builder()
-        .name(format!("{} Leistung", cfg.hass.name))
-        .object_id(format!("{}.{}", cfg.hass.object_id, "w"))
-        .state_topic(format!("nrg/energy-meter/{}/{}", cfg.hass.object_id, "w"))
-        .device_class(DeviceClass::Energy)
-        .unique_id(format!("{}.{}", cfg.hass.object_id, "w"))
-        .unit_of_measurement(UnitOfMeasurement::Watt)
-        .icon("mdi:home-lightning-bolt-outline")
-        .build()
-        .unwrap();
-
-    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass_wh).await?;
-    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass_wh_return).await?;
-    announce(&mqtt, &cfg.hass, &cfg.hass.object_id, &hass_w).await?;
-
-    let uart = uart_ir_sensor_data_stream(cfg.serial);
-    let mut reader = BufReader::new(uart);
-    let mut decoder = Decoder::<ArrayBuf<2048>>::new();
+    let mqtt = Arc::new(MqttClient::new(&cfg.mqtt));
+    let meters = Arc::new(Mutex::new(Meters::new(cfg.meters)));
+
+    // Every input is run in its own task so a failing input does not
+    // affect the other ones.
+    let inputs = cfg
+        .inputs
+        .into_iter()
+        .map(|input| {
+            tokio::spawn(supervise_input(
+                Arc::new(input),
+                mqtt.clone(),
+                meters.clone(),
+            ))
+        })
+        .collect::<Vec<_>>();
+    for input in inputs {
+        if let Err(e) = input.await {
+            error!("Supervising input failed: {}", e);
+        }
+    }
+    mqtt.shutdown().await?;
+    Ok(())
+}
+
+/// Run an input and restart it if it fails or panics.
+async fn supervise_input(cfg: Arc<InputConfig>, mqtt: Arc<MqttClient>, meters: Arc<Mutex<Meters>>) {
+    loop {
+        let input = tokio::spawn(run_input(cfg.clone(), mqtt.clone(), meters.clone()));
+        match input.await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => error!("Publishing values of {} failed: {}", cfg, e),
+            Err(e) => error!("Reading {} panicked: {}", cfg, e),
+        }
+        warn!("Restarting {} in {:?}", cfg, RETRY_DELAY);
+        sleep(RETRY_DELAY).await;
+    }
+}
+
+/// Read telegrams from the input. The input is opened again if it
+/// fails, e.g. because the IR head was unplugged. Recordings are only
+/// read once unless configured otherwise.
+async fn run_input(
+    cfg: Arc<InputConfig>,
+    mqtt: Arc<MqttClient>,
+    meters: Arc<Mutex<Meters>>,
+) -> Result<(), rumqttc::ClientError> {
+    loop {
+        match read_input(&cfg, &mqtt, &meters).await? {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && cfg.is_recording() => {
+                info!("End of {}", cfg);
+                if !cfg.repeat() {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => error!("Reading {} failed: {}", cfg, e),
+            Ok(()) => {}
+        }
+        if !cfg.repeat() {
+            return Ok(());
+        }
+        sleep(RETRY_DELAY).await;
+    }
+}
+
+async fn read_input(
+    cfg: &InputConfig,
+    mqtt: &MqttClient,
+    meters: &Mutex<Meters>,
+) -> Result<io::Result<()>, rumqttc::ClientError> {
+    if let InputConfig::Serial(
+        serial @ SerialConfig {
+            mode: Some(mode), ..
+        },
+    ) = cfg
+    {
+        let port = match serial.open() {
+            Ok(port) => port,
+            Err(e) => return Ok(Err(e)),
+        };
+        info!("Reading {}", cfg);
+        return poll_d0(port, *mode, serial, mqtt, meters).await;
+    }
+    let stream = match cfg.open().await {
+        Ok(stream) => stream,
+        Err(e) => return Ok(Err(e)),
+    };
+    info!("Reading {}", cfg);
+    match cfg.protocol() {
+        Protocol::Sml => read_sml(stream, mqtt, meters).await,
+        Protocol::D0 => read_d0(stream, mqtt, meters).await,
+    }
+}
+
+async fn read_sml(
+    stream: impl AsyncRead,
+    mqtt: &MqttClient,
+    meters: &Mutex<Meters>,
+) -> Result<io::Result<()>, rumqttc::ClientError> {
+    let mut reader = Box::pin(BufReader::new(stream));
+    let mut decoder = SmlDecoder::new();
 
     loop {
-        let byte = reader.read_u8().await?;
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(e) => return Ok(Err(e)),
+        };
         match decoder.push_byte(byte) {
             Ok(None) => {}
-            Ok(Some(decoded)) => {
-                let Ok(file) = complete::parse(decoded) else {
-                    println!("Parsing failed");
-                    continue;
-                };
-                for m in file.messages {
-                    let complete::MessageBody::GetListResponse(lst) = m.message_body else {
-                        continue;
-                    };
-                    let mut w: Option<i64> = None;
-                    let mut wh: Option<f64> = None;
-                    let mut wh_return: Option<f64> = None;
-                    for val in &lst.val_list {
-                        match val.obj_name[2..2 + 3] {
-                            [16, 7, 0] => {
-                                w = Some(match val.value {
-                                    Value::I32(wv) => wv.into(),
-                                    Value::I64(wv) => wv,
-                                    _ => panic!("Unsupported value {:?}", val.value),
-                                });
-                            }
-                            [1, 8, 0] => {
-                                let whv: i64 = match val.value {
-                                    Value::I64(whv) => whv,
-                                    Value::U64(whv) => whv.try_into().unwrap(),
-                                    Value::I32(whv) => whv.into(),
-                                    _ => panic!("Unsupported value {:?}", val.value),
-                                };
-                                wh =
-                                    Some((whv as f64) * 10f64.powi(val.scaler.unwrap_or(0).into()));
-                            }
-                            [2, 8, 0] => {
-                                let whv: i64 = match val.value {
-                                    Value::I64(whv) => whv,
-                                    Value::U64(whv) => whv.try_into().unwrap(),
-                                    Value::I32(whv) => whv.into(),
-                                    _ => panic!("Unsupported value {:?}", val.value),
-                                };
-                                wh_return =
-                                    Some((whv as f64) * 10f64.powi(val.scaler.unwrap_or(0).into()));
-                            }
-                            _ => {}
-                        }
-                    }
-                    println!(
-                        "[<- {:.4?} kWh] [-> {:.4?} kWh] [{} W]",
-                        wh.unwrap_or(0f64) / 1000f64,
-                        wh_return.unwrap_or(0f64) / 1000f64,
-                        w.unwrap_or(0)
-                    );
-
-                    publish_state(&mqtt, &hass_wh, wh.unwrap()).await.unwrap();
-                    publish_state(&mqtt, &hass_wh_return, wh_return.unwrap())
-                        .await
-                        .unwrap();
-                    publish_state(&mqtt, &hass_w, w.unwrap()).await.unwrap();
+            Ok(Some(telegrams)) => {
+                let mut meters = meters.lock().await;
+                for telegram in telegrams {
+                    meters.process(mqtt, &telegram).await?;
                 }
             }
-            Err(e) => {
-                println!("Unexpected error: {:?}", e);
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+/// Read D0 telegrams which are sent by the meter without request
+async fn read_d0(
+    stream: impl AsyncRead,
+    mqtt: &MqttClient,
+    meters: &Mutex<Meters>,
+) -> Result<io::Result<()>, rumqttc::ClientError> {
+    let mut reader = Box::pin(BufReader::new(stream));
+    loop {
+        match d0::read_telegram(&mut reader).await {
+            Ok((_, telegram)) => meters.lock().await.process(mqtt, &telegram).await?,
+            Err(D0Error::Io(e)) => return Ok(Err(e)),
+            Err(e) => warn!("Invalid D0 telegram: {}", e),
+        }
+    }
+}
+
+/// Request D0 readouts in the configured interval
+async fn poll_d0(
+    mut port: tokio_serial::SerialStream,
+    mode: d0::Mode,
+    cfg: &SerialConfig,
+    mqtt: &MqttClient,
+    meters: &Mutex<Meters>,
+) -> Result<io::Result<()>, rumqttc::ClientError> {
+    loop {
+        let readout = d0::readout(&mut port, mode, cfg.baud());
+        match timeout(d0::READOUT_TIMEOUT, readout).await {
+            Ok(Ok((identification, telegram))) => {
+                debug!("Readout of {:?}", identification);
+                meters.lock().await.process(mqtt, &telegram).await?;
             }
+            Ok(Err(D0Error::Io(e))) => return Ok(Err(e)),
+            Ok(Err(e)) => warn!("Readout of {} failed: {}", cfg.device, e),
+            Err(_) => warn!("Readout of {} timed out", cfg.device),
         }
+        sleep(cfg.poll_interval).await;
     }
 }
@@ -0,0 +1,314 @@
+//! Assignment of SML telegrams to the configured meters.
+
+use std::{collections::HashSet, time::Instant};
+
+use nrg_hass::{
+    config::HomeAssistantConfig,
+    discovery::announce,
+    models::sensor::Sensor,
+    policy::{PublishPolicy, Throttle},
+    state::publish_state,
+};
+use rumqttc::AsyncClient;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{MeterConfig, MeterId},
+    derived::{DerivedPower, Timestamp},
+    obis::{derived_power_mapping, ObisCode, ObisMapping},
+    telegram::{Reading, Telegram, Value},
+};
+
+const ENERGY_IMPORT: ObisCode = ObisCode {
+    medium: None,
+    channel: None,
+    quantity: 1,
+    processing: 8,
+    tariff: 0,
+    storage: None,
+};
+
+const ENERGY_EXPORT: ObisCode = ObisCode {
+    quantity: 2,
+    ..ENERGY_IMPORT
+};
+
+const POWER: ObisCode = ObisCode {
+    quantity: 16,
+    processing: 7,
+    ..ENERGY_IMPORT
+};
+
+/// A sensor which is announced once its OBIS code is first received
+struct MappedSensor {
+    mapping: ObisMapping,
+    sensor: Sensor,
+    throttle: Throttle,
+    announced: bool,
+    /// Set once a non-numeric value was reported to avoid flooding
+    /// the log
+    unsupported: bool,
+}
+
+impl MappedSensor {
+    fn new(cfg: &HomeAssistantConfig, mapping: ObisMapping, policy: &PublishPolicy) -> Self {
+        let mut builder = Sensor::builder();
+        builder
+            .name(format!("{} {}", cfg.name, mapping.name))
+            .object_id(format!("{}.{}", cfg.object_id, mapping.id))
+            .state_topic(format!("nrg/energy-meter/{}/{}", cfg.object_id, mapping.id))
+            .unique_id(format!("{}.{}", cfg.object_id, mapping.id));
+        if let Some(unit) = mapping.unit {
+            builder.unit_of_measurement(unit);
+        }
+        if let Some(device_class) = mapping.device_class {
+            builder.device_class(device_class);
+        }
+        if let Some(state_class) = mapping.state_class {
+            builder.state_class(state_class);
+        }
+        if let Some(icon) = &mapping.icon {
+            builder.icon(icon);
+        }
+        Self {
+            sensor: builder.build().unwrap(),
+            throttle: Throttle::new(mapping.publish.clone().unwrap_or_else(|| policy.clone())),
+            mapping,
+            announced: false,
+            unsupported: false,
+        }
+    }
+
+    async fn publish(
+        &mut self,
+        client: &AsyncClient,
+        hass: &HomeAssistantConfig,
+        value: f64,
+    ) -> Result<(), rumqttc::ClientError> {
+        let Some(value) = self.throttle.update(value, Instant::now()) else {
+            return Ok(());
+        };
+        if !self.announced {
+            info!(
+                "{}: found {} ({})",
+                hass.object_id, self.mapping.code, self.mapping.name
+            );
+            announce(client, hass, &hass.object_id, &self.sensor).await?;
+            self.announced = true;
+        }
+        debug!("{}: {} = {}", hass.object_id, self.mapping.id, value);
+        publish_state(client, &self.sensor, value).await
+    }
+}
+
+struct Meter {
+    id: Option<MeterId>,
+    hass: HomeAssistantConfig,
+    sensors: Vec<MappedSensor>,
+    /// Used if the meter does not send 16.7.0
+    power: DerivedPower,
+    power_sensor: MappedSensor,
+    /// ID of the meter as received. Used to log the assignment once.
+    seen: Option<MeterId>,
+}
+
+pub struct Meters {
+    meters: Vec<Meter>,
+    /// IDs of telegrams which could not be assigned to a meter
+    unknown: HashSet<Option<MeterId>>,
+}
+
+impl Meters {
+    pub fn new(cfg: Vec<MeterConfig>) -> Self {
+        Self {
+            meters: cfg
+                .into_iter()
+                .map(|meter| Meter {
+                    sensors: meter
+                        .mappings
+                        .into_iter()
+                        .map(|mapping| MappedSensor::new(&meter.hass, mapping, &meter.publish))
+                        .collect(),
+                    power: DerivedPower::new(meter.power_window),
+                    power_sensor: MappedSensor::new(
+                        &meter.hass,
+                        derived_power_mapping(),
+                        &meter.publish,
+                    ),
+                    id: meter.id,
+                    hass: meter.hass,
+                    seen: None,
+                })
+                .collect(),
+            unknown: HashSet::new(),
+        }
+    }
+
+    /// Publish the values of the telegram using the matching meter.
+    pub async fn process(
+        &mut self,
+        client: &AsyncClient,
+        telegram: &Telegram,
+    ) -> Result<(), rumqttc::ClientError> {
+        let ids = telegram.ids();
+        let Some(meter) = self.find(&ids) else {
+            let id = ids.into_iter().next();
+            if self.unknown.insert(id.clone()) {
+                match id {
+                    Some(id) => warn!("Ignoring telegrams of unknown meter {}", id),
+                    None => warn!("Ignoring telegrams of meter without ID"),
+                }
+            }
+            return Ok(());
+        };
+        if let Some(id) = ids.into_iter().next() {
+            if meter.seen.as_ref() != Some(&id) {
+                info!("Meter {} identified as {}", id, meter.hass.object_id);
+                meter.seen = Some(id);
+            }
+        }
+        meter.publish(client, &telegram.readings).await?;
+        meter.derive_power(client, telegram).await
+    }
+
+    /// Find the meter by the server ID of the telegram or the value of
+    /// 96.1.0. Meters without ID are used as fallback.
+    fn find(&mut self, ids: &[MeterId]) -> Option<&mut Meter> {
+        let pos = self
+            .meters
+            .iter()
+            .position(|meter| meter.id.as_ref().is_some_and(|id| ids.contains(id)))
+            .or_else(|| self.meters.iter().position(|meter| meter.id.is_none()))?;
+        Some(&mut self.meters[pos])
+    }
+}
+
+impl Meter {
+    async fn publish(
+        &mut self,
+        client: &AsyncClient,
+        values: &[Reading],
+    ) -> Result<(), rumqttc::ClientError> {
+        for val in values {
+            let Some(sensor) = self
+                .sensors
+                .iter_mut()
+                .find(|sensor| sensor.mapping.code.matches(&val.obj_name))
+            else {
+                continue;
+            };
+            let Some(value) = sensor.mapping.convert(&val.value) else {
+                if !sensor.unsupported {
+                    warn!(
+                        "Unsupported value for {}: {:?}",
+                        sensor.mapping.code, val.value
+                    );
+                    sensor.unsupported = true;
+                }
+                continue;
+            };
+            sensor.publish(client, &self.hass, value).await?;
+        }
+        Ok(())
+    }
+
+    /// Publish the power derived from the energy counters if the
+    /// telegram does not contain the power.
+    async fn derive_power(
+        &mut self,
+        client: &AsyncClient,
+        telegram: &Telegram,
+    ) -> Result<(), rumqttc::ClientError> {
+        let find = |code: ObisCode| {
+            telegram.readings.iter().find_map(|r| match r.value {
+                Value::Number(v) if code.matches(&r.obj_name) => Some(v),
+                _ => None,
+            })
+        };
+        if find(POWER).is_some() {
+            return Ok(());
+        }
+        let Some(import) = find(ENERGY_IMPORT) else {
+            return Ok(());
+        };
+        let export = find(ENERGY_EXPORT).unwrap_or(0.0);
+        let time = match telegram.sec_index {
+            Some(secs) => Timestamp::SecIndex(secs),
+            None => Timestamp::Received(Instant::now()),
+        };
+        match self.power.update(time, import, export) {
+            Some(power) => self.power_sensor.publish(client, &self.hass, power).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::MqttOptions;
+
+    use super::*;
+
+    fn meters() -> Meters {
+        let meter: MeterConfig = toml::from_str(
+            r#"
+            [home-assistant]
+            discovery_prefix = "homeassistant"
+            object_id = "grid"
+            name = "Netz"
+            "#,
+        )
+        .unwrap();
+        Meters::new(vec![meter])
+    }
+
+    fn reading(obj_name: [u8; 6], value: Value) -> Reading {
+        Reading { obj_name, value }
+    }
+
+    fn sensor<'a>(meters: &'a Meters, id: &str) -> &'a MappedSensor {
+        meters.meters[0]
+            .sensors
+            .iter()
+            .find(|sensor| sensor.mapping.id == id)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn invalid_values_are_skipped() {
+        // The event loop is not polled, the requests stay queued
+        let (client, _eventloop) =
+            AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 1000);
+        let mut meters = meters();
+        meters.process(&client, &Telegram::default()).await.unwrap();
+        let telegram = Telegram {
+            sec_index: Some(10),
+            readings: vec![
+                reading([1, 0, 1, 8, 0, 255], Value::Bytes(b"invalid".to_vec())),
+                reading([1, 0, 16, 7, 0, 255], Value::Bool(true)),
+                reading([1, 0, 2, 8, 0, 255], Value::Number(100.0)),
+            ],
+            ..Telegram::default()
+        };
+        meters.process(&client, &telegram).await.unwrap();
+
+        for id in ["wh", "w"] {
+            assert!(sensor(&meters, id).unsupported, "{id}");
+            assert!(!sensor(&meters, id).announced, "{id}");
+        }
+        let export = sensor(&meters, "wh_return");
+        assert!(export.announced);
+        assert!(!export.unsupported);
+        // Without a valid import counter there is no derived power
+        assert!(!meters.meters[0].power_sensor.announced);
+
+        // A valid value is published after an invalid one
+        let telegram = Telegram {
+            sec_index: Some(20),
+            readings: vec![reading([1, 0, 1, 8, 0, 255], Value::Number(200.0))],
+            ..Telegram::default()
+        };
+        meters.process(&client, &telegram).await.unwrap();
+        assert!(sensor(&meters, "wh").announced);
+    }
+}
@@ -0,0 +1,216 @@
+//! Mapping of OBIS codes to Home Assistant entities.
+//!
+//! OBIS codes (IEC 62056-61) are written as `A-B:C.D.E*F`. The groups
+//! `A-B:` and `*F` are optional and match any value when omitted, e.g.
+//! `1.8.0` matches `1-0:1.8.0*255` as well as `1-1:1.8.0*1`.
+
+use std::{fmt, str::FromStr};
+
+use nrg_hass::{
+    models::{device_class::DeviceClass, state_class::StateClass, unit::UnitOfMeasurement},
+    policy::PublishPolicy,
+};
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::telegram::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ObisCode {
+    pub medium: Option<u8>,
+    pub channel: Option<u8>,
+    pub quantity: u8,
+    pub processing: u8,
+    pub tariff: u8,
+    pub storage: Option<u8>,
+}
+
+impl ObisCode {
+    /// Check if the six groups of an object name match this code
+    pub fn matches(&self, obj_name: &[u8]) -> bool {
+        let &[a, b, c, d, e, f] = obj_name else {
+            return false;
+        };
+        self.medium.is_none_or(|medium| medium == a)
+            && self.channel.is_none_or(|channel| channel == b)
+            && (self.quantity, self.processing, self.tariff) == (c, d, e)
+            && self.storage.is_none_or(|storage| storage == f)
+    }
+}
+
+impl From<[u8; 6]> for ObisCode {
+    fn from([a, b, c, d, e, f]: [u8; 6]) -> Self {
+        Self {
+            medium: Some(a),
+            channel: Some(b),
+            quantity: c,
+            processing: d,
+            tariff: e,
+            storage: Some(f),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid OBIS code: {0}")]
+pub struct ParseObisError(String);
+
+impl FromStr for ObisCode {
+    type Err = ParseObisError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseObisError(s.to_owned());
+        let num = |s: &str| s.trim().parse::<u8>().map_err(|_| err());
+        let (ab, rest) = match s.split_once(':') {
+            Some((ab, rest)) => (Some(ab), rest),
+            None => (None, s),
+        };
+        let (cde, f) = match rest.split_once('*') {
+            Some((cde, f)) => (cde, Some(num(f)?)),
+            None => (rest, None),
+        };
+        let (a, b) = match ab {
+            Some(ab) => {
+                let (a, b) = ab.split_once('-').ok_or_else(err)?;
+                (Some(num(a)?), Some(num(b)?))
+            }
+            None => (None, None),
+        };
+        let mut cde = cde.split('.');
+        let (Some(c), Some(d), Some(e), None) = (cde.next(), cde.next(), cde.next(), cde.next())
+        else {
+            return Err(err());
+        };
+        Ok(Self {
+            medium: a,
+            channel: b,
+            quantity: num(c)?,
+            processing: num(d)?,
+            tariff: num(e)?,
+            storage: f,
+        })
+    }
+}
+
+impl fmt::Display for ObisCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(a), Some(b)) = (self.medium, self.channel) {
+            write!(f, "{}-{}:", a, b)?;
+        }
+        write!(f, "{}.{}.{}", self.quantity, self.processing, self.tariff)?;
+        if let Some(storage) = self.storage {
+            write!(f, "*{}", storage)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for ObisCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A value of the meter which is exposed as a Home Assistant sensor
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObisMapping {
+    pub code: ObisCode,
+    /// Used as suffix of the object id and the state topic
+    pub id: String,
+    /// Appended to the name of the meter
+    pub name: String,
+    pub unit: Option<UnitOfMeasurement>,
+    pub device_class: Option<DeviceClass>,
+    pub state_class: Option<StateClass>,
+    pub icon: Option<String>,
+    /// Factor applied after the scaler sent by the meter, e.g. `0.001`
+    /// to publish Wh as kWh.
+    #[serde(default = "default_factor")]
+    pub factor: f64,
+    /// Overrides the policy of the meter
+    pub publish: Option<PublishPolicy>,
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+impl ObisMapping {
+    fn new(code: &str, id: &str, name: &str) -> Self {
+        Self {
+            code: code.parse().unwrap(),
+            id: id.to_owned(),
+            name: name.to_owned(),
+            unit: None,
+            device_class: None,
+            state_class: None,
+            icon: None,
+            factor: 1.0,
+            publish: None,
+        }
+    }
+    fn energy(mut self, icon: &str) -> Self {
+        self.unit = Some(UnitOfMeasurement::WattHours);
+        self.device_class = Some(DeviceClass::Energy);
+        self.state_class = Some(StateClass::TotalIncreasing);
+        self.icon = Some(icon.to_owned());
+        self
+    }
+    fn measurement(mut self, unit: UnitOfMeasurement, device_class: DeviceClass) -> Self {
+        self.unit = Some(unit);
+        self.device_class = Some(device_class);
+        self.state_class = Some(StateClass::Measurement);
+        self
+    }
+    fn icon(mut self, icon: &str) -> Self {
+        self.icon = Some(icon.to_owned());
+        self
+    }
+    /// Apply the configured factor. Returns `None` for values which
+    /// are not numeric.
+    pub fn convert(&self, value: &Value) -> Option<f64> {
+        match *value {
+            Value::Number(v) => Some(v * self.factor),
+            Value::Bool(_) | Value::Bytes(_) => None,
+        }
+    }
+}
+
+/// Entity for the power derived from the energy counters of meters
+/// which do not send 16.7.0
+pub fn derived_power_mapping() -> ObisMapping {
+    ObisMapping::new("16.7.0", "w_derived", "Leistung (berechnet)")
+        .measurement(UnitOfMeasurement::Watt, DeviceClass::Power)
+        .icon("mdi:calculator-variant-outline")
+}
+
+/// Values reported by most electricity meters. Used unless the config
+/// contains `[[obis]]` tables.
+pub fn default_mappings() -> Vec<ObisMapping> {
+    use DeviceClass::{Current, Frequency, Power, Voltage};
+    use UnitOfMeasurement::{Ampere, Hertz, Volt, Watt};
+    vec![
+        ObisMapping::new("1.8.0", "wh", "Verbrauch").energy("mdi:transmission-tower-import"),
+        ObisMapping::new("1.8.1", "wh_t1", "Verbrauch Tarif 1")
+            .energy("mdi:transmission-tower-import"),
+        ObisMapping::new("1.8.2", "wh_t2", "Verbrauch Tarif 2")
+            .energy("mdi:transmission-tower-import"),
+        ObisMapping::new("2.8.0", "wh_return", "Einspeisung")
+            .energy("mdi:transmission-tower-export"),
+        ObisMapping::new("16.7.0", "w", "Leistung")
+            .measurement(Watt, Power)
+            .icon("mdi:home-lightning-bolt-outline"),
+        ObisMapping::new("36.7.0", "w_l1", "Leistung L1").measurement(Watt, Power),
+        ObisMapping::new("56.7.0", "w_l2", "Leistung L2").measurement(Watt, Power),
+        ObisMapping::new("76.7.0", "w_l3", "Leistung L3").measurement(Watt, Power),
+        ObisMapping::new("32.7.0", "v_l1", "Spannung L1").measurement(Volt, Voltage),
+        ObisMapping::new("52.7.0", "v_l2", "Spannung L2").measurement(Volt, Voltage),
+        ObisMapping::new("72.7.0", "v_l3", "Spannung L3").measurement(Volt, Voltage),
+        ObisMapping::new("31.7.0", "a_l1", "Strom L1").measurement(Ampere, Current),
+        ObisMapping::new("51.7.0", "a_l2", "Strom L2").measurement(Ampere, Current),
+        ObisMapping::new("71.7.0", "a_l3", "Strom L3").measurement(Ampere, Current),
+        ObisMapping::new("14.7.0", "hz", "Frequenz").measurement(Hertz, Frequency),
+    ]
+}
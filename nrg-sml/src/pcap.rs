@@ -0,0 +1,147 @@
+//! Minimal reader for pcap files containing the SML stream of network
+//! IR readers, e.g. recorded with `tcpdump -w sml.pcap port 8888`.
+//!
+//! Only the classic pcap format is supported (not pcapng). The TCP and
+//! UDP payloads of all IPv4 and IPv6 packets are extracted in the order
+//! of the capture. Retransmissions are not filtered.
+
+use std::{io, time::Duration};
+
+/// Payload of a captured packet
+pub struct Packet {
+    /// Capture time relative to the first packet
+    pub offset: Duration,
+    pub payload: Vec<u8>,
+}
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const IP_PROTO_TCP: u8 = 6;
+const IP_PROTO_UDP: u8 = 17;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid pcap file: {}", msg),
+    )
+}
+
+/// Extract the payloads of all packets. If `port` is given only packets
+/// sent from that port are used.
+pub fn read(data: &[u8], port: Option<u16>) -> io::Result<Vec<Packet>> {
+    let header = data.get(..24).ok_or_else(|| invalid("missing header"))?;
+    let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let (big_endian, nanos) = match magic {
+        0xa1b2c3d4 => (false, false),
+        0xd4c3b2a1 => (true, false),
+        0xa1b23c4d => (false, true),
+        0x4d3cb2a1 => (true, true),
+        _ => return Err(invalid("unknown magic number")),
+    };
+    let u32_at = |bytes: &[u8], pos: usize| -> u32 {
+        let bytes: [u8; 4] = bytes[pos..pos + 4].try_into().unwrap();
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let linktype = u32_at(header, 20);
+
+    let mut packets = Vec::new();
+    let mut start = None;
+    let mut rest = &data[24..];
+    while !rest.is_empty() {
+        let record = rest.get(..16).ok_or_else(|| invalid("truncated record"))?;
+        let len = u32_at(record, 8) as usize;
+        let frame = rest
+            .get(16..16 + len)
+            .ok_or_else(|| invalid("truncated packet"))?;
+        rest = &rest[16 + len..];
+
+        let subsec = u32_at(record, 4);
+        let time = Duration::new(
+            u32_at(record, 0).into(),
+            if nanos {
+                subsec
+            } else {
+                subsec.saturating_mul(1000)
+            }
+            .min(999_999_999),
+        );
+        let start = *start.get_or_insert(time);
+        let Some((src_port, payload)) = payload(linktype, frame) else {
+            continue;
+        };
+        if payload.is_empty() || port.is_some_and(|port| port != src_port) {
+            continue;
+        }
+        packets.push(Packet {
+            offset: time.saturating_sub(start),
+            payload: payload.to_vec(),
+        });
+    }
+    Ok(packets)
+}
+
+/// Source port and payload of a TCP or UDP packet
+fn payload(linktype: u32, frame: &[u8]) -> Option<(u16, &[u8])> {
+    let (ethertype, ip) = match linktype {
+        LINKTYPE_NULL => {
+            // The address family is stored in host byte order. IPv4
+            // is 2 on all systems.
+            let family = u32::from_le_bytes(frame.get(..4)?.try_into().ok()?);
+            let ethertype = if family == 2 || family.swap_bytes() == 2 {
+                0x0800
+            } else {
+                0x86dd
+            };
+            (ethertype, frame.get(4..)?)
+        }
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+            let mut offset = 14;
+            // 802.1Q VLAN tag
+            if ethertype == 0x8100 {
+                ethertype = u16::from_be_bytes([*frame.get(16)?, *frame.get(17)?]);
+                offset = 18;
+            }
+            (ethertype, frame.get(offset..)?)
+        }
+        LINKTYPE_RAW => match frame.first()? >> 4 {
+            4 => (0x0800, frame),
+            6 => (0x86dd, frame),
+            _ => return None,
+        },
+        LINKTYPE_LINUX_SLL => (
+            u16::from_be_bytes([*frame.get(14)?, *frame.get(15)?]),
+            frame.get(16..)?,
+        ),
+        _ => return None,
+    };
+    let (proto, segment) = match ethertype {
+        0x0800 => {
+            let header_len = usize::from(ip.first()? & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]));
+            (*ip.get(9)?, ip.get(header_len..total_len.min(ip.len()))?)
+        }
+        // Extension headers are not supported
+        0x86dd => {
+            let payload_len = usize::from(u16::from_be_bytes([*ip.get(4)?, *ip.get(5)?]));
+            (*ip.get(6)?, ip.get(40..(40 + payload_len).min(ip.len()))?)
+        }
+        _ => return None,
+    };
+    let src_port = u16::from_be_bytes([*segment.first()?, *segment.get(1)?]);
+    match proto {
+        IP_PROTO_TCP => {
+            let header_len = usize::from(segment.get(12)? >> 4) * 4;
+            Some((src_port, segment.get(header_len..)?))
+        }
+        IP_PROTO_UDP => Some((src_port, segment.get(8..)?)),
+        _ => None,
+    }
+}
@@ -0,0 +1,285 @@
+//! Protocol independent representation of the values sent by a meter.
+//!
+//! SML and D0 telegrams are both converted to a [`Telegram`] so the
+//! assignment to meters and the Home Assistant entities do not depend
+//! on the protocol spoken by the meter.
+
+use sml_rs::{
+    parser::{common, complete, ParseError},
+    transport::{DecodeErr, Decoder},
+    util::ArrayBuf,
+};
+use thiserror::Error;
+use tracing::debug;
+
+use crate::{config::MeterId, obis::ObisCode};
+
+/// OBIS code of the server ID which some meters send in addition to
+/// the server ID of the SML response.
+const SERVER_ID: ObisCode = ObisCode {
+    medium: None,
+    channel: None,
+    quantity: 96,
+    processing: 1,
+    tariff: 0,
+    storage: None,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// Numeric value with the scaler of the meter applied
+    Number(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reading {
+    /// The six groups of the OBIS code, e.g. `[1, 0, 1, 8, 0, 255]`
+    pub obj_name: [u8; 6],
+    pub value: Value,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Telegram {
+    /// Server ID of the SML response or device address of a D0
+    /// telegram
+    pub server_id: Option<MeterId>,
+    /// Seconds index of the meter, usually the seconds since the meter
+    /// was installed
+    pub sec_index: Option<u32>,
+    pub readings: Vec<Reading>,
+}
+
+impl Telegram {
+    /// Extract the values of all `GetListResponse` messages. Each
+    /// response is returned as separate telegram as a single SML file
+    /// may contain the responses of several meters.
+    pub fn from_sml(file: &complete::File<'_>) -> Vec<Self> {
+        file.messages
+            .iter()
+            .filter_map(|m| match &m.message_body {
+                complete::MessageBody::GetListResponse(lst) => Some(Self {
+                    server_id: Some(MeterId(lst.server_id.to_vec())),
+                    sec_index: lst.act_sensor_time.as_ref().map(|time| match *time {
+                        common::Time::SecIndex(secs) => secs,
+                    }),
+                    readings: lst.val_list.iter().filter_map(Reading::from_sml).collect(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// IDs which identify the meter: the server ID and the value of
+    /// 96.1.0 if present.
+    pub fn ids(&self) -> Vec<MeterId> {
+        let obis_id = self.readings.iter().find_map(|r| match &r.value {
+            Value::Bytes(bytes) if SERVER_ID.matches(&r.obj_name) => Some(MeterId(bytes.clone())),
+            _ => None,
+        });
+        self.server_id.iter().cloned().chain(obis_id).collect()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SmlError {
+    #[error("Transport error: {0}")]
+    Transport(DecodeErr),
+    #[error("Parsing failed: {0:?}")]
+    Parse(ParseError),
+}
+
+/// Decoder for a stream of SML files, e.g. as received from an IR head
+pub struct SmlDecoder {
+    decoder: Decoder<ArrayBuf<2048>>,
+}
+
+impl Default for SmlDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SmlDecoder {
+    pub fn new() -> Self {
+        Self {
+            decoder: Decoder::new(),
+        }
+    }
+    /// Push the next byte of the stream. Returns the telegrams once a
+    /// complete SML file was received.
+    pub fn push_byte(&mut self, byte: u8) -> Result<Option<Vec<Telegram>>, SmlError> {
+        match self.decoder.push_byte(byte) {
+            Ok(None) => Ok(None),
+            Ok(Some(decoded)) => complete::parse(decoded)
+                .map(|file| Some(Telegram::from_sml(&file)))
+                .map_err(SmlError::Parse),
+            Err(e) => Err(SmlError::Transport(e)),
+        }
+    }
+}
+
+impl Reading {
+    fn from_sml(entry: &common::ListEntry<'_>) -> Option<Self> {
+        let Ok(obj_name) = entry.obj_name.try_into() else {
+            debug!("Ignoring invalid object name {:02x?}", entry.obj_name);
+            return None;
+        };
+        let number = match entry.value {
+            common::Value::I8(v) => v as f64,
+            common::Value::I16(v) => v as f64,
+            common::Value::I32(v) => v as f64,
+            common::Value::I64(v) => v as f64,
+            common::Value::U8(v) => v as f64,
+            common::Value::U16(v) => v as f64,
+            common::Value::U32(v) => v as f64,
+            common::Value::U64(v) => v as f64,
+            common::Value::Bool(v) => return Some(Self::new(obj_name, Value::Bool(v))),
+            common::Value::Bytes(v) => {
+                return Some(Self::new(obj_name, Value::Bytes(v.to_vec())));
+            }
+            common::Value::List(_) => {
+                debug!("Ignoring list value of {:02x?}", entry.obj_name);
+                return None;
+            }
+        };
+        // Dividing avoids results like 0.30000000000000004
+        let number = match entry.scaler.unwrap_or(0) {
+            scaler @ 0.. => number * 10f64.powi(scaler.into()),
+            scaler => number / 10f64.powi(-i32::from(scaler)),
+        };
+        Some(Self::new(obj_name, Value::Number(number)))
+    }
+
+    fn new(obj_name: [u8; 6], value: Value) -> Self {
+        Self { obj_name, value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(data: &[u8]) -> Vec<Telegram> {
+        let mut decoder = SmlDecoder::new();
+        let mut telegrams = Vec::new();
+        for &byte in data {
+            if let Some(decoded) = decoder.push_byte(byte).unwrap() {
+                telegrams.extend(decoded);
+            }
+        }
+        telegrams
+    }
+
+    fn number(telegram: &Telegram, obj_name: [u8; 6]) -> Option<f64> {
+        telegram.readings.iter().find_map(|r| match r.value {
+            Value::Number(v) if r.obj_name == obj_name => Some(v),
+            _ => None,
+        })
+    }
+
+    fn bytes(telegram: &Telegram, obj_name: [u8; 6]) -> Option<&[u8]> {
+        telegram.readings.iter().find_map(|r| match &r.value {
+            Value::Bytes(v) if r.obj_name == obj_name => Some(&v[..]),
+            _ => None,
+        })
+    }
+
+    const IMPORT: [u8; 6] = [1, 0, 1, 8, 0, 255];
+    const EXPORT: [u8; 6] = [1, 0, 2, 8, 0, 255];
+    const POWER: [u8; 6] = [1, 0, 16, 7, 0, 255];
+
+    #[test]
+    fn emh_ehz() {
+        let telegrams = decode(include_bytes!("../telegrams/emh-ehz.sml"));
+        assert_eq!(telegrams.len(), 3);
+        let id = MeterId(vec![0x0a, 0x01, 0x45, 0x4d, 0x48, 0, 0, 0x7f, 0x12, 0x34]);
+        for (telegram, (import, power)) in
+            telegrams
+                .iter()
+                .zip([(9876543.2, 720.0), (9876543.4, 720.3), (9876543.6, 720.6)])
+        {
+            assert_eq!(telegram.server_id.as_ref(), Some(&id));
+            assert_eq!(number(telegram, IMPORT), Some(import));
+            assert_eq!(number(telegram, [1, 0, 1, 8, 1, 255]), Some(import));
+            assert_eq!(number(telegram, EXPORT), Some(123450.0));
+            assert_eq!(number(telegram, POWER), Some(power));
+        }
+        assert_eq!(
+            bytes(&telegrams[0], [129, 129, 199, 130, 3, 255]),
+            Some(&b"EMH"[..])
+        );
+    }
+
+    #[test]
+    fn iskra_mt175() {
+        let telegrams = decode(include_bytes!("../telegrams/iskra-mt175.sml"));
+        assert_eq!(telegrams.len(), 3);
+        let telegram = &telegrams[0];
+        assert_eq!(
+            telegram.server_id,
+            Some(MeterId(vec![
+                0x0a, 0x01, 0x49, 0x53, 0x4b, 0, 0x04, 0x5e, 0x6a, 0x2b
+            ]))
+        );
+        assert_eq!(number(telegram, IMPORT), Some(12345678.9));
+        assert_eq!(number(telegram, POWER), Some(1800.0));
+        for (phase, power, voltage, current) in [
+            (0, 600.0, 230.1, 2.61),
+            (1, 700.0, 229.8, 3.04),
+            (2, 500.0, 231.0, 2.17),
+        ] {
+            let offset = 20 * phase;
+            assert_eq!(
+                number(telegram, [1, 0, 36 + offset, 7, 0, 255]),
+                Some(power)
+            );
+            assert_eq!(
+                number(telegram, [1, 0, 32 + offset, 7, 0, 255]),
+                Some(voltage)
+            );
+            assert_eq!(
+                number(telegram, [1, 0, 31 + offset, 7, 0, 255]),
+                Some(current)
+            );
+        }
+        assert_eq!(number(telegram, [1, 0, 14, 7, 0, 255]), Some(50.0));
+        assert_eq!(number(&telegrams[1], IMPORT), Some(12345679.4));
+        assert_eq!(number(&telegrams[1], POWER), Some(1801.0));
+    }
+
+    #[test]
+    fn easymeter_q3a() {
+        let telegrams = decode(include_bytes!("../telegrams/easymeter-q3a.sml"));
+        assert_eq!(telegrams.len(), 3);
+        let telegram = &telegrams[0];
+        assert_eq!(
+            bytes(telegram, [1, 0, 0, 0, 0, 255]),
+            Some(&b"1ESY1103123456"[..])
+        );
+        assert_eq!(number(telegram, IMPORT), Some(4567890.1));
+        assert_eq!(number(telegram, EXPORT), Some(0.0));
+        // Feed-in is reported as negative power
+        assert_eq!(number(telegram, POWER), Some(-350.12));
+        assert_eq!(number(telegram, [1, 0, 36, 7, 0, 255]), Some(-120.0));
+        assert_eq!(number(&telegrams[2], POWER), Some(-350.46));
+    }
+
+    #[test]
+    fn dzg_dws7410() {
+        let telegrams = decode(include_bytes!("../telegrams/dzg-dws7410.sml"));
+        assert_eq!(telegrams.len(), 10);
+        let id = MeterId(vec![
+            0x0a, 0x01, 0x44, 0x5a, 0x47, 0, 0x02, 0x8e, 0x1a, 0x5b,
+        ]);
+        // The power is not sent in reduced data mode
+        for (i, telegram) in telegrams.iter().enumerate() {
+            assert_eq!(telegram.ids(), vec![id.clone(), id.clone()]);
+            assert_eq!(number(telegram, POWER), None);
+            let import = number(telegram, IMPORT).unwrap();
+            assert!((import - (51234567.0 + 0.2 * i as f64)).abs() < 0.01);
+            assert_eq!(number(telegram, EXPORT), Some(100.0));
+        }
+    }
+}
@@ -11,6 +11,9 @@ pub struct Config {
     pub mqtt: MqttConfig,
     #[serde(rename = "home-assistant")]
     pub hass: HomeAssistantConfig,
+    /// Energy meters attached to the inverter
+    #[serde(default, rename = "meter")]
+    pub meters: Vec<MeterConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,4 +22,34 @@ pub struct ModbusConfig {
     pub slave: u8,
     pub retry_delay: Duration,
     pub poll_delay: Duration,
+    /// Maximum time to wait for a response. Connecting including the
+    /// discovery of the models has to complete within this time as
+    /// well.
+    #[serde(default = "nrg_modbus::connection::default_timeout")]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MeterConfig {
+    /// Start of the device block of the meter, e.g. 40121 for the first
+    /// meter of SolarEdge inverters. Without an address the meter model
+    /// discovered together with the inverter is used.
+    pub addr: Option<u16>,
+    pub object_id: String,
+    pub name: String,
+    /// Negate the power. Positive values are expected to be consumption
+    /// from the grid.
+    #[serde(default)]
+    pub invert_power: bool,
+}
+
+impl MeterConfig {
+    /// Home Assistant config of the meter entities
+    pub fn hass(&self, hass: &HomeAssistantConfig) -> HomeAssistantConfig {
+        HomeAssistantConfig {
+            discovery_prefix: hass.discovery_prefix.clone(),
+            object_id: self.object_id.clone(),
+            name: self.name.clone(),
+        }
+    }
 }
@@ -0,0 +1,540 @@
+use std::sync::Arc;
+
+use nrg_hass::{
+    config::HomeAssistantConfig,
+    discovery::announce,
+    models::{
+        binary_sensor::{BinarySensor, BinarySensorBuilder, BinarySensorDeviceClass},
+        device::Device,
+        device_class::DeviceClass,
+        entity_category::EntityCategory,
+        sensor::{Sensor, SensorBuilder},
+        state_class::StateClass,
+        unit::UnitOfMeasurement,
+    },
+    state::publish_state,
+};
+use rumqttc::{AsyncClient, ClientError};
+use sunspec::models::model1::Model1;
+
+use crate::{
+    inverter::{Inverter, Mppt, OperatingState},
+    meter::{Meter, MeterAddr},
+};
+
+/// Identifier of the device described by a common model
+fn device_id(m1: &Model1) -> String {
+    format!("sunspec_{}", m1.sn)
+}
+
+/// Builds the entities of one device. Topics are
+/// `{topic_prefix}/{object_id}/{id}`.
+pub struct Entities<'a> {
+    cfg: &'a HomeAssistantConfig,
+    topic_prefix: &'a str,
+    device: Arc<Device>,
+    availability_topic: String,
+}
+
+impl<'a> Entities<'a> {
+    /// Device information read from the common model
+    pub fn new(cfg: &'a HomeAssistantConfig, topic_prefix: &'a str, m1: &Model1) -> Self {
+        let mut device = Device::builder();
+        device
+            .identifiers(vec![device_id(m1)])
+            .manufacturer(&m1.mn)
+            .model(&m1.md)
+            .name(&cfg.name)
+            .serial_number(&m1.sn);
+        if let Some(version) = &m1.vr {
+            device.sw_version(version);
+        }
+        Self::with_device(cfg, topic_prefix, device.build().unwrap())
+    }
+
+    /// Device of a meter without a common model of its own. It is
+    /// identified by the address of its meter model and connected via
+    /// the inverter.
+    pub fn meter(
+        cfg: &'a HomeAssistantConfig,
+        topic_prefix: &'a str,
+        inverter: &Model1,
+        addr: &MeterAddr,
+    ) -> Self {
+        let device = Device::builder()
+            .identifiers(vec![format!("{}_meter{}", device_id(inverter), addr.addr)])
+            .manufacturer(&inverter.mn)
+            .model(format!("SunSpec Model {}", addr.model.id()))
+            .name(&cfg.name)
+            .via_device(device_id(inverter))
+            .build()
+            .unwrap();
+        Self::with_device(cfg, topic_prefix, device)
+    }
+
+    fn with_device(cfg: &'a HomeAssistantConfig, topic_prefix: &'a str, device: Device) -> Self {
+        let topic_prefix = topic_prefix.trim_end_matches('/');
+        Self {
+            cfg,
+            topic_prefix,
+            device: Arc::new(device),
+            availability_topic: format!("{}/{}/availability", topic_prefix, cfg.object_id),
+        }
+    }
+
+    pub fn availability_topic(&self) -> &str {
+        &self.availability_topic
+    }
+
+    fn state_topic(&self, id: &str) -> String {
+        format!("{}/{}/{}", self.topic_prefix, self.cfg.object_id, id)
+    }
+
+    /// Sensor with the given id appended to the object id and the name
+    /// of the device
+    pub fn text_sensor(&self, id: &str, name: &str) -> SensorBuilder {
+        let mut builder = Sensor::builder();
+        builder
+            .device(self.device.clone())
+            .availability_topic(&self.availability_topic)
+            .name(format!("{} {}", self.cfg.name, name))
+            .object_id(format!("{}_{}", self.cfg.object_id, id))
+            .state_topic(self.state_topic(id))
+            .unique_id(format!("{}_{}", self.cfg.object_id, id));
+        builder
+    }
+
+    /// Measurement sensor, see [`Self::text_sensor`]
+    pub fn sensor(
+        &self,
+        id: &str,
+        name: &str,
+        unit: UnitOfMeasurement,
+        device_class: DeviceClass,
+    ) -> SensorBuilder {
+        let mut builder = self.text_sensor(id, name);
+        builder
+            .unit_of_measurement(unit)
+            .device_class(device_class)
+            .state_class(StateClass::Measurement);
+        builder
+    }
+
+    /// Binary sensor reading the state topic of the entity `state_id`
+    pub fn binary_sensor(&self, id: &str, name: &str, state_id: &str) -> BinarySensorBuilder {
+        let mut builder = BinarySensor::builder();
+        builder
+            .device(self.device.clone())
+            .availability_topic(&self.availability_topic)
+            .name(format!("{} {}", self.cfg.name, name))
+            .object_id(format!("{}_{}", self.cfg.object_id, id))
+            .state_topic(self.state_topic(state_id))
+            .unique_id(format!("{}_{}", self.cfg.object_id, id));
+        builder
+    }
+
+    /// Measurement sensor which is only created if the device
+    /// implements the point
+    fn optional(
+        &self,
+        implemented: bool,
+        id: &str,
+        name: &str,
+        unit: UnitOfMeasurement,
+        device_class: DeviceClass,
+    ) -> Option<Sensor> {
+        implemented.then(|| self.sensor(id, name, unit, device_class).build().unwrap())
+    }
+
+    /// Operating state as enum sensor
+    fn operating_state(&self, id: &str, name: &str) -> Sensor {
+        self.text_sensor(id, name)
+            .device_class(DeviceClass::Enum)
+            .options(
+                OperatingState::OPTIONS
+                    .iter()
+                    .map(|state| state.as_ref().to_owned())
+                    .collect::<Vec<_>>(),
+            )
+            .value_template("{{ value_json }}")
+            .icon("mdi:state-machine")
+            .build()
+            .unwrap()
+    }
+}
+
+/// Home Assistant entities of the inverter. Entities of values which
+/// are not implemented by the inverter are omitted.
+pub struct Hass {
+    pub availability_topic: String,
+    pub w: Sensor,
+    pub wh: Sensor,
+    pub hz: Option<Sensor>,
+    pub current_phases: [Option<Sensor>; 3],
+    pub voltage_phases: [Option<Sensor>; 3],
+    pub dc_a: Option<Sensor>,
+    pub dc_v: Option<Sensor>,
+    pub dc_w: Option<Sensor>,
+    pub temperature: Option<Sensor>,
+    pub state: Sensor,
+    /// Active events as JSON object, see [`crate::inverter::Events`]
+    pub events: Sensor,
+    /// On if any event is active
+    pub problem: BinarySensor,
+    pub mppt: Vec<MpptEntities>,
+}
+
+/// Entities of one MPPT input
+pub struct MpptEntities {
+    pub dc_a: Option<Sensor>,
+    pub dc_v: Option<Sensor>,
+    pub dc_w: Option<Sensor>,
+    pub dc_wh: Option<Sensor>,
+    pub temperature: Option<Sensor>,
+    pub state: Option<Sensor>,
+}
+
+impl MpptEntities {
+    /// `n` is the number of the input starting at 1
+    fn new(entities: &Entities, n: usize, mppt: &Mppt) -> Self {
+        let label = match &mppt.label {
+            Some(label) => label.clone(),
+            None => format!("MPPT {n}"),
+        };
+        let id = |suffix| format!("mppt{n}_{suffix}");
+        let name = |suffix| format!("{label} {suffix}");
+        Self {
+            dc_a: entities.optional(
+                mppt.dc_a.is_some(),
+                &id("dc_a"),
+                &name("Strom"),
+                UnitOfMeasurement::Ampere,
+                DeviceClass::Current,
+            ),
+            dc_v: entities.optional(
+                mppt.dc_v.is_some(),
+                &id("dc_v"),
+                &name("Spannung"),
+                UnitOfMeasurement::Volt,
+                DeviceClass::Voltage,
+            ),
+            dc_w: entities.optional(
+                mppt.dc_w.is_some(),
+                &id("dc_w"),
+                &name("Leistung"),
+                UnitOfMeasurement::Watt,
+                DeviceClass::Power,
+            ),
+            dc_wh: mppt.dc_wh.map(|_| {
+                entities
+                    .sensor(
+                        &id("dc_wh"),
+                        &name("Energie"),
+                        UnitOfMeasurement::WattHours,
+                        DeviceClass::Energy,
+                    )
+                    .state_class(StateClass::TotalIncreasing)
+                    .build()
+                    .unwrap()
+            }),
+            temperature: entities.optional(
+                mppt.temperature.is_some(),
+                &id("temperature"),
+                &name("Temperatur"),
+                UnitOfMeasurement::TempCelsius,
+                DeviceClass::Temperature,
+            ),
+            state: mppt
+                .state
+                .map(|_| entities.operating_state(&id("state"), &name("Betriebszustand"))),
+        }
+    }
+
+    fn sensors(&self) -> impl Iterator<Item = &Sensor> {
+        [
+            &self.dc_a,
+            &self.dc_v,
+            &self.dc_w,
+            &self.dc_wh,
+            &self.temperature,
+            &self.state,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    async fn publish(&self, client: &AsyncClient, mppt: &Mppt) -> Result<(), ClientError> {
+        publish_value(client, self.dc_a.as_ref(), mppt.dc_a).await?;
+        publish_value(client, self.dc_v.as_ref(), mppt.dc_v).await?;
+        publish_value(client, self.dc_w.as_ref(), mppt.dc_w).await?;
+        publish_value(client, self.dc_wh.as_ref(), mppt.dc_wh).await?;
+        publish_value(client, self.temperature.as_ref(), mppt.temperature).await?;
+        if let (Some(sensor), Some(state)) = (&self.state, mppt.state) {
+            publish_state(client, sensor, state.as_ref()).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Hass {
+    /// The points of the inverter model and the first readings of the
+    /// MPPT inputs decide which entities are created.
+    pub fn new(entities: &Entities, inverter: &Inverter, mppt: &[Mppt]) -> Self {
+        let phases = ["L1", "L2", "L3"];
+        Self {
+            availability_topic: entities.availability_topic().to_owned(),
+            w: entities
+                .sensor("w", "Leistung", UnitOfMeasurement::Watt, DeviceClass::Power)
+                .icon("mdi:solar-power-variant")
+                .build()
+                .unwrap(),
+            wh: entities
+                .sensor(
+                    "wh",
+                    "Energie",
+                    UnitOfMeasurement::WattHours,
+                    DeviceClass::Energy,
+                )
+                .state_class(StateClass::TotalIncreasing)
+                .icon("mdi:solar-power-variant-outline")
+                .build()
+                .unwrap(),
+            hz: entities.optional(
+                inverter.points.hz,
+                "hz",
+                "Frequenz",
+                UnitOfMeasurement::Hertz,
+                DeviceClass::Frequency,
+            ),
+            current_phases: std::array::from_fn(|i| {
+                entities.optional(
+                    inverter.points.current_phases[i],
+                    &format!("a_{}", phases[i].to_lowercase()),
+                    &format!("Strom {}", phases[i]),
+                    UnitOfMeasurement::Ampere,
+                    DeviceClass::Current,
+                )
+            }),
+            voltage_phases: std::array::from_fn(|i| {
+                entities.optional(
+                    inverter.points.voltage_phases[i],
+                    &format!("v_{}", phases[i].to_lowercase()),
+                    &format!("Spannung {}", phases[i]),
+                    UnitOfMeasurement::Volt,
+                    DeviceClass::Voltage,
+                )
+            }),
+            dc_a: entities.optional(
+                inverter.points.dc_a,
+                "dc_a",
+                "DC-Strom",
+                UnitOfMeasurement::Ampere,
+                DeviceClass::Current,
+            ),
+            dc_v: entities.optional(
+                inverter.points.dc_v,
+                "dc_v",
+                "DC-Spannung",
+                UnitOfMeasurement::Volt,
+                DeviceClass::Voltage,
+            ),
+            dc_w: entities.optional(
+                inverter.points.dc_w,
+                "dc_w",
+                "DC-Leistung",
+                UnitOfMeasurement::Watt,
+                DeviceClass::Power,
+            ),
+            temperature: entities.optional(
+                inverter.points.temperature,
+                "temperature",
+                "Temperatur",
+                UnitOfMeasurement::TempCelsius,
+                DeviceClass::Temperature,
+            ),
+            state: entities.operating_state("state", "Betriebszustand"),
+            events: entities
+                .text_sensor("events", "Ereignisse")
+                .json_attributes_topic(entities.state_topic("events"))
+                .value_template(
+                    "{{ value_json.active | join(', ') if value_json.active else 'keine' }}",
+                )
+                .entity_category(EntityCategory::Diagnostic)
+                .icon("mdi:alert-circle-outline")
+                .build()
+                .unwrap(),
+            problem: entities
+                .binary_sensor("problem", "Störung", "events")
+                .value_template("{{ 'ON' if value_json.active else 'OFF' }}")
+                .device_class(BinarySensorDeviceClass::Problem)
+                .build()
+                .unwrap(),
+            mppt: mppt
+                .iter()
+                .enumerate()
+                .map(|(i, mppt)| MpptEntities::new(entities, i + 1, mppt))
+                .collect(),
+        }
+    }
+
+    fn sensors(&self) -> impl Iterator<Item = &Sensor> {
+        [&self.w, &self.wh, &self.state, &self.events]
+            .into_iter()
+            .chain(
+                [
+                    &self.hz,
+                    &self.dc_a,
+                    &self.dc_v,
+                    &self.dc_w,
+                    &self.temperature,
+                ]
+                .into_iter()
+                .chain(&self.current_phases)
+                .chain(&self.voltage_phases)
+                .flatten(),
+            )
+            .chain(self.mppt.iter().flat_map(MpptEntities::sensors))
+    }
+
+    pub async fn announce(
+        &self,
+        client: &AsyncClient,
+        cfg: &HomeAssistantConfig,
+    ) -> Result<(), ClientError> {
+        for sensor in self.sensors() {
+            announce(client, cfg, &cfg.object_id, sensor).await?;
+        }
+        announce(client, cfg, &cfg.object_id, &self.problem).await?;
+        Ok(())
+    }
+
+    pub async fn publish(
+        &self,
+        client: &AsyncClient,
+        inverter: &Inverter,
+    ) -> Result<(), ClientError> {
+        publish_value(client, Some(&self.w), inverter.w).await?;
+        publish_value(client, Some(&self.wh), inverter.wh).await?;
+        publish_value(client, self.hz.as_ref(), inverter.hz).await?;
+        for (sensor, value) in self.current_phases.iter().zip(inverter.current_phases) {
+            publish_value(client, sensor.as_ref(), value).await?;
+        }
+        for (sensor, value) in self.voltage_phases.iter().zip(inverter.voltage_phases) {
+            publish_value(client, sensor.as_ref(), value).await?;
+        }
+        publish_value(client, self.dc_a.as_ref(), inverter.dc_a).await?;
+        publish_value(client, self.dc_v.as_ref(), inverter.dc_v).await?;
+        publish_value(client, self.dc_w.as_ref(), inverter.dc_w).await?;
+        publish_value(client, self.temperature.as_ref(), inverter.temperature).await?;
+        if let Some(state) = inverter.state {
+            publish_state(client, &self.state, state.as_ref()).await?;
+        }
+        publish_state(client, &self.events, &inverter.events).await?;
+        Ok(())
+    }
+
+    /// Publish the MPPT inputs in the order they were announced
+    pub async fn publish_mppt(
+        &self,
+        client: &AsyncClient,
+        mppt: &[Mppt],
+    ) -> Result<(), ClientError> {
+        for (entities, mppt) in self.mppt.iter().zip(mppt) {
+            entities.publish(client, mppt).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Home Assistant entities of a meter. Named like the entities of
+/// nrg-sml so either can provide the grid values.
+pub struct MeterHass {
+    pub availability_topic: String,
+    pub w: Option<Sensor>,
+    pub power_phases: [Option<Sensor>; 3],
+    pub wh_import: Option<Sensor>,
+    pub wh_export: Option<Sensor>,
+}
+
+impl MeterHass {
+    pub fn new(entities: &Entities, meter: &Meter) -> Self {
+        let energy = |value: Option<f64>, id, name, icon| {
+            value.map(|_| {
+                entities
+                    .sensor(id, name, UnitOfMeasurement::WattHours, DeviceClass::Energy)
+                    .state_class(StateClass::TotalIncreasing)
+                    .icon(icon)
+                    .build()
+                    .unwrap()
+            })
+        };
+        Self {
+            availability_topic: entities.availability_topic().to_owned(),
+            w: meter.w.map(|_| {
+                entities
+                    .sensor("w", "Leistung", UnitOfMeasurement::Watt, DeviceClass::Power)
+                    .icon("mdi:home-lightning-bolt-outline")
+                    .build()
+                    .unwrap()
+            }),
+            power_phases: std::array::from_fn(|i| {
+                entities.optional(
+                    meter.power_phases[i].is_some(),
+                    &format!("w_l{}", i + 1),
+                    &format!("Leistung L{}", i + 1),
+                    UnitOfMeasurement::Watt,
+                    DeviceClass::Power,
+                )
+            }),
+            wh_import: energy(
+                meter.wh_import,
+                "wh",
+                "Verbrauch",
+                "mdi:transmission-tower-import",
+            ),
+            wh_export: energy(
+                meter.wh_export,
+                "wh_return",
+                "Einspeisung",
+                "mdi:transmission-tower-export",
+            ),
+        }
+    }
+
+    pub async fn announce(
+        &self,
+        client: &AsyncClient,
+        cfg: &HomeAssistantConfig,
+    ) -> Result<(), ClientError> {
+        let sensors = [&self.w, &self.wh_import, &self.wh_export]
+            .into_iter()
+            .chain(&self.power_phases)
+            .flatten();
+        for sensor in sensors {
+            announce(client, cfg, &cfg.object_id, sensor).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn publish(&self, client: &AsyncClient, meter: &Meter) -> Result<(), ClientError> {
+        publish_value(client, self.w.as_ref(), meter.w).await?;
+        for (sensor, value) in self.power_phases.iter().zip(meter.power_phases) {
+            publish_value(client, sensor.as_ref(), value).await?;
+        }
+        publish_value(client, self.wh_import.as_ref(), meter.wh_import).await?;
+        publish_value(client, self.wh_export.as_ref(), meter.wh_export).await?;
+        Ok(())
+    }
+}
+
+/// Publish a value if both the entity and the value exist
+async fn publish_value(
+    client: &AsyncClient,
+    sensor: Option<&Sensor>,
+    value: Option<f64>,
+) -> Result<(), ClientError> {
+    if let (Some(sensor), Some(value)) = (sensor, value) {
+        publish_state(client, sensor, value).await?;
+    }
+    Ok(())
+}
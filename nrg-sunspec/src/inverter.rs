@@ -0,0 +1,530 @@
+//! Telemetry of the inverter models.
+//!
+//! Inverters implement one of the integer models 101–103 with scale
+//! factors or one of the float models 111–113. Both are converted to an
+//! [`Inverter`] so the Home Assistant entities do not depend on the
+//! model. Unimplemented points are `None`.
+
+use serde::Serialize;
+use strum::{AsRefStr, FromRepr};
+use sunspec::{
+    client::{AsyncClient, AsyncModbusClient, ModbusError, ReadModelError},
+    models::{
+        model101::Model101, model102::Model102, model103::Model103, model111::Model111,
+        model112::Model112, model113::Model113, model160::Model160,
+    },
+    DecodeError, Model, Models, Value,
+};
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+
+/// Inverter models in the order of preference. The float models are
+/// preferred as they do not need scale factors.
+const PREFERENCE: [InverterModel; 6] = [
+    InverterModel::M113,
+    InverterModel::M112,
+    InverterModel::M111,
+    InverterModel::M103,
+    InverterModel::M102,
+    InverterModel::M101,
+];
+
+/// Length of a module block of the MPPT model 160
+const MPPT_MODULE_LEN: usize = 20;
+/// Length of the fixed block of the MPPT model 160
+const MPPT_HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InverterModel {
+    /// Single phase inverter
+    M101,
+    /// Split phase inverter
+    M102,
+    /// Three phase inverter
+    M103,
+    /// Single phase inverter (float)
+    M111,
+    /// Split phase inverter (float)
+    M112,
+    /// Three phase inverter (float)
+    M113,
+}
+
+impl InverterModel {
+    pub fn id(self) -> u16 {
+        match self {
+            Self::M101 => Model101::ID,
+            Self::M102 => Model102::ID,
+            Self::M103 => Model103::ID,
+            Self::M111 => Model111::ID,
+            Self::M112 => Model112::ID,
+            Self::M113 => Model113::ID,
+        }
+    }
+
+    /// Pick the inverter model from the discovered models
+    pub fn detect(models: &Models) -> Option<Self> {
+        let supported = models.supported_model_ids();
+        PREFERENCE
+            .into_iter()
+            .find(|model| supported.contains(&model.id()))
+    }
+
+    pub async fn read(self, client: &mut AsyncClient<Context>) -> Result<Inverter, ReadModelError> {
+        Ok(match self {
+            Self::M101 => client.read_model::<Model101>().await?.into(),
+            Self::M102 => client.read_model::<Model102>().await?.into(),
+            Self::M103 => client.read_model::<Model103>().await?.into(),
+            Self::M111 => client.read_model::<Model111>().await?.into(),
+            Self::M112 => client.read_model::<Model112>().await?.into(),
+            Self::M113 => client.read_model::<Model113>().await?.into(),
+        })
+    }
+}
+
+/// Operating state of the inverter and of MPPT modules
+#[derive(Copy, Clone, Debug, AsRefStr, FromRepr, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+#[repr(u16)]
+pub enum OperatingState {
+    Off = 1,
+    Sleeping = 2,
+    Starting = 3,
+    Mppt = 4,
+    Throttled = 5,
+    ShuttingDown = 6,
+    Fault = 7,
+    Standby = 8,
+}
+
+impl OperatingState {
+    pub const OPTIONS: [Self; 8] = [
+        Self::Off,
+        Self::Sleeping,
+        Self::Starting,
+        Self::Mppt,
+        Self::Throttled,
+        Self::ShuttingDown,
+        Self::Fault,
+        Self::Standby,
+    ];
+}
+
+/// Names of the bits of `Evt1`
+const EVENT_NAMES: [&str; 16] = [
+    "ground_fault",
+    "dc_over_volt",
+    "ac_disconnect",
+    "dc_disconnect",
+    "grid_disconnect",
+    "cabinet_open",
+    "manual_shutdown",
+    "over_temp",
+    "over_frequency",
+    "under_frequency",
+    "ac_over_volt",
+    "ac_under_volt",
+    "blown_string_fuse",
+    "under_temp",
+    "memory_loss",
+    "hw_test_failure",
+];
+
+/// Active events of the inverter as published via MQTT
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Events {
+    /// Raw value of `Evt1`
+    pub bits: u32,
+    /// Names of the set bits, reserved bits are omitted
+    pub active: Vec<&'static str>,
+}
+
+impl Events {
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            bits,
+            active: EVENT_NAMES
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| bits & (1 << bit) != 0)
+                .map(|(_, name)| *name)
+                .collect(),
+        }
+    }
+}
+
+/// Values of the inverter in W, Wh, A, V, Hz and °C
+#[derive(Clone, Debug)]
+pub struct Inverter {
+    pub w: Option<f64>,
+    pub wh: Option<f64>,
+    pub hz: Option<f64>,
+    /// Current of the phases L1 to L3
+    pub current_phases: [Option<f64>; 3],
+    /// Phase to neutral voltage of the phases L1 to L3
+    pub voltage_phases: [Option<f64>; 3],
+    pub dc_a: Option<f64>,
+    pub dc_v: Option<f64>,
+    pub dc_w: Option<f64>,
+    /// Cabinet temperature
+    pub temperature: Option<f64>,
+    pub state: Option<OperatingState>,
+    pub events: Events,
+    /// Points which get an entity
+    pub points: Points,
+}
+
+/// Points implemented by the inverter. Integer models mark unimplemented
+/// points with special values. Float models use NaN for these as well
+/// as for values which are unavailable, e.g. while the inverter sleeps,
+/// so their points are taken from the model definition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Points {
+    pub hz: bool,
+    pub current_phases: [bool; 3],
+    pub voltage_phases: [bool; 3],
+    pub dc_a: bool,
+    pub dc_v: bool,
+    pub dc_w: bool,
+    pub temperature: bool,
+}
+
+impl Points {
+    /// Points of an integer model with valid values
+    fn implemented(inverter: &Inverter) -> Self {
+        Self {
+            hz: inverter.hz.is_some(),
+            current_phases: inverter.current_phases.map(|a| a.is_some()),
+            voltage_phases: inverter.voltage_phases.map(|v| v.is_some()),
+            dc_a: inverter.dc_a.is_some(),
+            dc_v: inverter.dc_v.is_some(),
+            dc_w: inverter.dc_w.is_some(),
+            temperature: inverter.temperature.is_some(),
+        }
+    }
+
+    /// Points of a float model with the given number of phases. The DC
+    /// points are optional but cannot be told apart from unavailable
+    /// values, so they are assumed to be implemented.
+    fn defined(phases: usize) -> Self {
+        Self {
+            hz: true,
+            current_phases: std::array::from_fn(|i| i < phases),
+            voltage_phases: std::array::from_fn(|i| i < phases),
+            dc_a: true,
+            dc_v: true,
+            dc_w: true,
+            temperature: true,
+        }
+    }
+}
+
+/// Integer point which uses a special value for "not implemented"
+pub(crate) trait Raw: Copy + PartialEq + Into<f64> {
+    const NOT_IMPLEMENTED: Self;
+}
+
+impl Raw for u16 {
+    const NOT_IMPLEMENTED: Self = u16::MAX;
+}
+
+impl Raw for i16 {
+    const NOT_IMPLEMENTED: Self = i16::MIN;
+}
+
+impl Raw for u32 {
+    const NOT_IMPLEMENTED: Self = u32::MAX;
+}
+
+/// Apply the scale factor to an integer point
+pub(crate) fn scaled<T: Raw>(value: Option<T>, sf: Option<i16>) -> Option<f64> {
+    let value: f64 = value.filter(|v| *v != T::NOT_IMPLEMENTED)?.into();
+    // Dividing avoids results like 230.10000000000002
+    Some(match sf.filter(|sf| *sf != i16::MIN)? {
+        sf @ 0.. => value * 10f64.powi(sf.into()),
+        sf => value / 10f64.powi(-i32::from(sf)),
+    })
+}
+
+/// Float points use NaN for "not implemented"
+fn float(value: Option<f32>) -> Option<f64> {
+    // Converting via the shortest decimal representation keeps 9.1 from
+    // becoming 9.100000381469727
+    value
+        .filter(|v| v.is_finite())
+        .and_then(|v| v.to_string().parse().ok())
+}
+
+macro_rules! impl_from_int_model {
+    ($model:ty) => {
+        impl From<$model> for Inverter {
+            fn from(m: $model) -> Self {
+                let mut inverter = Self {
+                    w: scaled(Some(m.w), Some(m.w_sf)),
+                    wh: scaled(Some(m.wh), Some(m.wh_sf)),
+                    hz: scaled(Some(m.hz), Some(m.hz_sf)),
+                    current_phases: [m.aph_a.into(), m.aph_b.into(), m.aph_c.into()]
+                        .map(|a| scaled::<u16>(a, Some(m.a_sf))),
+                    voltage_phases: [m.ph_vph_a.into(), m.ph_vph_b.into(), m.ph_vph_c.into()]
+                        .map(|v| scaled::<u16>(v, Some(m.v_sf))),
+                    dc_a: scaled(m.dca, m.dca_sf),
+                    dc_v: scaled(m.dcv, m.dcv_sf),
+                    dc_w: scaled(m.dcw, m.dcw_sf),
+                    temperature: scaled(Some(m.tmp_cab), Some(m.tmp_sf)),
+                    state: OperatingState::from_repr(m.st as u16),
+                    events: Events::from_bits(m.evt1.bits()),
+                    points: Points::defined(0),
+                };
+                inverter.points = Points::implemented(&inverter);
+                inverter
+            }
+        }
+    };
+}
+
+macro_rules! impl_from_float_model {
+    ($model:ty, $phases:literal) => {
+        impl From<$model> for Inverter {
+            fn from(m: $model) -> Self {
+                Self {
+                    w: float(Some(m.w)),
+                    wh: float(Some(m.wh)),
+                    hz: float(Some(m.hz)),
+                    current_phases: [m.aph_a.into(), m.aph_b.into(), m.aph_c.into()].map(float),
+                    voltage_phases: [m.ph_vph_a.into(), m.ph_vph_b.into(), m.ph_vph_c.into()]
+                        .map(float),
+                    dc_a: float(m.dca),
+                    dc_v: float(m.dcv),
+                    dc_w: float(m.dcw),
+                    temperature: float(Some(m.tmp_cab)),
+                    state: OperatingState::from_repr(m.st as u16),
+                    events: Events::from_bits(m.evt1.bits()),
+                    points: Points::defined($phases),
+                }
+            }
+        }
+    };
+}
+
+impl_from_int_model!(Model101);
+impl_from_int_model!(Model102);
+impl_from_int_model!(Model103);
+impl_from_float_model!(Model111, 1);
+impl_from_float_model!(Model112, 2);
+impl_from_float_model!(Model113, 3);
+
+/// Values of one input of the MPPT extension model 160
+#[derive(Clone, Debug)]
+pub struct Mppt {
+    pub id: u16,
+    /// Label of the input, e.g. "String 1"
+    pub label: Option<String>,
+    pub dc_a: Option<f64>,
+    pub dc_v: Option<f64>,
+    pub dc_w: Option<f64>,
+    pub dc_wh: Option<f64>,
+    pub temperature: Option<f64>,
+    pub state: Option<OperatingState>,
+}
+
+/// Read all modules of the MPPT model. The `sunspec` crate only decodes
+/// the fixed block, so the repeating blocks are decoded here.
+pub async fn read_mppt(client: &mut AsyncClient<Context>) -> Result<Vec<Mppt>, ReadModelError> {
+    let addr = Model160::addr(&client.models);
+    let data = read_registers(client, addr.addr, addr.len).await?;
+    Ok(decode_mppt(&data)?)
+}
+
+/// Decode the registers of the MPPT model without the model header
+fn decode_mppt(data: &[u16]) -> Result<Vec<Mppt>, DecodeError> {
+    let header = Model160::from_data(data)?;
+    let modules = data.get(MPPT_HEADER_LEN..).unwrap_or_default();
+    let count = header
+        .n
+        .map_or(modules.len() / MPPT_MODULE_LEN, usize::from);
+    modules
+        .chunks_exact(MPPT_MODULE_LEN)
+        .take(count)
+        .map(|module| {
+            // Energy counters use 0 for "not implemented"
+            let dc_wh = u32::decode(&module[12..14])?;
+            Ok(Mppt {
+                id: u16::decode(&module[0..1])?,
+                label: Option::<String>::decode(&module[1..9])?.filter(|s| !s.is_empty()),
+                dc_a: scaled(Some(u16::decode(&module[9..10])?), header.dca_sf),
+                dc_v: scaled(Some(u16::decode(&module[10..11])?), header.dcv_sf),
+                dc_w: scaled(Some(u16::decode(&module[11..12])?), header.dcw_sf),
+                dc_wh: scaled(Some(dc_wh).filter(|wh| *wh != 0), header.dcwh_sf),
+                temperature: scaled(Some(i16::decode(&module[16..17])?), Some(0)),
+                state: OperatingState::from_repr(u16::decode(&module[17..18])?),
+            })
+        })
+        .collect()
+}
+
+/// Read a range of registers in chunks of `max_read_length`
+pub(crate) async fn read_registers(
+    client: &mut AsyncClient<Context>,
+    addr: u16,
+    len: u16,
+) -> Result<Vec<u16>, ModbusError> {
+    let mut data = Vec::with_capacity(len.into());
+    // The range must not extend past the last register address
+    let end = addr
+        .checked_add(len)
+        .ok_or(ModbusError::IllegalDataAddress)?;
+    let mut start = addr;
+    while start < end {
+        let count = (end - start).min(client.config.max_read_length);
+        let read = client.client.read_registers(start, count);
+        let chunk = match client.config.read_timeout {
+            Some(duration) => timeout(duration, read)
+                .await
+                .map_err(|_| ModbusError::Timeout)??,
+            None => read.await?,
+        };
+        data.extend(chunk);
+        start += count;
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Model 103 of a three phase inverter feeding in 5.4 kW without a
+    /// cabinet temperature. Register image without the model header
+    /// built from the model definition.
+    const MODEL_103: [u16; 50] = [
+        2390, 797, 796, 797, 0xfffe, // A, AphA-C, A_SF -2
+        0xffff, 0xffff, 0xffff, // PPVph not implemented
+        2301, 2298, 2310, 0xffff, // PhVphA-C, V_SF -1
+        5432, 0, // W, W_SF
+        5001, 0xfffe, // Hz, Hz_SF -2
+        0x8000, 0x8000, 0x8000, 0x8000, 0x8000, 0x8000, // VA, VAr, PF
+        0x0012, 0xd687, 1, // WH 1234567, WH_SF 1
+        1234, 0xfffe, // DCA, DCA_SF -2
+        4501, 0xffff, // DCV, DCV_SF -1
+        5600, 0, // DCW, DCW_SF
+        0x8000, 0x8000, 0x8000, 0x8000, 0, // TmpCab-TmpOt, Tmp_SF
+        4, 0xffff, // St MPPT, StVnd
+        0, 0x0081, // Evt1 ground fault and over temperature
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // Evt2, EvtVnd1-4
+    ];
+
+    /// Model 160 with two inputs and a third module beyond `N`
+    const MODEL_160: [u16; 68] = [
+        0xfffe, 0xffff, 0, 0, // DCA_SF -2, DCV_SF -1, DCW_SF, DCWH_SF
+        0, 0, 2, 0xffff, // Evt, N, TmsPer
+        // Module 1
+        1, 0x5374, 0x7269, 0x6e67, 0x2031, 0, 0, 0, 0, // ID, IDStr "String 1"
+        512, 3805, 1948, 0x0001, 0x2345, // DCA, DCV, DCW, DCWH 74565
+        0, 0, 0x8000, 4, 0, 0, // Tms, Tmp, DCSt, DCEvt
+        // Module 2 without label, current, power and energy
+        2, 0, 0, 0, 0, 0, 0, 0, 0, // ID, IDStr
+        0xffff, 3790, 0xffff, 0, 0, // DCA, DCV, DCW, DCWH
+        0, 0, 25, 0xffff, 0, 0, // Tms, Tmp, DCSt, DCEvt
+        // Module 3
+        3, 0, 0, 0, 0, 0, 0, 0, 0, // ID, IDStr
+        100, 100, 100, 0, 0, // DCA, DCV, DCW, DCWH
+        0, 0, 0, 4, 0, 0, // Tms, Tmp, DCSt, DCEvt
+    ];
+
+    #[test]
+    fn scale_factors() {
+        assert_eq!(scaled(Some(2301u16), Some(-1)), Some(230.1));
+        assert_eq!(scaled(Some(12u16), Some(2)), Some(1200.0));
+        assert_eq!(scaled(Some(-5i16), Some(0)), Some(-5.0));
+        assert_eq!(scaled(Some(1234567u32), Some(-3)), Some(1234.567));
+        // Not implemented values and scale factors
+        assert_eq!(scaled(Some(u16::MAX), Some(0)), None);
+        assert_eq!(scaled(Some(i16::MIN), Some(0)), None);
+        assert_eq!(scaled(Some(u32::MAX), Some(0)), None);
+        assert_eq!(scaled(Some(5u16), Some(i16::MIN)), None);
+        assert_eq!(scaled(Some(5u16), None), None);
+        assert_eq!(scaled::<u16>(None, Some(0)), None);
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(float(Some(9.1)), Some(9.1));
+        assert_eq!(float(Some(f32::NAN)), None);
+        assert_eq!(float(None), None);
+    }
+
+    #[test]
+    fn events() {
+        let events = Events::from_bits(0x0081);
+        assert_eq!(events.active, ["ground_fault", "over_temp"]);
+        // Reserved bits are omitted
+        let events = Events::from_bits(1 << 20);
+        assert_eq!(events.bits, 1 << 20);
+        assert!(events.active.is_empty());
+    }
+
+    #[test]
+    fn model_103() {
+        let inverter = Inverter::from(Model103::from_data(&MODEL_103).unwrap());
+        assert_eq!(inverter.w, Some(5432.0));
+        assert_eq!(inverter.wh, Some(12345670.0));
+        assert_eq!(inverter.hz, Some(50.01));
+        assert_eq!(
+            inverter.current_phases,
+            [Some(7.97), Some(7.96), Some(7.97)]
+        );
+        assert_eq!(
+            inverter.voltage_phases,
+            [Some(230.1), Some(229.8), Some(231.0)]
+        );
+        assert_eq!(inverter.dc_a, Some(12.34));
+        assert_eq!(inverter.dc_v, Some(450.1));
+        assert_eq!(inverter.dc_w, Some(5600.0));
+        assert_eq!(inverter.temperature, None);
+        assert_eq!(inverter.state, Some(OperatingState::Mppt));
+        assert_eq!(inverter.events.active, ["ground_fault", "over_temp"]);
+        assert!(!inverter.points.temperature);
+        assert!(inverter.points.dc_w);
+    }
+
+    #[test]
+    fn float_model_points() {
+        // A sleeping inverter reports NaN for all values
+        let points = Points::defined(1);
+        assert_eq!(points.current_phases, [true, false, false]);
+        assert!(points.hz && points.temperature && points.dc_a);
+    }
+
+    #[test]
+    fn mppt() {
+        let mppt = decode_mppt(&MODEL_160).unwrap();
+        assert_eq!(mppt.len(), 2);
+        let [first, second] = &mppt[..] else {
+            unreachable!()
+        };
+        assert_eq!(first.id, 1);
+        assert_eq!(first.label.as_deref(), Some("String 1"));
+        assert_eq!(first.dc_a, Some(5.12));
+        assert_eq!(first.dc_v, Some(380.5));
+        assert_eq!(first.dc_w, Some(1948.0));
+        assert_eq!(first.dc_wh, Some(74565.0));
+        assert_eq!(first.temperature, None);
+        assert_eq!(first.state, Some(OperatingState::Mppt));
+        assert_eq!(second.id, 2);
+        assert_eq!(second.label, None);
+        assert_eq!(second.dc_a, None);
+        assert_eq!(second.dc_v, Some(379.0));
+        assert_eq!(second.dc_w, None);
+        assert_eq!(second.dc_wh, None);
+        assert_eq!(second.temperature, Some(25.0));
+        assert_eq!(second.state, None);
+    }
+
+    #[test]
+    fn mppt_without_count() {
+        let mut data = MODEL_160;
+        data[6] = 0xffff;
+        assert_eq!(decode_mppt(&data).unwrap().len(), 3);
+        assert!(decode_mppt(&MODEL_160[..4]).is_err());
+    }
+}
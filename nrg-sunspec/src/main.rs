@@ -1,19 +1,31 @@
-use std::{error::Error, fs, time::Duration};
+use std::{error::Error, fs};
 
+use nrg_hass::availability::publish_availability;
 use nrg_mqtt::client::MqttClient;
-use rumqttc::QoS;
 use sunspec::{
-    client::AsyncClient,
-    models::{model1::Model1, model103::Model103},
+    client::{AsyncClient, ReadModelError},
+    models::{model1::Model1, model160::Model160},
+    Model,
 };
 use tokio::time::sleep;
-use tokio_modbus::{client::tcp::connect_slave, Slave};
-use tracing::Level;
+use tokio_modbus::{
+    client::{tcp::connect_slave, Context},
+    Slave,
+};
+use tracing::{info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
-use crate::config::Config;
+use crate::{
+    config::{Config, MeterConfig, ModbusConfig},
+    hass::{Entities, Hass, MeterHass},
+    inverter::{read_mppt, Inverter, InverterModel, Mppt},
+    meter::{Meter, MeterAddr},
+};
 
 pub mod config;
+pub mod hass;
+pub mod inverter;
+pub mod meter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -27,12 +39,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let data = String::from_utf8(data).expect("Config file contains non-utf8 characters");
     let cfg: Config = toml::from_str(&data).expect("Error in config file");
 
-    let mut client = AsyncClient::new(
-        connect_slave(cfg.modbus.addr, Slave(cfg.modbus.slave)).await?,
-        sunspec::client::Config::default(),
-    )
-    .await?;
-    let m1: Model1 = client.read_model().await?;
+    let (mut client, m1) = loop {
+        let mut client = connect(&cfg.modbus).await;
+        match client.read_model::<Model1>().await {
+            Ok(m1) => break (client, m1),
+            Err(e) => warn!("Reading common model failed: {}", e),
+        }
+        sleep(cfg.modbus.retry_delay).await;
+    };
 
     println!("Manufacturer: {}", m1.mn);
     println!("Model: {}", m1.md);
@@ -43,31 +57,289 @@ async fn main() -> Result<(), Box<dyn Error>> {
         client.models.supported_model_ids()
     );
 
+    let model = InverterModel::detect(&client.models)
+        .ok_or("No supported inverter model (101-103, 111-113) found")?;
+    let has_mppt = client.models.supported_model_ids().contains(&Model160::ID);
+    info!("Using inverter model {}", model.id());
+
+    let (inverter, mppt) = loop {
+        match read(&mut client, model, has_mppt).await {
+            Ok(reading) => break reading,
+            Err(e) => {
+                warn!("Reading inverter model failed: {}", e);
+                sleep(cfg.modbus.retry_delay).await;
+                client = connect(&cfg.modbus).await;
+            }
+        }
+    };
+
     let mqtt = MqttClient::new(&cfg.mqtt);
 
+    let entities = Entities::new(&cfg.hass, &cfg.mqtt.topic_prefix, &m1);
+    let hass = Hass::new(&entities, &inverter, &mppt);
+    hass.announce(&mqtt, &cfg.hass).await?;
+    publish_availability(&mqtt, &hass.availability_topic, true).await?;
+
+    // Meters are set up with the first poll
+    let mut meters: Vec<_> = cfg.meters.iter().map(MeterDevice::new).collect();
+
+    // Availability of the inverter as last published
+    let mut available = true;
+    let mut reading = Some((inverter, mppt));
     loop {
-        let m103: Model103 = client.read_model().await?;
-        let w = m103.w as f32 * 10f32.powf(m103.w_sf.into());
-        let wh = m103.wh as f32 * 10f32.powf(m103.wh_sf.into());
-
-        println!("{:12.3} kWh {:9.3} kW", wh / 1000.0, w / 1000.0,);
-
-        mqtt.publish(
-            "nrg/solar-inverter/wh",
-            QoS::AtLeastOnce,
-            true,
-            wh.to_string(),
-        )
-        .await?;
-
-        mqtt.publish(
-            "nrg/solar-inverter/w",
-            QoS::AtLeastOnce,
-            true,
-            format!("{:.1}", w),
-        )
-        .await?;
-
-        sleep(Duration::from_secs(5)).await;
+        let (inverter, mppt) = match reading.take() {
+            Some(reading) => reading,
+            None => match read(&mut client, model, has_mppt).await {
+                Ok(reading) => reading,
+                Err(e) => {
+                    warn!("Reading inverter model failed: {}", e);
+                    if available {
+                        publish_availability(&mqtt, &hass.availability_topic, false).await?;
+                        available = false;
+                    }
+                    for found in meters.iter_mut().filter_map(|meter| meter.found.as_mut()) {
+                        found.set_available(&mqtt, false).await?;
+                    }
+                    sleep(cfg.modbus.retry_delay).await;
+                    client = connect(&cfg.modbus).await;
+                    continue;
+                }
+            },
+        };
+        if !available {
+            publish_availability(&mqtt, &hass.availability_topic, true).await?;
+            available = true;
+        }
+
+        println!(
+            "{:12.3} kWh {:9.3} kW",
+            inverter.wh.unwrap_or_default() / 1000.0,
+            inverter.w.unwrap_or_default() / 1000.0,
+        );
+
+        hass.publish(&mqtt, &inverter).await?;
+        hass.publish_mppt(&mqtt, &mppt).await?;
+
+        for meter in &mut meters {
+            let Some(found) = &mut meter.found else {
+                meter.setup(&mut client, &mqtt, &cfg, &m1).await?;
+                continue;
+            };
+            match found.read(&mut client).await {
+                Ok(values) => {
+                    found.set_available(&mqtt, true).await?;
+                    found.hass.publish(&mqtt, &values).await?;
+                }
+                Err(e) => {
+                    warn!("Reading meter {} failed: {}", meter.cfg.object_id, e);
+                    found.set_available(&mqtt, false).await?;
+                }
+            }
+        }
+
+        sleep(cfg.modbus.poll_delay).await;
+    }
+}
+
+/// Meter attached to the inverter. Meters which could not be
+/// discovered or read yet are set up again with every poll.
+struct MeterDevice<'a> {
+    cfg: &'a MeterConfig,
+    found: Option<FoundMeter>,
+}
+
+/// Meter whose entities have been announced
+struct FoundMeter {
+    addr: MeterAddr,
+    invert_power: bool,
+    hass: MeterHass,
+    available: bool,
+}
+
+impl<'a> MeterDevice<'a> {
+    fn new(cfg: &'a MeterConfig) -> Self {
+        Self { cfg, found: None }
+    }
+
+    /// Locate the meter model and announce the entities based on the
+    /// first reading. Failures are logged and retried with the next
+    /// poll.
+    async fn setup(
+        &mut self,
+        client: &mut AsyncClient<Context>,
+        mqtt: &rumqttc::AsyncClient,
+        cfg: &Config,
+        m1: &Model1,
+    ) -> Result<(), rumqttc::ClientError> {
+        let (meter_m1, addr) = match find_meter(client, self.cfg).await {
+            Ok(Some(found)) => found,
+            Ok(None) => {
+                warn!("No meter model found for meter {}", self.cfg.object_id);
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Discovering meter {} failed: {}", self.cfg.object_id, e);
+                return Ok(());
+            }
+        };
+        info!(
+            "Using meter model {} at {} for meter {}",
+            addr.model.id(),
+            addr.addr,
+            self.cfg.object_id
+        );
+        let values = match read_meter(client, &addr, self.cfg.invert_power).await {
+            Ok(values) => values,
+            Err(e) => {
+                warn!("Reading meter {} failed: {}", self.cfg.object_id, e);
+                return Ok(());
+            }
+        };
+        let hass_cfg = self.cfg.hass(&cfg.hass);
+        let entities = match &meter_m1 {
+            Some(meter_m1) => Entities::new(&hass_cfg, &cfg.mqtt.topic_prefix, meter_m1),
+            None => Entities::meter(&hass_cfg, &cfg.mqtt.topic_prefix, m1, &addr),
+        };
+        let hass = MeterHass::new(&entities, &values);
+        hass.announce(mqtt, &hass_cfg).await?;
+        publish_availability(mqtt, &hass.availability_topic, true).await?;
+        hass.publish(mqtt, &values).await?;
+        self.found = Some(FoundMeter {
+            addr,
+            invert_power: self.cfg.invert_power,
+            hass,
+            available: true,
+        });
+        Ok(())
+    }
+}
+
+impl FoundMeter {
+    async fn read(&self, client: &mut AsyncClient<Context>) -> Result<Meter, ReadModelError> {
+        read_meter(client, &self.addr, self.invert_power).await
+    }
+
+    /// Publish the availability if it changed
+    async fn set_available(
+        &mut self,
+        client: &rumqttc::AsyncClient,
+        available: bool,
+    ) -> Result<(), rumqttc::ClientError> {
+        if self.available != available {
+            publish_availability(client, &self.hass.availability_topic, available).await?;
+            self.available = available;
+        }
+        Ok(())
+    }
+}
+
+async fn read_meter(
+    client: &mut AsyncClient<Context>,
+    addr: &MeterAddr,
+    invert_power: bool,
+) -> Result<Meter, ReadModelError> {
+    let mut meter = addr.read(client).await?;
+    if invert_power {
+        meter.invert_power();
+    }
+    Ok(meter)
+}
+
+/// Locate the meter model of a configured meter
+async fn find_meter(
+    client: &mut AsyncClient<Context>,
+    cfg: &MeterConfig,
+) -> Result<Option<(Option<Model1>, MeterAddr)>, ReadModelError> {
+    match cfg.addr {
+        Some(addr) => MeterAddr::discover(client, addr).await,
+        None => Ok(MeterAddr::from_models(&client.models).map(|addr| (None, addr))),
+    }
+}
+
+/// Read the inverter model and the MPPT inputs if supported
+async fn read(
+    client: &mut AsyncClient<Context>,
+    model: InverterModel,
+    has_mppt: bool,
+) -> Result<(Inverter, Vec<Mppt>), ReadModelError> {
+    let inverter = model.read(client).await?;
+    let mppt = if has_mppt {
+        read_mppt(client).await?
+    } else {
+        Vec::new()
+    };
+    Ok((inverter, mppt))
+}
+
+/// Connect to the inverter and discover the supported models
+async fn connect(cfg: &ModbusConfig) -> AsyncClient<Context> {
+    let config = sunspec::client::Config {
+        read_timeout: Some(cfg.timeout),
+        write_timeout: Some(cfg.timeout),
+        ..Default::default()
+    };
+    nrg_modbus::connection::connect(cfg.retry_delay, cfg.timeout, || async {
+        info!("Connecting to inverter {:?}...", cfg.addr);
+        let ctx = connect_slave(cfg.addr, Slave(cfg.slave))
+            .await
+            .map_err(|e| e.to_string())?;
+        AsyncClient::new(ctx, config.clone())
+            .await
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::MqttOptions;
+
+    use super::*;
+    use crate::meter::tests::{inverter, solaredge_meter};
+
+    const CONFIG: &str = r#"
+        [modbus]
+        addr = "127.0.0.1:502"
+        slave = 1
+        retry_delay = { secs = 5, nanos = 0 }
+        poll_delay = { secs = 10, nanos = 0 }
+
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        client_id = "nrg-sunspec"
+        topic_prefix = "nrg/solar-inverter"
+
+        [home-assistant]
+        discovery_prefix = "homeassistant"
+        object_id = "pv"
+        name = "PV"
+
+        [[meter]]
+        addr = 40121
+        object_id = "grid"
+        name = "Netz"
+    "#;
+
+    #[tokio::test]
+    async fn meter_setup_is_retried() {
+        let cfg: Config = toml::from_str(CONFIG).unwrap();
+        let image = inverter();
+        let mut client = image.connect().await;
+        let m1 = client.read_model::<Model1>().await.unwrap();
+        // The event loop is not polled, the requests stay queued
+        let (mqtt, _eventloop) =
+            rumqttc::AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 100);
+        let mut meter = MeterDevice::new(&cfg.meters[0]);
+
+        // The meter block is not available yet
+        meter.setup(&mut client, &mqtt, &cfg, &m1).await.unwrap();
+        assert!(meter.found.is_none());
+
+        solaredge_meter(&image);
+        meter.setup(&mut client, &mqtt, &cfg, &m1).await.unwrap();
+        let found = meter.found.as_ref().unwrap();
+        assert_eq!(found.addr.addr, 40190);
+        assert!(found.available);
     }
 }
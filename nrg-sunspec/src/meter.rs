@@ -0,0 +1,345 @@
+//! Energy meters attached to the inverter (models 201–204).
+//!
+//! Meters are either discovered together with the inverter models or
+//! live in a device block of their own. SolarEdge inverters put the
+//! common model and the meter model of their export meters at 40121,
+//! 40295 and 40469 without a `SunS` marker.
+
+use sunspec::{
+    client::{AsyncClient, ReadModelError},
+    models::{
+        model1::Model1, model201::Model201, model202::Model202, model203::Model203,
+        model204::Model204,
+    },
+    DecodeError, Model, Models, SUNS_IDENTIFIER,
+};
+use tokio_modbus::client::Context;
+
+use crate::inverter::{read_registers, scaled};
+
+/// Model ID which ends the list of models
+const END_MODEL_ID: u16 = 0xFFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeterModel {
+    /// Single phase meter
+    M201,
+    /// Split phase meter
+    M202,
+    /// Three phase meter (wye)
+    M203,
+    /// Three phase meter (delta)
+    M204,
+}
+
+impl MeterModel {
+    pub fn id(self) -> u16 {
+        match self {
+            Self::M201 => Model201::ID,
+            Self::M202 => Model202::ID,
+            Self::M203 => Model203::ID,
+            Self::M204 => Model204::ID,
+        }
+    }
+
+    fn from_id(id: u16) -> Option<Self> {
+        [Self::M201, Self::M202, Self::M203, Self::M204]
+            .into_iter()
+            .find(|model| model.id() == id)
+    }
+}
+
+/// Location of a meter model
+#[derive(Clone, Copy, Debug)]
+pub struct MeterAddr {
+    pub model: MeterModel,
+    pub addr: u16,
+    pub len: u16,
+}
+
+impl MeterAddr {
+    /// Meter model found during the discovery of the inverter
+    pub fn from_models(models: &Models) -> Option<Self> {
+        [
+            (MeterModel::M201, models.m201.addr, models.m201.len),
+            (MeterModel::M202, models.m202.addr, models.m202.len),
+            (MeterModel::M203, models.m203.addr, models.m203.len),
+            (MeterModel::M204, models.m204.addr, models.m204.len),
+        ]
+        .into_iter()
+        .find(|(_, addr, _)| *addr != 0)
+        .map(|(model, addr, len)| Self { model, addr, len })
+    }
+
+    /// Walk the models of the device block starting at `addr` until a
+    /// meter model is found. The common model of the meter is returned
+    /// as well if the block contains one.
+    pub async fn discover(
+        client: &mut AsyncClient<Context>,
+        mut addr: u16,
+    ) -> Result<Option<(Option<Model1>, Self)>, ReadModelError> {
+        if read_registers(client, addr, 2).await? == SUNS_IDENTIFIER {
+            let Some(next) = addr.checked_add(2) else {
+                return Ok(None);
+            };
+            addr = next;
+        }
+        let mut m1 = None;
+        loop {
+            let header = read_registers(client, addr, 2).await?;
+            let Some(&[id, len]) = header.get(..2) else {
+                return Err(DecodeError::OutOfBounds.into());
+            };
+            if id == END_MODEL_ID {
+                return Ok(None);
+            }
+            let Some(next) = addr.checked_add(2) else {
+                return Ok(None);
+            };
+            addr = next;
+            if id == Model1::ID {
+                m1 = Some(Model1::from_data(
+                    &read_registers(client, addr, len).await?,
+                )?);
+            } else if let Some(model) = MeterModel::from_id(id) {
+                return Ok(Some((m1, Self { model, addr, len })));
+            }
+            let Some(next) = addr.checked_add(len) else {
+                return Ok(None);
+            };
+            addr = next;
+        }
+    }
+
+    pub async fn read(&self, client: &mut AsyncClient<Context>) -> Result<Meter, ReadModelError> {
+        let data = read_registers(client, self.addr, self.len).await?;
+        Ok(match self.model {
+            MeterModel::M201 => Model201::from_data(&data)?.into(),
+            MeterModel::M202 => Model202::from_data(&data)?.into(),
+            MeterModel::M203 => Model203::from_data(&data)?.into(),
+            MeterModel::M204 => Model204::from_data(&data)?.into(),
+        })
+    }
+}
+
+/// Values of a meter in W and Wh. The sign of the power is the one
+/// reported by the meter, see [`Meter::invert_power`].
+#[derive(Clone, Debug)]
+pub struct Meter {
+    pub w: Option<f64>,
+    /// Power of the phases L1 to L3
+    pub power_phases: [Option<f64>; 3],
+    /// Energy received from the grid
+    pub wh_import: Option<f64>,
+    /// Energy fed into the grid
+    pub wh_export: Option<f64>,
+}
+
+impl Meter {
+    /// Negate the power for meters which report feed-in as positive
+    /// value
+    pub fn invert_power(&mut self) {
+        for w in [&mut self.w].into_iter().chain(&mut self.power_phases) {
+            *w = w.map(|w| -w);
+        }
+    }
+}
+
+macro_rules! impl_from_meter_model {
+    ($model:ty) => {
+        impl From<$model> for Meter {
+            fn from(m: $model) -> Self {
+                Self {
+                    w: scaled(Some(m.w), Some(m.w_sf)),
+                    power_phases: [m.wph_a, m.wph_b, m.wph_c].map(|w| scaled(w, Some(m.w_sf))),
+                    wh_import: scaled(Some(m.tot_wh_imp), Some(m.tot_wh_sf)),
+                    wh_export: scaled(Some(m.tot_wh_exp), Some(m.tot_wh_sf)),
+                }
+            }
+        }
+    };
+}
+
+impl_from_meter_model!(Model201);
+impl_from_meter_model!(Model202);
+impl_from_meter_model!(Model203);
+impl_from_meter_model!(Model204);
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::BTreeMap,
+        future,
+        sync::{Arc, Mutex},
+    };
+
+    use sunspec::client::Config;
+    use tokio::net::TcpListener;
+    use tokio_modbus::{
+        client::tcp::connect,
+        prelude::*,
+        server::{
+            tcp::{accept_tcp_connection, Server},
+            Service,
+        },
+    };
+
+    use super::*;
+
+    /// Holding registers of a device. Reading unset registers fails
+    /// with an exception like on real devices.
+    #[derive(Clone, Default)]
+    pub(crate) struct Image(Arc<Mutex<BTreeMap<u16, u16>>>);
+
+    impl Image {
+        pub(crate) fn write(&self, addr: u16, data: &[u16]) {
+            let mut registers = self.0.lock().unwrap();
+            for (addr, value) in (addr..).zip(data) {
+                registers.insert(addr, *value);
+            }
+        }
+
+        /// Write a model including its header and return the address
+        /// following it
+        pub(crate) fn write_model(&self, addr: u16, id: u16, data: &[u16]) -> u16 {
+            self.write(addr, &[id, data.len() as u16]);
+            self.write(addr + 2, data);
+            addr + 2 + data.len() as u16
+        }
+
+        /// Serve the registers via Modbus TCP and connect a SunSpec
+        /// client which discovers the models at 40000
+        pub(crate) async fn connect(&self) -> AsyncClient<Context> {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let image = self.clone();
+            tokio::spawn(async move {
+                let on_connected = |stream, socket_addr| {
+                    let image = image.clone();
+                    async move {
+                        accept_tcp_connection(stream, socket_addr, |_| {
+                            Ok(Some(ImageService(image.clone())))
+                        })
+                    }
+                };
+                Server::new(listener)
+                    .serve(&on_connected, |_| {})
+                    .await
+                    .unwrap();
+            });
+            let config = Config {
+                discovery_addresses: vec![40000],
+                ..Config::default()
+            };
+            AsyncClient::new(connect(addr).await.unwrap(), config)
+                .await
+                .unwrap()
+        }
+    }
+
+    struct ImageService(Image);
+
+    impl Service for ImageService {
+        type Request = Request<'static>;
+        type Response = Response;
+        type Exception = ExceptionCode;
+        type Future = future::Ready<Result<Self::Response, Self::Exception>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            let registers = self.0 .0.lock().unwrap();
+            let result = match req {
+                Request::ReadHoldingRegisters(addr, len) => (addr..)
+                    .take(len.into())
+                    .map(|addr| registers.get(&addr).copied())
+                    .collect::<Option<Vec<_>>>()
+                    .map(Response::ReadHoldingRegisters)
+                    .ok_or(ExceptionCode::IllegalDataAddress),
+                _ => Err(ExceptionCode::IllegalFunction),
+            };
+            future::ready(result)
+        }
+    }
+
+    /// Common model with the given serial number
+    pub(crate) fn model1(sn: &str) -> Vec<u16> {
+        let mut data = vec![0; 65];
+        for (offset, text) in [(0, "SolarEdge"), (16, "SE8K"), (48, sn)] {
+            for (i, pair) in text.as_bytes().chunks(2).enumerate() {
+                let high = u16::from(pair[0]) << 8;
+                data[offset + i] = high | pair.get(1).copied().map_or(0, u16::from);
+            }
+        }
+        data
+    }
+
+    /// Three phase meter importing 1.5 kW
+    pub(crate) fn model203() -> Vec<u16> {
+        let mut data = vec![0; 105];
+        // W and WphA-C with W_SF 0
+        data[16..21].copy_from_slice(&[1500, 500, 400, 600, 0]);
+        // TotWhExp and TotWhImp with TotWh_SF 1
+        data[36..38].copy_from_slice(&[0, 4321]);
+        data[44..46].copy_from_slice(&[0x0001, 0x0000]);
+        data[52] = 1;
+        data
+    }
+
+    /// Inverter with the common model at 40000 but without models of
+    /// its own meters
+    pub(crate) fn inverter() -> Image {
+        let image = Image::default();
+        image.write(40000, &SUNS_IDENTIFIER);
+        let end = image.write_model(40002, Model1::ID, &model1("7E0123456"));
+        image.write(end, &[END_MODEL_ID, 0]);
+        image
+    }
+
+    /// Export meter as placed by SolarEdge inverters at 40121
+    pub(crate) fn solaredge_meter(image: &Image) {
+        let addr = image.write_model(40121, Model1::ID, &model1("M0001"));
+        let end = image.write_model(addr, Model203::ID, &model203());
+        assert_eq!(end, 40295);
+        image.write(end, &[END_MODEL_ID, 0]);
+    }
+
+    #[tokio::test]
+    async fn discover_meter() {
+        let image = inverter();
+        solaredge_meter(&image);
+        let mut client = image.connect().await;
+        assert!(MeterAddr::from_models(&client.models).is_none());
+
+        let (m1, addr) = MeterAddr::discover(&mut client, 40121)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(m1.unwrap().sn, "M0001");
+        assert_eq!(addr.model, MeterModel::M203);
+        assert_eq!((addr.addr, addr.len), (40190, 105));
+
+        let meter = addr.read(&mut client).await.unwrap();
+        assert_eq!(meter.w, Some(1500.0));
+        assert_eq!(meter.power_phases, [Some(500.0), Some(400.0), Some(600.0)]);
+        assert_eq!(meter.wh_import, Some(655360.0));
+        assert_eq!(meter.wh_export, Some(43210.0));
+    }
+
+    #[tokio::test]
+    async fn discover_without_meter() {
+        let image = inverter();
+        solaredge_meter(&image);
+        let mut client = image.connect().await;
+        // The block of the second meter only contains the end marker
+        assert!(MeterAddr::discover(&mut client, 40295)
+            .await
+            .unwrap()
+            .is_none());
+        // The block of the inverter contains no meter model
+        assert!(MeterAddr::discover(&mut client, 40000)
+            .await
+            .unwrap()
+            .is_none());
+        // Nothing at all
+        assert!(MeterAddr::discover(&mut client, 40469).await.is_err());
+    }
+}
@@ -1,31 +1,28 @@
 use std::time::Duration;
 
 use itertools::Itertools;
+use nrg_modbus::{Bitfield16, ByteOrder, Register};
 use tokio::time::sleep;
-use tokio_modbus::{
-    client::{rtu, Reader, Writer},
-    Slave,
-};
+use tokio_modbus::{client::rtu, Slave};
 use tokio_serial::SerialStream;
 
+const DEVICE_ADDRESS: Register<u16> = Register::new("device_address", 0x4000);
+const SOFTWARE_VERSION: Register<u16> = Register::new("software_version", 0x8000);
+/// The relays are addressed MSB->LSB, i.e. coils 0-7 switch relays 9-16
+/// and coils 8-15 switch relays 1-8.
+const RELAYS: Register<Bitfield16> =
+    Register::coil("relays", 0x0000).byte_order(ByteOrder::LittleEndian);
+
 #[tokio::main]
 async fn main() {
     let builder = tokio_serial::new("/dev/ttyACM0", 9600);
     let stream = SerialStream::open(&builder).unwrap();
     let mut ctx = rtu::attach_slave(stream, Slave(1));
 
-    let rsp = ctx
-        .read_holding_registers(0x4000, 1)
-        .await
-        .unwrap()
-        .unwrap();
-    println!("Device address: {}", rsp[0]);
-    let rsp = ctx
-        .read_holding_registers(0x8000, 1)
-        .await
-        .unwrap()
-        .unwrap();
-    let version = (rsp[0] / 100, rsp[0] % 100);
+    let address = nrg_modbus::read(&mut ctx, DEVICE_ADDRESS).await.unwrap();
+    println!("Device address: {}", address);
+    let version = nrg_modbus::read(&mut ctx, SOFTWARE_VERSION).await.unwrap();
+    let version = (version / 100, version % 100);
     println!("Version: {}.{}", version.0, version.1);
 
     //let rsp = ctx.read_coils(0x0000, 0x0010).await.unwrap();
@@ -34,13 +31,11 @@ async fn main() {
         let mut flags = [false; 16];
         for x in 0..16 {
             flags[x] = true;
-            // MSB->LSB
-            let lsb = [
-                flags[8], flags[9], flags[10], flags[11], flags[12], flags[13], flags[14],
-                flags[15], flags[0], flags[1], flags[2], flags[3], flags[4], flags[5], flags[6],
-                flags[7],
-            ];
-            ctx.write_multiple_coils(0x0000, &lsb).await.unwrap();
+            let mut relays = Bitfield16::default();
+            for (bit, &flag) in flags.iter().enumerate() {
+                relays.set(bit as u8, flag);
+            }
+            nrg_modbus::write(&mut ctx, RELAYS, &relays).await.unwrap();
             println!(
                 "Relais: {} | {}",
                 flags[0..8]